@@ -0,0 +1,172 @@
+use anyhow::Result;
+
+/// Number of decimal places of a BCH amount in satoshis.
+pub const BCH_DECIMALS: u32 = 8;
+
+/// Maximum amount of BCH that can ever exist, in satoshis.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Parses a non-negative decimal string like `"12.345"` into an integer amount of base units,
+/// given the number of decimals of one whole unit.
+///
+/// Only digits and at most one decimal point are accepted; signs, exponents, whitespace,
+/// more than `decimals` fractional digits and amounts that don't fit into a `u64` are rejected.
+pub fn parse_amount(amount: &str, decimals: u32) -> Result<u64> {
+    let (int_part, frac_part) = match amount.find('.') {
+        Some(idx) => (&amount[..idx], Some(&amount[idx + 1..])),
+        None => (amount, None),
+    };
+    let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(int_part) || !frac_part.is_none_or(is_digits) {
+        anyhow::bail!("Invalid amount {:?}, must be a non-negative decimal number", amount);
+    }
+    let frac_part = frac_part.unwrap_or("");
+    if frac_part.len() > decimals as usize {
+        anyhow::bail!("Invalid amount {:?}, at most {} decimals allowed", amount, decimals);
+    }
+    let overflow = || anyhow::anyhow!("Invalid amount {:?}, too large", amount);
+    let mut value = 0u64;
+    for digit in int_part.bytes().chain(frac_part.bytes()) {
+        value = value.checked_mul(10)
+            .and_then(|value| value.checked_add((digit - b'0') as u64))
+            .ok_or_else(overflow)?;
+    }
+    let scale = 10u64.checked_pow(decimals - frac_part.len() as u32).ok_or_else(overflow)?;
+    value.checked_mul(scale).ok_or_else(overflow)
+}
+
+/// Formats an integer amount of base units as a decimal string, without trailing zeros.
+/// The inverse of `parse_amount`.
+pub fn format_amount(amount: u64, decimals: u32) -> String {
+    let scale = 10u128.pow(decimals);
+    let int_part = amount as u128 / scale;
+    let frac_part = amount as u128 % scale;
+    if frac_part == 0 {
+        return int_part.to_string();
+    }
+    let frac = format!("{:0width$}", frac_part, width = decimals as usize);
+    format!("{}.{}", int_part, frac.trim_end_matches('0'))
+}
+
+/// Parses a decimal BCH amount like `"0.00001"` into satoshis.
+pub fn parse_bch_amount(amount: &str) -> Result<u64> {
+    let value = parse_amount(amount, BCH_DECIMALS)?;
+    if value > MAX_MONEY {
+        anyhow::bail!("Invalid BCH amount {:?}, exceeds 21 million BCH", amount);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_parse_bch_amount() {
+        assert_eq!(parse_bch_amount("0").unwrap(), 0);
+        assert_eq!(parse_bch_amount("1").unwrap(), 100_000_000);
+        assert_eq!(parse_bch_amount("0.00000001").unwrap(), 1);
+        assert_eq!(parse_bch_amount("0.1").unwrap(), 10_000_000);
+        assert_eq!(parse_bch_amount("12.3456789").unwrap(), 1_234_567_890);
+        assert_eq!(parse_bch_amount("00012.30").unwrap(), 1_230_000_000);
+        assert_eq!(parse_bch_amount("21000000").unwrap(), MAX_MONEY);
+        // Values that f64 can't represent exactly.
+        assert_eq!(parse_bch_amount("0.29").unwrap(), 29_000_000);
+        assert_eq!(parse_bch_amount("20999999.99999999").unwrap(), MAX_MONEY - 1);
+    }
+
+    #[test]
+    fn test_parse_bch_amount_invalid() {
+        let invalid = [
+            "", ".", "1.", ".5", "-1", "-0.1", "+1", " 1", "1 ", "1e8", "0x10", "1.2.3", "1,5", "NaN", "inf",
+            "0.000000001", "1.123456789", "21000000.00000001", "99999999999999999999",
+        ];
+        for amount in &invalid {
+            assert!(parse_bch_amount(amount).is_err(), "{:?} should be rejected", amount);
+        }
+    }
+
+    #[test]
+    fn test_parse_amount_decimals() {
+        assert_eq!(parse_amount("123", 0).unwrap(), 123);
+        assert!(parse_amount("1.0", 0).is_err());
+        assert_eq!(parse_amount("1.5", 2).unwrap(), 150);
+        assert_eq!(parse_amount("18446744073709551615", 0).unwrap(), u64::MAX);
+        assert!(parse_amount("18446744073709551616", 0).is_err());
+        assert_eq!(parse_amount("1844674407370955161.5", 1).unwrap(), u64::MAX);
+        assert!(parse_amount("1844674407370955161.6", 1).is_err());
+        assert!(parse_amount("1", 20).is_err());
+    }
+
+    #[test]
+    fn test_parse_amount_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..10_000 {
+            let decimals = rng.gen_range(0, 20);
+            let scale = 10u64.pow(decimals);
+            let value = match rng.gen_range(0, 3) {
+                0 => rng.gen_range(0, 1_000),
+                1 => rng.gen_range(0, MAX_MONEY),
+                _ => rng.gen(),
+            };
+            let int_part = value / scale;
+            let frac_part = value % scale;
+            // Pad the integer part and drop trailing zeros of the fractional part, which mustn't change the value.
+            let mut frac = match decimals {
+                0 => String::new(),
+                _ => format!("{:0width$}", frac_part, width = decimals as usize),
+            };
+            let keep = rng.gen_range(frac.trim_end_matches('0').len(), frac.len() + 1);
+            frac.truncate(keep);
+            let padding = "0".repeat(rng.gen_range(0, 3));
+            let amount = if frac.is_empty() {
+                format!("{}{}", padding, int_part)
+            } else {
+                format!("{}{}.{}", padding, int_part, frac)
+            };
+            assert_eq!(parse_amount(&amount, decimals).unwrap(), value, "{:?} with {} decimals", amount, decimals);
+            // One more decimal than allowed is always rejected.
+            assert!(parse_amount(&format!("{}.{}1", int_part, "0".repeat(decimals as usize)), decimals).is_err());
+        }
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(0, 0), "0");
+        assert_eq!(format_amount(0, 8), "0");
+        assert_eq!(format_amount(150, 2), "1.5");
+        assert_eq!(format_amount(10_000, 4), "1");
+        assert_eq!(format_amount(1, 9), "0.000000001");
+        assert_eq!(format_amount(u64::MAX, 19), "1.8446744073709551615");
+        assert_eq!(format_amount(u64::MAX, 0), "18446744073709551615");
+    }
+
+    #[test]
+    fn test_format_parse_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(0xf0a7);
+        for _ in 0..10_000 {
+            let decimals = rng.gen_range(0, 20);
+            let value = match rng.gen_range(0, 2) {
+                0 => rng.gen_range(0, 1_000_000),
+                _ => rng.gen(),
+            };
+            let formatted = format_amount(value, decimals);
+            assert_eq!(parse_amount(&formatted, decimals).unwrap(), value, "{:?} with {} decimals", formatted, decimals);
+        }
+    }
+
+    #[test]
+    fn test_parse_bch_amount_matches_sats() {
+        let mut rng = StdRng::seed_from_u64(0xb0c);
+        for _ in 0..10_000 {
+            let sats = rng.gen_range(0, MAX_MONEY + 1);
+            let amount = format!("{}.{:08}", sats / 100_000_000, sats % 100_000_000);
+            assert_eq!(parse_bch_amount(&amount).unwrap(), sats);
+            if let Some(more) = sats.checked_add(MAX_MONEY + 1) {
+                let amount = format!("{}.{:08}", more / 100_000_000, more % 100_000_000);
+                assert!(parse_bch_amount(&amount).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use clap::ArgEnum;
+use bitcoin_cash::{Hashed, Sha256d};
+use anyhow::Result;
+
+use crate::error::ErrorKind;
+
+/// Lock times below this threshold are block heights, above are UNIX timestamps.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Number of blocks whose timestamps make up the median time past (BIP113).
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+pub const HEADER_SIZE: usize = 80;
+
+/// Network whose proof of work limit block headers are checked against.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    /// Local regression test network, whose proof of work is trivial.
+    Regtest,
+}
+
+/// A parsed block header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockHeader {
+    pub hash: Sha256d,
+    pub version: i32,
+    pub prev_hash: Sha256d,
+    pub merkle_root: Sha256d,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+/// The tip of the chain, as far as lock times are concerned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChainTip {
+    pub height: u32,
+    /// Median time past of the tip.
+    pub median_time_past: u32,
+}
+
+/// Whether a tx with some lock time can be mined in the next block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Finality {
+    Final,
+    /// The tx becomes final once this many more blocks are mined.
+    Blocks(u32),
+    /// The tx becomes final once the median time past advances by this many seconds.
+    Seconds(u32),
+}
+
+impl BlockHeader {
+    pub fn parse(raw: &[u8]) -> Result<BlockHeader> {
+        if raw.len() != HEADER_SIZE {
+            bail_kind!(ErrorKind::RpcTransport, "Invalid block header {}, must be {} bytes", hex::encode(raw), HEADER_SIZE);
+        }
+        let u32_at = |idx: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&raw[idx..idx + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        Ok(BlockHeader {
+            hash: Sha256d::digest(raw),
+            version: u32_at(0) as i32,
+            prev_hash: Sha256d::from_slice(&raw[4..36])?,
+            merkle_root: Sha256d::from_slice(&raw[36..68])?,
+            time: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        })
+    }
+
+    /// The proof of work target encoded in `bits`, as a big-endian number.
+    pub fn target(&self) -> Result<[u8; 32]> {
+        match target_from_bits(self.bits) {
+            Some(target) => Ok(target),
+            None => bail_kind!(ErrorKind::SpvVerification, "Block {} has invalid bits {:08x}", self.hash.to_hex_le(), self.bits),
+        }
+    }
+
+    /// Checks that the target encoded in the header's `bits` is within the proof of work limit of
+    /// `network` and that the header's hash meets it.
+    ///
+    /// Whether `bits` follows the network's difficulty adjustment isn't checked.
+    pub fn check_pow(&self, network: Network) -> Result<()> {
+        let target = self.target()?;
+        if target > network.pow_limit() {
+            bail_kind!(
+                ErrorKind::SpvVerification,
+                "Block {} has bits {:08x}, easier than the proof of work limit of {:?}", self.hash.to_hex_le(), self.bits, network,
+            );
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(self.hash.as_slice());
+        hash.reverse();
+        if hash > target {
+            bail_kind!(
+                ErrorKind::SpvVerification,
+                "Block {} doesn't meet its proof of work target {:08x}", self.hash.to_hex_le(), self.bits,
+            );
+        }
+        Ok(())
+    }
+
+    /// Parses consecutive headers, as returned by an Electrum server.
+    pub fn parse_many(raw: &[u8]) -> Result<Vec<BlockHeader>> {
+        if !raw.len().is_multiple_of(HEADER_SIZE) {
+            bail_kind!(ErrorKind::RpcTransport, "Invalid block headers, {} bytes isn't a multiple of {}", raw.len(), HEADER_SIZE);
+        }
+        raw.chunks(HEADER_SIZE).map(BlockHeader::parse).collect()
+    }
+}
+
+impl Network {
+    /// The easiest proof of work target a block may have, as a big-endian number.
+    pub fn pow_limit(self) -> [u8; 32] {
+        let bits = match self {
+            Network::Mainnet | Network::Testnet => 0x1d00_ffff,
+            Network::Regtest => 0x207f_ffff,
+        };
+        target_from_bits(bits).expect("valid pow limit")
+    }
+}
+
+/// The proof of work target encoded in compact `bits`, as a big-endian number, if it's valid and fits in 256 bits.
+fn target_from_bits(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 || mantissa == 0 {
+        return None;
+    }
+    let mut target = [0; 32];
+    for (idx, &byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+        // Significance of the byte, 0 for the least significant one.
+        let significance = match (exponent + 2).checked_sub(idx + 3) {
+            Some(significance) => significance,
+            None => continue,
+        };
+        if significance >= 32 {
+            if byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[31 - significance] = byte;
+    }
+    Some(target)
+}
+
+/// Checks that each of `headers` builds on the one before.
+fn check_linked(headers: &[BlockHeader], err_kind: ErrorKind) -> Result<()> {
+    for pair in headers.windows(2) {
+        if pair[1].prev_hash != pair[0].hash {
+            bail_kind!(
+                err_kind,
+                "Block headers aren't consecutive, {} doesn't follow {}", pair[1].hash.to_hex_le(), pair[0].hash.to_hex_le(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `headers` form a chain and each of them has a valid proof of work on `network`.
+pub fn verify_headers(headers: &[BlockHeader], network: Network) -> Result<()> {
+    check_linked(headers, ErrorKind::SpvVerification)?;
+    headers.iter().try_for_each(|header| header.check_pow(network))
+}
+
+/// Merkle root of a block containing `txid` at position `pos`, given the merkle branch from the
+/// txid's sibling upwards.
+pub fn merkle_root_from_branch(txid: &Sha256d, branch: &[Sha256d], pos: u32) -> Sha256d {
+    let mut hash = txid.clone();
+    for (level, sibling) in branch.iter().enumerate() {
+        let concat = if (pos >> level) & 1 == 0 {
+            [hash.as_slice(), sibling.as_slice()].concat()
+        } else {
+            [sibling.as_slice(), hash.as_slice()].concat()
+        };
+        hash = Sha256d::digest(concat);
+    }
+    hash
+}
+
+/// Median time past of the last of `headers`, which must be consecutive and include the
+/// `MEDIAN_TIME_SPAN - 1` headers before it, unless the chain is shorter.
+pub fn median_time_past(headers: &[BlockHeader]) -> Result<u32> {
+    if headers.is_empty() {
+        anyhow::bail!("Median time past needs at least one header");
+    }
+    check_linked(headers, ErrorKind::RpcTransport)?;
+    let start = headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut times = headers[start..].iter().map(|header| header.time).collect::<Vec<_>>();
+    times.sort_unstable();
+    Ok(times[times.len() / 2])
+}
+
+/// Whether a tx with `lock_time` and a non-final input can be mined in the block after `tip`.
+///
+/// Height lock times must be below the height of that block, timestamps below the tip's median time past.
+pub fn lock_time_finality(lock_time: u32, tip: &ChainTip) -> Finality {
+    if lock_time < LOCKTIME_THRESHOLD {
+        if lock_time <= tip.height {
+            Finality::Final
+        } else {
+            Finality::Blocks(lock_time - tip.height)
+        }
+    } else if lock_time < tip.median_time_past {
+        Finality::Final
+    } else {
+        Finality::Seconds(lock_time - tip.median_time_past + 1)
+    }
+}
+
+impl std::fmt::Display for Finality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finality::Final => write!(f, "final"),
+            Finality::Blocks(1) => write!(f, "final after the next block"),
+            Finality::Blocks(blocks) => write!(f, "final after {} more blocks (about {} minutes)", blocks, blocks * 10),
+            Finality::Seconds(seconds) => write!(
+                f, "final once the median time past advances by {} seconds (about {} minutes)", seconds, seconds.div_ceil(60),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Headers of the first two blocks of the BTC/BCH main chain.
+    const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+    const BLOCK_1_HEADER: &str = "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299";
+
+    /// A chain of headers with the given timestamps.
+    fn headers(times: &[u32]) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(times.len());
+        for &time in times {
+            let mut raw = vec![1, 0, 0, 0];
+            raw.extend_from_slice(headers.last().map_or(&[0; 32][..], |header| header.hash.as_slice()));
+            raw.extend_from_slice(&[0; 32]);
+            raw.extend_from_slice(&time.to_le_bytes());
+            raw.extend_from_slice(&0x207f_ffff_u32.to_le_bytes());
+            raw.extend_from_slice(&[0; 4]);
+            headers.push(BlockHeader::parse(&raw).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let raw = hex::decode(GENESIS_HEADER.to_string() + BLOCK_1_HEADER).unwrap();
+        let headers = BlockHeader::parse_many(&raw).unwrap();
+        assert_eq!(headers[0].hash.to_hex_le(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+        assert_eq!(headers[0].version, 1);
+        assert_eq!(headers[0].prev_hash, Sha256d::new([0; 32]));
+        assert_eq!(headers[0].merkle_root.to_hex_le(), "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        assert_eq!(headers[0].time, 1231006505);
+        assert_eq!(headers[0].bits, 0x1d00ffff);
+        assert_eq!(headers[0].nonce, 2083236893);
+        assert_eq!(headers[1].hash.to_hex_le(), "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+        assert_eq!(headers[1].prev_hash, headers[0].hash);
+        assert_eq!(median_time_past(&headers).unwrap(), 1231469665);
+
+        assert!(BlockHeader::parse(&raw[..79]).is_err());
+        assert!(BlockHeader::parse_many(&raw[..159]).is_err());
+    }
+
+    #[test]
+    fn test_check_pow() {
+        let raw = hex::decode(GENESIS_HEADER.to_string() + BLOCK_1_HEADER).unwrap();
+        let headers = BlockHeader::parse_many(&raw).unwrap();
+        let mut target = [0; 32];
+        target[4] = 0xff;
+        target[5] = 0xff;
+        assert_eq!(headers[0].target().unwrap(), target);
+        headers[0].check_pow(Network::Mainnet).unwrap();
+        verify_headers(&headers, Network::Mainnet).unwrap();
+        verify_headers(&headers, Network::Regtest).unwrap();
+
+        // A different nonce doesn't meet the target.
+        let mut raw_genesis = raw[..HEADER_SIZE].to_vec();
+        raw_genesis[76] ^= 1;
+        assert!(BlockHeader::parse(&raw_genesis).unwrap().check_pow(Network::Mainnet).is_err());
+        assert!(verify_headers(&[headers[1].clone(), headers[0].clone()], Network::Mainnet).is_err());
+
+        let header = |bits: u32| BlockHeader { bits, ..headers[0].clone() };
+        let mut target = [0; 32];
+        target[0] = 0x7f;
+        target[1] = 0xff;
+        target[2] = 0xff;
+        assert_eq!(header(0x207f_ffff).target().unwrap(), target);
+        let mut target = [0; 32];
+        target[31] = 0x12;
+        assert_eq!(header(0x0112_3456).target().unwrap(), target);
+        assert!(header(0x0000_0000).target().is_err());
+        assert!(header(0x0480_0001).target().is_err());
+        assert!(header(0x2101_0000).target().is_err());
+    }
+
+    #[test]
+    fn test_check_pow_limit() {
+        // A chain at the minimum difficulty of regtest, which anyone can mine in no time.
+        let mut chain: Vec<BlockHeader> = Vec::new();
+        for height in 0..10_u32 {
+            let mut raw = vec![1, 0, 0, 0];
+            raw.extend_from_slice(chain.last().map_or(&[0; 32][..], |header| header.hash.as_slice()));
+            raw.extend_from_slice(&[0; 32]);
+            raw.extend_from_slice(&(1_600_000_000 + height * 600).to_le_bytes());
+            raw.extend_from_slice(&0x207f_ffff_u32.to_le_bytes());
+            let header = (0_u32..)
+                .map(|nonce| BlockHeader::parse(&[&raw[..], &nonce.to_le_bytes()].concat()).unwrap())
+                .find(|header| header.check_pow(Network::Regtest).is_ok())
+                .unwrap();
+            chain.push(header);
+        }
+        verify_headers(&chain, Network::Regtest).unwrap();
+        let err = verify_headers(&chain, Network::Testnet).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SpvVerification);
+        assert!(format!("{:#}", err).contains("proof of work limit"), "{:#}", err);
+        assert!(verify_headers(&chain, Network::Mainnet).is_err());
+        assert!(chain[0].check_pow(Network::Testnet).is_err());
+    }
+
+    #[test]
+    fn test_merkle_root_from_branch() {
+        // Block 100000 of the main chain.
+        let txids = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ].iter().map(|txid| Sha256d::from_hex_le(txid).unwrap()).collect::<Vec<_>>();
+        let merkle_root = "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766";
+        let hash_pair = |a: &Sha256d, b: &Sha256d| Sha256d::digest([a.as_slice(), b.as_slice()].concat());
+        let left = hash_pair(&txids[0], &txids[1]);
+        let right = hash_pair(&txids[2], &txids[3]);
+
+        let root = merkle_root_from_branch(&txids[2], &[txids[3].clone(), left.clone()], 2);
+        assert_eq!(root.to_hex_le(), merkle_root);
+        let root = merkle_root_from_branch(&txids[1], &[txids[0].clone(), right.clone()], 1);
+        assert_eq!(root.to_hex_le(), merkle_root);
+        // The wrong position gives a different root.
+        let root = merkle_root_from_branch(&txids[1], &[txids[0].clone(), right], 0);
+        assert_ne!(root.to_hex_le(), merkle_root);
+        // A block with only a coinbase has its txid as merkle root.
+        assert_eq!(merkle_root_from_branch(&txids[0], &[], 0), txids[0]);
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        // Timestamps don't have to increase, the median of the last 11 counts.
+        let times = [
+            1000, 1600, 1300, 2200, 1900, 2500, 2400, 2800, 3100, 2900, 3400, 3600, 3300,
+        ];
+        let chain = headers(&times);
+        assert_eq!(median_time_past(&chain).unwrap(), 2800);
+        assert_eq!(median_time_past(&chain[..11]).unwrap(), 2400);
+        assert_eq!(median_time_past(&chain[..3]).unwrap(), 1300);
+        assert_eq!(median_time_past(&chain[..1]).unwrap(), 1000);
+        assert!(median_time_past(&[]).is_err());
+
+        // Headers that don't link up are rejected.
+        let mut broken = chain.clone();
+        broken.remove(5);
+        assert!(median_time_past(&broken).is_err());
+    }
+
+    #[test]
+    fn test_lock_time_finality() {
+        let tip = ChainTip {
+            height: 700_000,
+            median_time_past: 1_600_000_000,
+        };
+        assert_eq!(lock_time_finality(0, &tip), Finality::Final);
+        assert_eq!(lock_time_finality(700_000, &tip), Finality::Final);
+        assert_eq!(lock_time_finality(700_001, &tip), Finality::Blocks(1));
+        assert_eq!(lock_time_finality(700_010, &tip), Finality::Blocks(10));
+        assert_eq!(lock_time_finality(1_599_999_999, &tip), Finality::Final);
+        assert_eq!(lock_time_finality(1_600_000_000, &tip), Finality::Seconds(1));
+        assert_eq!(lock_time_finality(1_600_003_600, &tip), Finality::Seconds(3601));
+    }
+}
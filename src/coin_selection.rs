@@ -0,0 +1,235 @@
+use clap::ArgEnum;
+use bitcoin_cash::DUST_AMOUNT;
+
+/// Serialized size of a P2PKH input signed with a maximum-size signature and compressed pubkey.
+pub const P2PKH_INPUT_SIZE: usize = 32 + 4 + 1 + (1 + 72 + 1 + 33) + 4;
+
+/// Upper bound on the number of branches explored by branch-and-bound before giving up.
+const MAX_BNB_TRIES: usize = 100_000;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CoinSelection {
+    /// Add the largest UTXOs first until the fee is covered.
+    LargestFirst,
+    /// Search for a set of UTXOs that needs no change output, falling back to largest-first.
+    BranchAndBound,
+    /// Use the smallest single UTXO that covers the fee, falling back to largest-first.
+    SmallestSufficient,
+}
+
+/// What the gas inputs have to pay for.
+pub struct SelectionTarget {
+    /// Output value not covered by the tx's other inputs; negative if they leave a surplus.
+    pub shortfall: i64,
+    /// Size of the tx without any gas inputs and without a change output.
+    pub base_size: usize,
+    /// Size of the change output.
+    pub change_size: usize,
+    /// Fee rate in sat/byte.
+    pub fee_rate: u64,
+}
+
+impl SelectionTarget {
+    fn fee(&self, num_inputs: usize) -> i64 {
+        ((self.base_size + num_inputs * P2PKH_INPUT_SIZE) as u64 * self.fee_rate) as i64
+    }
+
+    /// Amount the selected inputs have to add up to, without a change output.
+    fn required(&self, num_inputs: usize) -> i64 {
+        self.shortfall + self.fee(num_inputs)
+    }
+
+    /// Excess up to which dropping the change output wastes less than creating it.
+    fn cost_of_change(&self) -> i64 {
+        (self.change_size as u64 * self.fee_rate + DUST_AMOUNT) as i64
+    }
+
+    fn effective_value(&self, value: u64) -> i64 {
+        value as i64 - (P2PKH_INPUT_SIZE as u64 * self.fee_rate) as i64
+    }
+}
+
+/// Selects UTXOs with the given values to cover `target`, returning their indices in ascending order,
+/// or `None` if all UTXOs together are insufficient.
+///
+/// The result only depends on the values and their order, so callers should sort UTXOs
+/// deterministically (e.g. by value and outpoint) beforehand.
+pub fn select_coins(values: &[u64], target: &SelectionTarget, strategy: CoinSelection) -> Option<Vec<usize>> {
+    if target.required(0) <= 0 {
+        return Some(vec![]);
+    }
+    let selected = match strategy {
+        CoinSelection::LargestFirst => None,
+        CoinSelection::BranchAndBound => branch_and_bound(values, target),
+        CoinSelection::SmallestSufficient => smallest_sufficient(values, target),
+    };
+    let mut selected = selected.or_else(|| largest_first(values, target))?;
+    selected.sort_unstable();
+    Some(selected)
+}
+
+fn indices_by_value_desc(values: &[u64]) -> Vec<usize> {
+    let mut indices = (0..values.len()).collect::<Vec<_>>();
+    indices.sort_by(|&a, &b| values[b].cmp(&values[a]).then(a.cmp(&b)));
+    indices
+}
+
+fn largest_first(values: &[u64], target: &SelectionTarget) -> Option<Vec<usize>> {
+    let mut selected = Vec::new();
+    let mut sum = 0i64;
+    for idx in indices_by_value_desc(values) {
+        selected.push(idx);
+        sum += values[idx] as i64;
+        if sum >= target.required(selected.len()) {
+            return Some(selected);
+        }
+    }
+    None
+}
+
+fn smallest_sufficient(values: &[u64], target: &SelectionTarget) -> Option<Vec<usize>> {
+    let mut indices = (0..values.len()).collect::<Vec<_>>();
+    indices.sort_by_key(|&idx| (values[idx], idx));
+    indices
+        .into_iter()
+        .find(|&idx| values[idx] as i64 >= target.required(1))
+        .map(|idx| vec![idx])
+}
+
+/// Depth-first search for a set of UTXOs whose effective value lands between the required amount
+/// and the cost of change, so that no change output is needed.
+fn branch_and_bound(values: &[u64], target: &SelectionTarget) -> Option<Vec<usize>> {
+    let candidates = indices_by_value_desc(values)
+        .into_iter()
+        .filter(|&idx| target.effective_value(values[idx]) > 0)
+        .collect::<Vec<_>>();
+    let effective = candidates.iter().map(|&idx| target.effective_value(values[idx])).collect::<Vec<_>>();
+    let lower = target.required(0);
+    let upper = lower + target.cost_of_change();
+    // remaining[i] is the sum of all effective values from position i on.
+    let mut remaining = vec![0; effective.len() + 1];
+    for i in (0..effective.len()).rev() {
+        remaining[i] = remaining[i + 1] + effective[i];
+    }
+    let mut included = vec![false; effective.len()];
+    let mut tries = 0;
+    if search(&effective, &remaining, lower, upper, 0, 0, &mut included, &mut tries) {
+        Some(candidates.into_iter().zip(included).filter(|(_, included)| *included).map(|(idx, _)| idx).collect())
+    } else {
+        None
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn search(
+    effective: &[i64],
+    remaining: &[i64],
+    lower: i64,
+    upper: i64,
+    pos: usize,
+    sum: i64,
+    included: &mut [bool],
+    tries: &mut usize,
+) -> bool {
+    *tries += 1;
+    if sum > upper || sum + remaining[pos] < lower || *tries > MAX_BNB_TRIES {
+        return false;
+    }
+    if sum >= lower {
+        return true;
+    }
+    if pos == effective.len() {
+        return false;
+    }
+    included[pos] = true;
+    if search(effective, remaining, lower, upper, pos + 1, sum + effective[pos], included, tries) {
+        return true;
+    }
+    included[pos] = false;
+    search(effective, remaining, lower, upper, pos + 1, sum, included, tries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(shortfall: i64) -> SelectionTarget {
+        SelectionTarget {
+            shortfall,
+            base_size: 200,
+            change_size: 34,
+            fee_rate: 1,
+        }
+    }
+
+    fn sum(values: &[u64], selected: &[usize]) -> u64 {
+        selected.iter().map(|&idx| values[idx]).sum()
+    }
+
+    #[test]
+    fn test_surplus_needs_no_inputs() {
+        let values = [10_000, 20_000];
+        for &strategy in &[CoinSelection::LargestFirst, CoinSelection::BranchAndBound, CoinSelection::SmallestSufficient] {
+            assert_eq!(select_coins(&values, &target(-1000), strategy), Some(vec![]));
+        }
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let values = [1_000, 2_000];
+        for &strategy in &[CoinSelection::LargestFirst, CoinSelection::BranchAndBound, CoinSelection::SmallestSufficient] {
+            assert_eq!(select_coins(&values, &target(5_000), strategy), None);
+        }
+    }
+
+    #[test]
+    fn test_largest_first() {
+        let values = [1_000, 50_000, 3_000, 20_000];
+        let selected = select_coins(&values, &target(30_000), CoinSelection::LargestFirst).unwrap();
+        assert_eq!(selected, vec![1]);
+        let selected = select_coins(&values, &target(60_000), CoinSelection::LargestFirst).unwrap();
+        assert_eq!(selected, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_smallest_sufficient() {
+        let values = [100_000, 5_000, 7_000, 6_000];
+        // 200 + 148 bytes at 1 sat/byte on top of 5_000
+        let selected = select_coins(&values, &target(5_000), CoinSelection::SmallestSufficient).unwrap();
+        assert_eq!(selected, vec![3]);
+        // No single UTXO suffices, falls back to largest-first
+        let selected = select_coins(&values, &target(104_000), CoinSelection::SmallestSufficient).unwrap();
+        assert_eq!(selected, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_branch_and_bound_exact_match() {
+        // Two inputs of 2_348 and 3_348 cover 5_000 plus fee for 200 + 2 * 148 bytes, leaving 200 sats.
+        let values = [10_000, 2_348, 9_000, 3_348, 12_000];
+        let t = target(5_000);
+        let selected = select_coins(&values, &t, CoinSelection::BranchAndBound).unwrap();
+        assert_eq!(selected, vec![1, 3]);
+        let excess = sum(&values, &selected) as i64 - t.required(selected.len());
+        assert!(excess >= 0 && excess <= t.cost_of_change());
+    }
+
+    #[test]
+    fn test_branch_and_bound_fallback() {
+        // Every combination leaves more than the cost of change, so largest-first is used.
+        let values = [100_000, 200_000];
+        let selected = select_coins(&values, &target(5_000), CoinSelection::BranchAndBound).unwrap();
+        assert_eq!(selected, vec![1]);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let values = [5_000, 5_000, 5_000, 5_000];
+        for &strategy in &[CoinSelection::LargestFirst, CoinSelection::BranchAndBound, CoinSelection::SmallestSufficient] {
+            let first = select_coins(&values, &target(4_000), strategy);
+            for _ in 0..10 {
+                assert_eq!(select_coins(&values, &target(4_000), strategy), first);
+            }
+            assert_eq!(first, Some(vec![0]));
+        }
+    }
+}
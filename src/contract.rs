@@ -1,4 +1,8 @@
-use bitcoin_cash::{Opcode::*, ByteArray, Hash160, Integer, Pubkey, Signatory, SignatoryKindOne, SigHashFlags, MAX_SIGNATURE_SIZE, TxPreimage, Script, TxOutput};
+use bitcoin_cash::{Opcode::*, Address, ByteArray, Hash160, Hashed, Integer, Pubkey, Signatory, SignatoryKindOne, SigHashFlags, MAX_SIGNATURE_SIZE, TxOutpoint, TxPreimage, Script, TxOutput};
+use anyhow::{Context, Result};
+
+use crate::error::ErrorKind;
+use crate::util;
 
 pub struct SlpHtlcParams {
     pub secret_hash: Hash160,
@@ -7,14 +11,29 @@ pub struct SlpHtlcParams {
     pub timeout: Integer,
 }
 
+/// Everything a counterparty needs to locate and spend a funded HTLC.
+///
+/// Serialized as `<txid>:<vout>,<buyer-address>,<seller-address>,<secret-hash>,<timeout>`.
+pub struct HtlcDescriptor {
+    pub contract_utxo: TxOutpoint,
+    pub buyer_address: Address<'static>,
+    pub seller_address: Address<'static>,
+    pub secret_hash: Hash160,
+    pub timeout: u32,
+}
+
+/// Signs a contract input, usually with `SigHashFlags::DEFAULT`. With `ANYONECANPAY`, the signature
+/// doesn't cover the other inputs, so a sponsor can add gas inputs afterwards.
 #[derive(Clone)]
 pub enum SlpHtlcSignatory {
     Redeem {
         seller_pk: Pubkey,
         secret: ByteArray,
+        sig_hash_flags: SigHashFlags,
     },
     Timeout {
         buyer_pk: Pubkey,
+        sig_hash_flags: SigHashFlags,
     },
 }
 
@@ -48,13 +67,84 @@ pub fn script(
     OP_CHECKSIG(sig, pk);
 }
 
+impl SlpHtlcParams {
+    /// The P2SH output script of contract UTXOs with these parameters.
+    pub fn p2sh_script(&self) -> Script {
+        // The address prefix doesn't affect the script.
+        Address::from_redeem_script("bitcoincash", self.script().into()).expect("infallible").into()
+    }
+}
+
+impl HtlcDescriptor {
+    pub fn parse(descriptor: &str, prefix: &str) -> Result<Self> {
+        let descriptor_msg = "Invalid contract descriptor, must be of form \
+            <txid>:<vout>,<buyer-address>,<seller-address>,<secret-hash>,<timeout>";
+        let parts = descriptor.split(',').collect::<Vec<_>>();
+        if parts.len() != 5 {
+            bail_kind!(ErrorKind::InvalidInput, descriptor_msg);
+        }
+        let secret_hash = Hash160::from_hex_be(parts[3])
+            .with_context(|| format!("Invalid secret hash: {}", parts[3]))
+            .context(ErrorKind::InvalidInput)?;
+        let timeout = parts[4].parse()
+            .with_context(|| format!("Invalid timeout: {}", parts[4]))
+            .context(ErrorKind::InvalidInput)?;
+        Ok(HtlcDescriptor {
+            contract_utxo: util::parse_outpoint(parts[0])?,
+            buyer_address: util::parse_p2pkh_address(parts[1], prefix, "Buyer")?,
+            seller_address: util::parse_p2pkh_address(parts[2], prefix, "Seller")?,
+            secret_hash,
+            timeout,
+        })
+    }
+
+    pub fn params(&self) -> Result<SlpHtlcParams> {
+        Ok(SlpHtlcParams {
+            secret_hash: self.secret_hash.clone(),
+            seller_pkh: self.seller_address.hash().clone(),
+            buyer_pkh: self.buyer_address.hash().clone(),
+            timeout: Integer::new(self.timeout)
+                .with_context(|| format!("Invalid timeout: {}", self.timeout))
+                .context(ErrorKind::InvalidInput)?,
+        })
+    }
+}
+
+impl std::fmt::Display for HtlcDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{},{},{},{},{}",
+            self.contract_utxo.tx_hash,
+            self.contract_utxo.vout,
+            self.buyer_address.cash_addr(),
+            self.seller_address.cash_addr(),
+            self.secret_hash.to_hex_be(),
+            self.timeout,
+        )
+    }
+}
+
+impl SlpHtlcSignatory {
+    pub fn with_sig_hash_flags(mut self, flags: SigHashFlags) -> Self {
+        match &mut self {
+            SlpHtlcSignatory::Redeem { sig_hash_flags, .. } | SlpHtlcSignatory::Timeout { sig_hash_flags, .. } => {
+                *sig_hash_flags = flags;
+            }
+        }
+        self
+    }
+}
+
 impl Signatory for SlpHtlcSignatory {
     type Script=SlpHtlcInputs;
     type Signatures=ByteArray;
     type Kind=SignatoryKindOne;
 
     fn sig_hash_flags(&self) -> SigHashFlags {
-        SigHashFlags::DEFAULT
+        match *self {
+            SlpHtlcSignatory::Redeem { sig_hash_flags, .. } | SlpHtlcSignatory::Timeout { sig_hash_flags, .. } => sig_hash_flags,
+        }
     }
 
     fn placeholder_signatures(&self) -> Self::Signatures {
@@ -71,7 +161,7 @@ impl Signatory for SlpHtlcSignatory {
     ) -> Self::Script {
         let sig = sig.concat([self.sig_hash_flags().bits() as u8]);
         match *self {
-            SlpHtlcSignatory::Redeem { seller_pk, ref secret } => {
+            SlpHtlcSignatory::Redeem { seller_pk, ref secret, .. } => {
                 SlpHtlcInputs::Redeem {
                     sig,
                     pk: seller_pk,
@@ -79,7 +169,7 @@ impl Signatory for SlpHtlcSignatory {
                     is_redeem: true,
                 }
             }
-            SlpHtlcSignatory::Timeout { buyer_pk } => {
+            SlpHtlcSignatory::Timeout { buyer_pk, .. } => {
                 SlpHtlcInputs::Timeout {
                     sig,
                     pk: buyer_pk,
@@ -89,3 +179,198 @@ impl Signatory for SlpHtlcSignatory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_cash::{deserialize_ops, BitcoinCode, Op, Opcode, Sha256d, TxBuilder, TxInput, UnhashedTx, UnsignedTxInput, ECC};
+    use bitcoin_cash_ecc::init_ecc;
+
+    use crate::interpreter::{eval_input, InputCheck, ScriptError, ScriptKind};
+
+    const SELLER_SK: [u8; 32] = [0x11; 32];
+    const BUYER_SK: [u8; 32] = [0x22; 32];
+    const OTHER_SK: [u8; 32] = [0x33; 32];
+    const SECRET: &[u8] = b"the secret";
+    const TIMEOUT: u32 = 1_000;
+    const CONTRACT_VALUE: u64 = 10_000;
+
+    fn params() -> SlpHtlcParams {
+        let ecc = init_ecc();
+        let pkh = |sk: &[u8]| Address::from_pk("bitcoincash", &ecc.derive_pubkey(sk).unwrap()).hash().clone();
+        SlpHtlcParams {
+            secret_hash: Hash160::digest(SECRET.to_vec()),
+            seller_pkh: pkh(&SELLER_SK),
+            buyer_pkh: pkh(&BUYER_SK),
+            timeout: Integer::new(TIMEOUT).unwrap(),
+        }
+    }
+
+    fn redeem(sk: &[u8], secret: &[u8]) -> (SlpHtlcSignatory, Vec<u8>) {
+        let seller_pk = init_ecc().derive_pubkey(sk).unwrap();
+        (SlpHtlcSignatory::Redeem { seller_pk, secret: secret.to_vec().into(), sig_hash_flags: SigHashFlags::DEFAULT }, sk.to_vec())
+    }
+
+    fn timeout(sk: &[u8]) -> (SlpHtlcSignatory, Vec<u8>) {
+        let buyer_pk = init_ecc().derive_pubkey(sk).unwrap();
+        (SlpHtlcSignatory::Timeout { buyer_pk, sig_hash_flags: SigHashFlags::DEFAULT }, sk.to_vec())
+    }
+
+    /// Signs a tx spending the HTLC with `params` with the given signatory, lock time and sequence.
+    fn spend(params: &SlpHtlcParams, (signatory, sk): (SlpHtlcSignatory, Vec<u8>), lock_time: u32, sequence: u32) -> UnhashedTx {
+        let ecc = init_ecc();
+        let mut tx_builder = TxBuilder::new_with_fee(2, lock_time, 1000);
+        let input_ref = tx_builder.add_input(
+            UnsignedTxInput {
+                prev_out: TxOutpoint { tx_hash: Sha256d::new([0xaa; 32]), vout: 1 },
+                sequence,
+                value: CONTRACT_VALUE,
+            },
+            params.script(),
+            signatory,
+        );
+        tx_builder.add_leftover_output(params.p2sh_script());
+        let mut unsigned_tx = tx_builder.build().unwrap();
+        let sig = ecc.sign(&sk, Sha256d::digest(unsigned_tx.input_preimages(input_ref).ser())).unwrap();
+        unsigned_tx.sign_input(input_ref, sig).unwrap();
+        unsigned_tx.complete_tx()
+    }
+
+    /// Evaluates the only input of `tx` against the HTLC with the parameters from `params()`.
+    fn eval(tx: &UnhashedTx) -> Result<InputCheck, ScriptError> {
+        let prev_outputs = vec![TxOutput { value: CONTRACT_VALUE, script: params().p2sh_script() }];
+        eval_input(&init_ecc(), tx, &prev_outputs, 0)
+    }
+
+    /// Asserts that the redeem script failed at op `op_idx`, which is `opcode`.
+    fn assert_fails_at(result: Result<InputCheck, ScriptError>, op_idx: usize, opcode: Opcode) {
+        let err = result.unwrap_err();
+        assert_eq!(err.script, ScriptKind::RedeemScript, "{}", err);
+        assert_eq!(err.op_idx, Some(op_idx), "{}", err);
+        assert_eq!(err.op, Some(Op::Code(opcode)), "{}", err);
+    }
+
+    /// Replaces the sighash flags appended to the signature of the only input of `tx`.
+    fn set_sig_hash_flags(tx: &mut UnhashedTx, flags: u8) {
+        let mut ops = deserialize_ops(&tx.inputs[0].script.ser_ops()).unwrap();
+        if let Op::PushByteArray { array, .. } = &ops[0] {
+            let mut sig = array.to_vec();
+            *sig.last_mut().unwrap() = flags;
+            ops[0] = Op::PushByteArray { array: sig.into(), is_minimal: true };
+        }
+        tx.inputs[0] = TxInput::new(tx.inputs[0].prev_out.clone(), Script::from_ops(ops), tx.inputs[0].sequence);
+    }
+
+    // Ops of the redeem script where the tests below fail.
+    const OP_IDX_SECRET_HASH: usize = 3;
+    const OP_IDX_CLTV: usize = 7;
+    const OP_IDX_PKH: usize = 13;
+    const OP_IDX_CHECKSIG: usize = 14;
+
+    #[test]
+    fn test_redeem() {
+        let tx = spend(&params(), redeem(&SELLER_SK, SECRET), 0, 0xffff_ffff);
+        assert_eq!(eval(&tx), Ok(InputCheck::Verified));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let tx = spend(&params(), timeout(&BUYER_SK), TIMEOUT, 0xffff_fffe);
+        assert_eq!(eval(&tx), Ok(InputCheck::Verified));
+        let tx = spend(&params(), timeout(&BUYER_SK), TIMEOUT + 1, 0);
+        assert_eq!(eval(&tx), Ok(InputCheck::Verified));
+    }
+
+    #[test]
+    fn test_redeem_wrong_secret() {
+        let tx = spend(&params(), redeem(&SELLER_SK, b"wrong secret"), 0, 0xffff_ffff);
+        assert_fails_at(eval(&tx), OP_IDX_SECRET_HASH, Opcode::OP_EQUALVERIFY);
+        let tx = spend(&params(), redeem(&SELLER_SK, b""), 0, 0xffff_ffff);
+        assert_fails_at(eval(&tx), OP_IDX_SECRET_HASH, Opcode::OP_EQUALVERIFY);
+    }
+
+    #[test]
+    fn test_redeem_wrong_pubkey() {
+        let tx = spend(&params(), redeem(&OTHER_SK, SECRET), 0, 0xffff_ffff);
+        assert_fails_at(eval(&tx), OP_IDX_PKH, Opcode::OP_EQUALVERIFY);
+        let tx = spend(&params(), timeout(&OTHER_SK), TIMEOUT, 0xffff_fffe);
+        assert_fails_at(eval(&tx), OP_IDX_PKH, Opcode::OP_EQUALVERIFY);
+    }
+
+    #[test]
+    fn test_buyer_redeem_branch() {
+        // Even knowing the secret, the buyer can't take the redeem branch.
+        let tx = spend(&params(), redeem(&BUYER_SK, SECRET), 0, 0xffff_ffff);
+        assert_fails_at(eval(&tx), OP_IDX_PKH, Opcode::OP_EQUALVERIFY);
+    }
+
+    #[test]
+    fn test_seller_refund_branch() {
+        // Even after the timeout, the seller can't take the refund branch.
+        let tx = spend(&params(), timeout(&SELLER_SK), TIMEOUT, 0xffff_fffe);
+        assert_fails_at(eval(&tx), OP_IDX_PKH, Opcode::OP_EQUALVERIFY);
+    }
+
+    #[test]
+    fn test_timeout_lock_time_too_early() {
+        let tx = spend(&params(), timeout(&BUYER_SK), TIMEOUT - 1, 0xffff_fffe);
+        assert_fails_at(eval(&tx), OP_IDX_CLTV, Opcode::OP_CHECKLOCKTIMEVERIFY);
+        let tx = spend(&params(), timeout(&BUYER_SK), 0, 0xffff_fffe);
+        assert_fails_at(eval(&tx), OP_IDX_CLTV, Opcode::OP_CHECKLOCKTIMEVERIFY);
+        // A timestamp lock time doesn't satisfy a block height timeout.
+        let tx = spend(&params(), timeout(&BUYER_SK), 1_600_000_000, 0xffff_fffe);
+        assert_fails_at(eval(&tx), OP_IDX_CLTV, Opcode::OP_CHECKLOCKTIMEVERIFY);
+    }
+
+    #[test]
+    fn test_timeout_final_sequence() {
+        let tx = spend(&params(), timeout(&BUYER_SK), TIMEOUT, 0xffff_ffff);
+        assert_fails_at(eval(&tx), OP_IDX_CLTV, Opcode::OP_CHECKLOCKTIMEVERIFY);
+    }
+
+    #[test]
+    fn test_wrong_sig_hash_flags() {
+        let valid_tx = spend(&params(), redeem(&SELLER_SK, SECRET), 0, 0xffff_ffff);
+        // Signed with ALL|FORKID, but claiming other flags changes the signed preimage.
+        for &flags in &[0xc1, 0x42, 0x43] {
+            let mut tx = valid_tx.clone();
+            set_sig_hash_flags(&mut tx, flags);
+            assert_fails_at(eval(&tx), OP_IDX_CHECKSIG, Opcode::OP_CHECKSIG);
+        }
+        // Flags without FORKID or with an undefined base type are invalid regardless of the signature.
+        for &flags in &[0x01, 0x40, 0x44, 0x61] {
+            let mut tx = valid_tx.clone();
+            set_sig_hash_flags(&mut tx, flags);
+            assert_fails_at(eval(&tx), OP_IDX_CHECKSIG, Opcode::OP_CHECKSIG);
+        }
+    }
+
+    #[test]
+    fn test_anyone_can_pay() {
+        let prev_outputs = vec![
+            TxOutput { value: CONTRACT_VALUE, script: params().p2sh_script() },
+            TxOutput { value: 5_000, script: Script::default() },
+        ];
+        let gas_input = TxInput::new(TxOutpoint { tx_hash: Sha256d::new([0xbb; 32]), vout: 0 }, Script::default(), 0xffff_ffff);
+        // Inputs added after signing with ANYONECANPAY, e.g. a sponsor's gas, keep the signature valid.
+        let (signatory, sk) = redeem(&SELLER_SK, SECRET);
+        let signatory = signatory.with_sig_hash_flags(SigHashFlags::DEFAULT | SigHashFlags::ANYONECANPAY);
+        let mut tx = spend(&params(), (signatory, sk), 0, 0xffff_ffff);
+        assert_eq!(eval(&tx), Ok(InputCheck::Verified));
+        tx.inputs.push(gas_input.clone());
+        assert_eq!(eval_input(&init_ecc(), &tx, &prev_outputs, 0), Ok(InputCheck::Verified));
+        // Without ANYONECANPAY, they don't.
+        let mut tx = spend(&params(), redeem(&SELLER_SK, SECRET), 0, 0xffff_ffff);
+        tx.inputs.push(gas_input);
+        assert_fails_at(eval_input(&init_ecc(), &tx, &prev_outputs, 0), OP_IDX_CHECKSIG, Opcode::OP_CHECKSIG);
+    }
+
+    #[test]
+    fn test_wrong_contract() {
+        // A spend of a contract with other parameters doesn't match the P2SH script.
+        let other_params = SlpHtlcParams { timeout: Integer::new(TIMEOUT + 1).unwrap(), ..params() };
+        let tx = spend(&other_params, redeem(&SELLER_SK, SECRET), 0, 0xffff_ffff);
+        let err = eval(&tx).unwrap_err();
+        assert_eq!(err.script, ScriptKind::LockScript, "{}", err);
+    }
+}
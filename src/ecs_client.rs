@@ -1,11 +1,65 @@
 use bitcoin_cash::{Address, TxOutpoint, Sha256d, Hashed};
 use chttp::{http::StatusCode, prelude::*};
+use clap::Clap;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 
+use crate::amount::parse_bch_amount;
+use crate::chain::{self, BlockHeader, ChainTip, MEDIAN_TIME_SPAN};
+use crate::error::ErrorKind;
+use crate::redact::Redactor;
+
+/// Upper bound for the delay between two attempts of an RPC call.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// JSON-RPC error code of calls to methods the daemon doesn't have.
+const METHOD_NOT_FOUND: i32 = -32601;
+/// Rejection reasons of a tx the node already has, e.g. because an attempt that timed out got through.
+const ALREADY_KNOWN: &[&str] = &["txn-already-known", "txn-already-in-mempool", "already in mempool", "already in block chain"];
+
 pub struct ECSClient<'a> {
     uri: String,
     address_prefix: &'a str,
+    retry_policy: RetryPolicy,
+    /// Masks the URI's credentials and secrets of requests and responses in errors.
+    redactor: Redactor,
+}
+
+#[derive(Clap)]
+pub struct RetryOpts {
+    /// How often to attempt an RPC call before giving up, if the wallet daemon is unreachable.
+    #[clap(long, default_value = "5")]
+    rpc_max_attempts: u32,
+    /// Delay before retrying a failed RPC call in milliseconds, doubled after every attempt.
+    #[clap(long, default_value = "500")]
+    rpc_backoff_ms: u64,
+    /// Timeout of a single RPC request in seconds.
+    #[clap(long, default_value = "60")]
+    rpc_timeout: u64,
+}
+
+/// How RPC calls are retried if the wallet daemon can't be reached or fails with a server error.
+/// Errors returned by the RPC method itself are never retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub timeout: Duration,
+}
+
+/// Failure of a single attempt of an RPC call.
+enum RequestError {
+    /// The request might succeed if tried again, e.g. the daemon is down or timed out.
+    Transient(anyhow::Error),
+    /// The daemon responded with an error, trying again won't help.
+    Definitive(anyhow::Error),
+}
+
+/// An error returned by an RPC method, with its JSON-RPC error code. Tagged `ErrorKind::RpcError`.
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i32,
+    message: String,
 }
 
 pub struct Utxo {
@@ -18,14 +72,25 @@ impl<'a> ECSClient<'a> {
     pub fn new(
         uri: String,
         address_prefix: &'a str,
+        retry_policy: RetryPolicy,
     ) -> Self {
         ECSClient {
+            redactor: Redactor::new(&uri),
             uri,
             address_prefix,
+            retry_policy,
         }
     }
 
-    pub fn createaddress(&self) -> Result<Address> {
+    pub fn address_prefix(&self) -> &'a str {
+        self.address_prefix
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    pub fn createaddress(&self) -> Result<Address<'static>> {
         #[derive(serde::Serialize)]
         struct Params {}
         let address_suffix: String = self.ecs_request(
@@ -39,57 +104,54 @@ impl<'a> ECSClient<'a> {
         return Ok(address)
     }
 
-    pub fn payto_slp(&self, token_id: &str, amount: &str, destination: &str) -> Result<String> {
-        #[derive(serde::Serialize)]
-        struct Params<'a> {
-            token_id: &'a str,
-            amount_slp: &'a str,
-            destination_slp: &'a str,
-        }
-        #[derive(serde::Deserialize)]
-        struct Res {
-            hex: String,
-        }
-
-        let result: Res = self.ecs_request(
-            "payto_slp",
-            Params {
-                token_id,
-                amount_slp: amount,
-                destination_slp: destination,
-            }
-        )?;
-        return Ok(result.hex)
-    }
-
-    pub fn signtransaction(&self, tx_hex: &str) -> Result<String> {
+    /// Broadcasts the tx, returning its txid. A tx the node already has counts as broadcast.
+    pub fn broadcast(&self, tx_hex: &str) -> Result<String> {
         #[derive(serde::Serialize)]
         struct Params<'a> {
             tx: &'a str,
         }
-        #[derive(serde::Deserialize)]
-        struct Res {
-            hex: String,
-        }
 
-        let result: Res = self.ecs_request(
-            "signtransaction",
+        let is_already_known = |message: &str| {
+            let message = message.to_lowercase();
+            ALREADY_KNOWN.iter().any(|reason| message.contains(reason))
+        };
+        let txid = || -> Result<String> {
+            let raw_tx = hex::decode(tx_hex).with_context(|| "Invalid tx hex").context(ErrorKind::Internal)?;
+            Ok(Sha256d::digest(raw_tx).to_hex_le())
+        };
+        let result: Result<(bool, String)> = self.ecs_request(
+            "broadcast",
             Params {tx: tx_hex}
-        )?;
-        return Ok(result.hex)
+        );
+        let (success, message) = match result {
+            Ok(result) => result,
+            Err(err) if err.downcast_ref::<RpcError>().is_some_and(|err| is_already_known(&err.message)) => {
+                return txid();
+            }
+            // An error for the broadcast call itself means the node refused the tx.
+            Err(err) if ErrorKind::of(&err) == ErrorKind::RpcError => {
+                return Err(err.context(ErrorKind::BroadcastRejected));
+            }
+            Err(err) => return Err(err),
+        };
+        if !success || message.starts_with("error") {
+            if is_already_known(&message) {
+                return txid();
+            }
+            bail_kind!(ErrorKind::BroadcastRejected, "broadcast rejected: {}", self.redactor.text(&message));
+        }
+        return Ok(message)
     }
 
-    pub fn broadcast(&self, tx_hex: &str) -> Result<String> {
+    /// Fee rate estimate of the wallet's server, in sat/kB, or `None` if the daemon doesn't support it.
+    pub fn getfeerate(&self) -> Result<Option<u64>> {
         #[derive(serde::Serialize)]
-        struct Params<'a> {
-            tx: &'a str,
+        struct Params {}
+        match self.ecs_request("getfeerate", Params {}) {
+            Ok(result) => Ok(Some(result)),
+            Err(err) if err.downcast_ref::<RpcError>().map(|err| err.code) == Some(METHOD_NOT_FOUND) => Ok(None),
+            Err(err) => Err(err),
         }
-
-        let result: (bool, String) = self.ecs_request(
-            "broadcast",
-            Params {tx: tx_hex}
-        )?;
-        return Ok(result.1)
     }
 
     pub fn slpvalidate(&self, txid: &str) -> Result<bool> {
@@ -100,21 +162,16 @@ impl<'a> ECSClient<'a> {
             reset: bool,
         }
 
-        let result: Result<String> = self.ecs_request(
+        let result: String = self.ecs_request(
             "slpvalidate",
             Params {
                 txid,
                 debug: true,
                 reset: false,
             },
-        );
-        if result.is_err() {
-            println!("retry slpvalidate...");
-            return self.slpvalidate(txid);
-        }
-        let result = result?;
+        )?;
         if result != "Valid" {
-            println!("SLP result: {}", result);
+            eprintln!("SLP result: {}", result);
         }
         return Ok(result == "Valid")
     }
@@ -142,11 +199,11 @@ impl<'a> ECSClient<'a> {
             let address = Address::from_cash_addr(&cash_addr)
                 .with_context(|| format!("listunspent invalid address generated: {}", cash_addr))?
                 .to_owned_address();
-            let value: f64 = unspent.value.parse()
+            let value = parse_bch_amount(&unspent.value)
                 .with_context(|| format!("listunspent invalid value: {:?}", unspent.value))?;
             utxos.push(Utxo {
                 address,
-                value: (value * 100_000_000.0).round() as u64,  // this *should* always be exact
+                value,
                 outpoint: TxOutpoint {
                     tx_hash: Sha256d::from_hex_le(&unspent.prevout_hash)?,
                     vout: unspent.prevout_n,
@@ -157,6 +214,40 @@ impl<'a> ECSClient<'a> {
         return Ok(utxos)
     }
 
+    /// UTXOs of `address`, which doesn't have to be part of the wallet.
+    pub fn getaddressunspent(&self, address: &Address<'static>) -> Result<Vec<Utxo>> {
+        #[derive(serde::Serialize)]
+        struct Params<'a> {
+            address: &'a str,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Unspent {
+            tx_hash: String,
+            tx_pos: u32,
+            value: u64,
+        }
+
+        let result: Vec<Unspent> = self.ecs_request(
+            "getaddressunspent",
+            Params { address: address.cash_addr() },
+        )?;
+        let mut utxos = Vec::with_capacity(result.len());
+        for unspent in result {
+            utxos.push(Utxo {
+                address: address.clone(),
+                value: unspent.value,
+                outpoint: TxOutpoint {
+                    tx_hash: Sha256d::from_hex_le(&unspent.tx_hash)
+                        .with_context(|| format!("getaddressunspent invalid tx hash: {}", unspent.tx_hash))
+                        .context(ErrorKind::RpcTransport)?,
+                    vout: unspent.tx_pos,
+                },
+            });
+        }
+        return Ok(utxos)
+    }
+
     pub fn gettransaction(&self, txid: &str) -> Result<String> {
         #[derive(serde::Serialize)]
         struct Params<'a> {
@@ -175,6 +266,68 @@ impl<'a> ECSClient<'a> {
         return Ok(result.hex)
     }
 
+    /// Height of the chain tip, as known to the daemon.
+    pub fn blockchain_height(&self) -> Result<u32> {
+        #[derive(serde::Serialize)]
+        struct Params {}
+        #[derive(serde::Deserialize)]
+        struct Res {
+            blockchain_height: u32,
+        }
+        let result: Res = self.ecs_request(
+            "getinfo",
+            Params {},
+        )?;
+        return Ok(result.blockchain_height)
+    }
+
+    /// Header of the block at `height` of the daemon's chain.
+    pub fn getblockheader(&self, height: u32) -> Result<BlockHeader> {
+        #[derive(serde::Serialize)]
+        struct Params {
+            height: u32,
+        }
+        let result: String = self.ecs_request(
+            "getblockheader",
+            Params { height },
+        )?;
+        let raw = hex::decode(&result)
+            .with_context(|| format!("getblockheader invalid header: {}", result))
+            .context(ErrorKind::RpcTransport)?;
+        BlockHeader::parse(&raw)
+    }
+
+    /// Height and median time past of the daemon's chain tip.
+    pub fn chain_tip(&self) -> Result<ChainTip> {
+        let height = self.blockchain_height()?;
+        let start_height = height.saturating_sub(MEDIAN_TIME_SPAN as u32 - 1);
+        let headers = (start_height..=height)
+            .map(|height| self.getblockheader(height))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ChainTip {
+            height,
+            median_time_past: chain::median_time_past(&headers)?,
+        })
+    }
+
+    /// Transactions involving `address` as `(txid, height)` pairs. Unconfirmed txs have a height of 0 or less.
+    pub fn getaddresshistory(&self, address: &str) -> Result<Vec<(String, i32)>> {
+        #[derive(serde::Serialize)]
+        struct Params<'a> {
+            address: &'a str,
+        }
+        #[derive(serde::Deserialize)]
+        struct Entry {
+            tx_hash: String,
+            height: i32,
+        }
+        let result: Vec<Entry> = self.ecs_request(
+            "getaddresshistory",
+            Params { address },
+        )?;
+        return Ok(result.into_iter().map(|entry| (entry.tx_hash, entry.height)).collect())
+    }
+
     pub fn getprivatekeys(&self, address: &str) -> Result<[u8; 32]> {
         #[derive(serde::Serialize)]
         struct Params<'a> {
@@ -184,7 +337,9 @@ impl<'a> ECSClient<'a> {
             "getprivatekeys",
             Params { address },
         )?;
-        let sk = bitcoin::PrivateKey::from_wif(&result).with_context(|| "getprivatekeys invalid private key")?;
+        // The parse error could tell parts of the key.
+        let sk = bitcoin::PrivateKey::from_wif(&result)
+            .map_err(|_| anyhow::anyhow!("getprivatekeys returned an invalid private key"))?;
         return Ok(*sk.key.as_ref())
     }
 
@@ -196,6 +351,36 @@ impl<'a> ECSClient<'a> {
             params: P,
         }
 
+        let req = Req {
+            id: 0,
+            method,
+            params,
+        };
+        let body = serde_json::to_string(&req)
+            .with_context(|| format!("{} JSON to_string failed", method))?;
+        let mut backoff = self.retry_policy.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match self.ecs_request_once(method, &body) {
+                Ok(result) => return Ok(result),
+                Err(RequestError::Definitive(err)) => return Err(err),
+                Err(RequestError::Transient(err)) if attempt >= self.retry_policy.max_attempts => {
+                    return Err(err.context(format!("{} failed after {} attempts", method, attempt)));
+                }
+                Err(RequestError::Transient(err)) => {
+                    eprintln!(
+                        "{} failed ({:#}), retrying in {} ms ({}/{})",
+                        method, err, backoff.as_millis(), attempt, self.retry_policy.max_attempts,
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn ecs_request_once<R: serde::de::DeserializeOwned>(&self, method: &str, body: &str) -> Result<R, RequestError> {
         #[derive(serde::Deserialize)]
         struct Resp<R> {
             result: Option<R>,
@@ -203,34 +388,307 @@ impl<'a> ECSClient<'a> {
         }
 
         #[derive(serde::Deserialize)]
-        #[allow(dead_code)]
         struct ErrorJson {
             code: i32,
             message: String,
         }
 
-        let req = Req {
-            id: 0,
-            method,
-            params,
+        // Everything but an error returned by the RPC method means we can't talk to the daemon properly.
+        let definitive = |err: anyhow::Error| RequestError::Definitive(err.context(ErrorKind::RpcTransport));
+        let transient = |err: anyhow::Error| RequestError::Transient(err.context(ErrorKind::RpcTransport));
+        let request = Request::post(&self.uri)
+            .timeout(self.retry_policy.timeout)
+            .connect_timeout(self.retry_policy.timeout)
+            .body(body.to_string())
+            .with_context(|| format!("{} body failed", method))
+            .map_err(definitive)?;
+        let mut response = match request.send() {
+            Ok(response) => response,
+            Err(err) => {
+                let is_transient = is_transient(&err);
+                let err = anyhow::anyhow!("{} send failed: {}", method, self.redactor.text(&err.to_string()));
+                return Err(if is_transient { transient(err) } else { definitive(err) });
+            }
         };
-        let body = serde_json::to_string(&req)
-            .with_context(|| format!("{} JSON to_string failed", method))?;
-        let mut response = Request::post(&self.uri)
-            .body(body.clone())
-            .with_context(|| format!("{} body failed", method))?
-            .send()
-            .with_context(|| format!("{} send failed", method))?;
-        if response.status() == StatusCode::OK {
-            let response_text = response.text()?;
-            let resp: Resp<R> = serde_json::from_str(&response_text)
-                .with_context(|| format!("{} invalid json: {}", method, response_text))?;
-            if let Some(err) = resp.error {
-                anyhow::bail!("{} error: {} (for {})", method, err.message, body)
+        let status = response.status();
+        let response_text = response.text()
+            .with_context(|| format!("{} reading response failed", method))
+            .map_err(transient)?;
+        // JSON-RPC errors are definitive, even if the server sends them with an error status.
+        let resp = serde_json::from_str::<Resp<R>>(&response_text);
+        if let Ok(Resp { error: Some(err), .. }) = &resp {
+            let err = anyhow::Error::new(RpcError {
+                code: err.code,
+                message: format!(
+                    "{} error: {} (for {})", method, self.redactor.text(&err.message), self.redactor.params(method, body),
+                ),
+            });
+            return Err(RequestError::Definitive(err.context(ErrorKind::RpcError)));
+        }
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            let response_text = self.redactor.response(method, &response_text);
+            return Err(transient(anyhow::anyhow!("{} server error {}: {}", method, status, response_text)));
+        }
+        if status != StatusCode::OK {
+            let response_text = self.redactor.response(method, &response_text);
+            return Err(definitive(anyhow::anyhow!("{} invalid response: {}", method, response_text)));
+        }
+        // Errors of serde_json quote the offending values.
+        let resp = resp
+            .map_err(|err| anyhow::anyhow!(
+                "{} invalid json: {} ({})",
+                method, self.redactor.response(method, &err.to_string()), self.redactor.response(method, &response_text),
+            ))
+            .map_err(definitive)?;
+        return resp.result
+            .ok_or_else(|| definitive(anyhow::anyhow!("{} returned neither result nor error", method)));
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl RetryOpts {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.rpc_max_attempts.max(1),
+            initial_backoff: Duration::from_millis(self.rpc_backoff_ms),
+            timeout: Duration::from_secs(self.rpc_timeout),
+        }
+    }
+}
+
+/// Whether a transport error might go away by trying again.
+fn is_transient(err: &chttp::Error) -> bool {
+    use chttp::Error::*;
+    matches!(
+        err,
+        Aborted | ConnectFailed | CouldntResolveHost | CouldntResolveProxy | Io(_) | NoResponse
+            | RequestBodyError(_) | ResponseBodyError(_) | Timeout | TooManyConnections | Curl(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// What the mock server does with a request.
+    #[derive(Clone, Debug)]
+    enum Reply {
+        Respond(u16, &'static str),
+        /// Read the request but never answer, to trigger a timeout.
+        Hang,
+    }
+
+    /// Serves the given replies in order, repeating the last one, and counts the requests.
+    fn mock_server(replies: Vec<Reply>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let num_requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&num_requests);
+        std::thread::spawn(move || {
+            let mut hung = Vec::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let idx = counter.fetch_add(1, Ordering::SeqCst);
+                read_request(&mut stream);
+                match replies[idx.min(replies.len() - 1)].clone() {
+                    Reply::Respond(status, body) => {
+                        let response = format!(
+                            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status, body.len(), body,
+                        );
+                        stream.write_all(response.as_bytes()).unwrap();
+                    }
+                    Reply::Hang => hung.push(stream),
+                }
+            }
+        });
+        (uri, num_requests)
+    }
+
+    fn read_request(stream: &mut TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(length) = line.strip_prefix("content-length:") {
+                content_length = length.trim().parse().unwrap();
+            }
+            if line == "expect: 100-continue" {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+    }
+
+    fn client(uri: String, max_attempts: u32) -> ECSClient<'static> {
+        ECSClient::new(uri, "slptest", RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            timeout: Duration::from_millis(500),
+        })
+    }
+
+    #[test]
+    fn test_success() {
+        let (uri, num_requests) = mock_server(vec![Reply::Respond(200, r#"{"result": 1500}"#)]);
+        assert_eq!(client(uri, 3).getfeerate().unwrap(), Some(1500));
+        assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_retry_server_errors() {
+        let (uri, num_requests) = mock_server(vec![
+            Reply::Respond(503, "unavailable"),
+            Reply::Respond(500, "internal error"),
+            Reply::Respond(200, r#"{"result": "Valid"}"#),
+        ]);
+        assert!(client(uri, 3).slpvalidate("00").unwrap());
+        assert_eq!(num_requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_give_up_after_max_attempts() {
+        let (uri, num_requests) = mock_server(vec![Reply::Respond(503, "unavailable")]);
+        let err = client(uri, 4).slpvalidate("00").unwrap_err();
+        assert!(format!("{:#}", err).contains("slpvalidate failed after 4 attempts"));
+        assert_eq!(num_requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_rpc_error_is_definitive() {
+        let (uri, num_requests) = mock_server(vec![
+            Reply::Respond(200, r#"{"error": {"code": -32000, "message": "txid not found"}}"#),
+            Reply::Respond(200, r#"{"result": "Valid"}"#),
+        ]);
+        let err = client(uri, 5).slpvalidate("00").unwrap_err();
+        assert!(format!("{:#}", err).contains("txid not found"));
+        assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_rpc_error_with_server_error_status_is_definitive() {
+        let (uri, num_requests) = mock_server(vec![
+            Reply::Respond(500, r#"{"error": {"code": -32603, "message": "wallet not loaded"}}"#),
+        ]);
+        assert!(client(uri, 5).getfeerate().is_err());
+        assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_fee_rate_method_not_found() {
+        let (uri, _) = mock_server(vec![
+            Reply::Respond(200, r#"{"error": {"code": -32601, "message": "Method not found"}}"#),
+        ]);
+        assert_eq!(client(uri, 1).getfeerate().unwrap(), None);
+        let (uri, _) = mock_server(vec![Reply::Respond(200, r#"{"error": {"code": -32603, "message": "wallet not loaded"}}"#)]);
+        let err = client(uri, 1).getfeerate().unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::RpcError);
+        assert_eq!(err.downcast_ref::<RpcError>().unwrap().code, -32603);
+    }
+
+    #[test]
+    fn test_broadcast_already_known() {
+        let tx_hex = "0100000000000000000000";
+        let txid = Sha256d::digest(hex::decode(tx_hex).unwrap()).to_hex_le();
+        // The first attempt times out after the node accepted the tx, the retry finds it known.
+        let (uri, num_requests) = mock_server(vec![
+            Reply::Hang,
+            Reply::Respond(200, r#"{"result": [false, "error: the transaction was rejected by network rules.\n\ntxn-already-known (code 18)"]}"#),
+        ]);
+        assert_eq!(client(uri, 2).broadcast(tx_hex).unwrap(), txid);
+        assert_eq!(num_requests.load(Ordering::SeqCst), 2);
+        let (uri, _) = mock_server(vec![
+            Reply::Respond(200, r#"{"error": {"code": -27, "message": "Transaction already in block chain"}}"#),
+        ]);
+        assert_eq!(client(uri, 1).broadcast(tx_hex).unwrap(), txid);
+        // Other rejections aren't.
+        let (uri, _) = mock_server(vec![
+            Reply::Respond(200, r#"{"result": [false, "error: txn-mempool-conflict (code 18)"]}"#),
+        ]);
+        let err = client(uri, 1).broadcast(tx_hex).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::BroadcastRejected);
+    }
+
+    #[test]
+    fn test_client_error_is_definitive() {
+        let (uri, num_requests) = mock_server(vec![Reply::Respond(401, "unauthorized")]);
+        assert!(client(uri, 5).getfeerate().is_err());
+        assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_retry_timeout() {
+        let (uri, num_requests) = mock_server(vec![Reply::Hang, Reply::Respond(200, r#"{"result": 1000}"#)]);
+        assert_eq!(client(uri, 2).getfeerate().unwrap(), Some(1000));
+        assert_eq!(num_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_errors_redact_secrets() {
+        const WIF: &str = "cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy";
+        const BAD_CHECKSUM_WIF: &str = "cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpz";
+        const PASSWORD: &str = "hunter2hunter2";
+        let tx_hex = "0100000001".repeat(30);
+        let secrets = [WIF, BAD_CHECKSUM_WIF, PASSWORD, &tx_hex];
+        let leak = |text: String| -> &'static str { Box::leak(text.into_boxed_str()) };
+        type Call = fn(&ECSClient) -> Result<()>;
+        let get_key: Call = |client| client.getprivatekeys("qq").map(|_| ());
+        let get_fee_rate: Call = |client| client.getfeerate().map(|_| ());
+        let broadcast: Call = |client| client.broadcast(&"0100000001".repeat(30)).map(|_| ());
+        let cases = vec![
+            (Reply::Respond(200, leak(format!(r#"{{"error": {{"code": -1, "message": "bad key {}"}}}}"#, WIF))), get_key),
+            (Reply::Respond(500, leak(format!("internal error {} {}", WIF, PASSWORD))), get_key),
+            (Reply::Respond(401, leak(format!("unauthorized rpcuser:{}", PASSWORD))), get_key),
+            (Reply::Respond(200, leak(format!(r#"{{"result": "{}""#, WIF))), get_key),
+            (Reply::Respond(200, leak(format!(r#"{{"result": "{}"}}"#, BAD_CHECKSUM_WIF))), get_key),
+            // serde_json quotes the value of the wrong type.
+            (Reply::Respond(200, leak(format!(r#"{{"result": "{}"}}"#, WIF))), get_fee_rate),
+            (Reply::Respond(503, leak(format!(r#"{{"result": {{"hex": "{}"}}}}"#, tx_hex))), get_fee_rate),
+            (Reply::Respond(200, leak(format!(r#"{{"error": {{"code": -1, "message": "bad tx {}"}}}}"#, tx_hex))), broadcast),
+            (Reply::Respond(200, leak(format!(r#"{{"result": [false, "error: bad tx {}"]}}"#, tx_hex))), broadcast),
+        ];
+        for (reply, call) in cases {
+            let (uri, _) = mock_server(vec![reply.clone()]);
+            let uri = uri.replacen("http://", &format!("http://rpcuser:{}@", PASSWORD), 1);
+            let message = format!("{:#}", call(&client(uri, 1)).unwrap_err());
+            for secret in &secrets {
+                assert!(!message.contains(secret), "{:?} leaks a secret: {}", reply, message);
             }
-            return Ok(resp.result.expect("No error but also no result"));
-        } else {
-            anyhow::bail!("{} invalid response: {}", method, response.text()?);
         }
+
+        let uri = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://rpcuser:{}@{}", PASSWORD, listener.local_addr().unwrap())
+        };
+        let message = format!("{:#}", client(uri, 1).getprivatekeys("qq").unwrap_err());
+        assert!(!message.contains(PASSWORD), "{}", message);
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        // Bind and immediately close a listener to get a port nobody listens on.
+        let uri = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let err = client(uri, 3).getfeerate().unwrap_err();
+        assert!(format!("{:#}", err).contains("getfeerate failed after 3 attempts"));
     }
 }
@@ -0,0 +1,206 @@
+use clap::Clap;
+use bitcoin_cash::{Hashed, Script, Sha256, Sha256d};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::chain::{BlockHeader, Network};
+use crate::error::ErrorKind;
+
+/// Version of the Electrum protocol spoken by `ElectrumClient`.
+const PROTOCOL_VERSION: &str = "1.4";
+
+/// Most headers an Electrum server returns for a single `blockchain.block.headers` request.
+const MAX_HEADERS_PER_REQUEST: u32 = 2016;
+
+#[derive(Clap)]
+pub struct ElectrumOpts {
+    /// Electrum server (<host>:<port>, plain TCP) to look up block headers and merkle proofs from.
+    #[clap(long)]
+    electrum_server: Option<String>,
+    /// Network of the Electrum server. Block headers with less proof of work than the network allows are rejected.
+    #[clap(long, arg_enum, default_value = "testnet")]
+    network: Network,
+}
+
+/// Client for an Electrum protocol server (e.g. Fulcrum or ElectrumX), which serves the address
+/// histories and merkle proofs needed for SPV checks of a tx's confirmations.
+pub struct ElectrumClient {
+    server: String,
+    network: Network,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+}
+
+impl ElectrumOpts {
+    /// Connects to the given Electrum server, if any.
+    pub fn connect(&self, timeout: Duration) -> Result<Option<ElectrumClient>> {
+        match &self.electrum_server {
+            Some(server) => Ok(Some(ElectrumClient::connect(server, self.network, timeout)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ElectrumClient {
+    pub fn connect(server: &str, network: Network, timeout: Duration) -> Result<ElectrumClient> {
+        let connect = || -> Result<TcpStream> {
+            let addr = server.to_socket_addrs()?.next()
+                .ok_or_else(|| anyhow::anyhow!("no address found"))?;
+            let stream = TcpStream::connect_timeout(&addr, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            Ok(stream)
+        };
+        let stream = connect()
+            .with_context(|| format!("Couldn't connect to Electrum server {}", server))
+            .context(ErrorKind::RpcTransport)?;
+        let mut client = ElectrumClient {
+            server: server.to_string(),
+            network,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            next_id: 0,
+        };
+        let _: Value = client.request("server.version", json!(["slp-htlc", PROTOCOL_VERSION]))?;
+        Ok(client)
+    }
+
+    /// Network whose proof of work the server's headers must have.
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Height and header of the server's chain tip.
+    pub fn tip(&mut self) -> Result<(u32, BlockHeader)> {
+        #[derive(serde::Deserialize)]
+        struct Res {
+            height: u32,
+            hex: String,
+        }
+        let result: Res = self.request("blockchain.headers.subscribe", json!([]))?;
+        let raw = hex::decode(&result.hex)
+            .with_context(|| format!("Invalid tip header: {}", result.hex))
+            .context(ErrorKind::RpcTransport)?;
+        Ok((result.height, BlockHeader::parse(&raw)?))
+    }
+
+    /// `count` consecutive headers starting at `start_height`.
+    pub fn block_headers(&mut self, start_height: u32, count: u32) -> Result<Vec<BlockHeader>> {
+        #[derive(serde::Deserialize)]
+        struct Res {
+            count: u32,
+            hex: String,
+        }
+        let result: Res = self.request("blockchain.block.headers", json!([start_height, count]))?;
+        if result.count != count {
+            bail_kind!(
+                ErrorKind::RpcTransport,
+                "Electrum server returned {} headers from height {}, expected {}", result.count, start_height, count,
+            );
+        }
+        let raw = hex::decode(&result.hex)
+            .with_context(|| "Invalid block headers")
+            .context(ErrorKind::RpcTransport)?;
+        BlockHeader::parse_many(&raw)
+    }
+
+    /// The headers from `start_height` to `end_height` inclusive, in as many requests as needed.
+    pub fn header_range(&mut self, start_height: u32, end_height: u32) -> Result<Vec<BlockHeader>> {
+        let mut headers = Vec::with_capacity((end_height + 1).saturating_sub(start_height) as usize);
+        let mut height = start_height;
+        while height <= end_height {
+            let count = (end_height - height + 1).min(MAX_HEADERS_PER_REQUEST);
+            headers.extend(self.block_headers(height, count)?);
+            height += count;
+        }
+        Ok(headers)
+    }
+
+    /// Txids and heights of the txs spending from or paying to `script`.
+    /// Unconfirmed txs have a height of 0 or less.
+    pub fn script_history(&mut self, script: &Script) -> Result<Vec<(Sha256d, i32)>> {
+        #[derive(serde::Deserialize)]
+        struct Entry {
+            tx_hash: String,
+            height: i32,
+        }
+        let script_hash = Sha256::digest(script.ser_ops()).to_hex_le();
+        let entries: Vec<Entry> = self.request("blockchain.scripthash.get_history", json!([script_hash]))?;
+        entries.into_iter()
+            .map(|entry| {
+                let txid = Sha256d::from_hex_le(&entry.tx_hash)
+                    .with_context(|| format!("Invalid txid in history: {}", entry.tx_hash))
+                    .context(ErrorKind::RpcTransport)?;
+                Ok((txid, entry.height))
+            })
+            .collect()
+    }
+
+    /// Merkle branch and position of `txid` in the block at `height`.
+    pub fn merkle_proof(&mut self, txid: &Sha256d, height: u32) -> Result<(Vec<Sha256d>, u32)> {
+        #[derive(serde::Deserialize)]
+        struct Res {
+            block_height: u32,
+            merkle: Vec<String>,
+            pos: u32,
+        }
+        let result: Res = self.request("blockchain.transaction.get_merkle", json!([txid.to_hex_le(), height]))?;
+        if result.block_height != height {
+            bail_kind!(
+                ErrorKind::SpvVerification,
+                "Merkle proof of {} is for height {}, expected {}", txid.to_hex_le(), result.block_height, height,
+            );
+        }
+        let branch = result.merkle.iter()
+            .map(|hash| {
+                Sha256d::from_hex_le(hash)
+                    .with_context(|| format!("Invalid merkle branch hash: {}", hash))
+                    .context(ErrorKind::RpcTransport)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((branch, result.pos))
+    }
+
+    fn request<R: serde::de::DeserializeOwned>(&mut self, method: &str, params: Value) -> Result<R> {
+        #[derive(serde::Deserialize)]
+        struct Resp {
+            id: Option<u64>,
+            result: Option<Value>,
+            error: Option<Value>,
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let transport_err = |err: anyhow::Error| {
+            err.context(format!("{} to Electrum server failed", method)).context(ErrorKind::RpcTransport)
+        };
+        writeln!(self.writer, "{}", request).map_err(|err| transport_err(err.into()))?;
+        loop {
+            let mut line = String::new();
+            let num_bytes = self.reader.read_line(&mut line).map_err(|err| transport_err(err.into()))?;
+            if num_bytes == 0 {
+                return Err(transport_err(anyhow::anyhow!("{} closed the connection", self.server)));
+            }
+            let resp = serde_json::from_str::<Resp>(&line)
+                .with_context(|| format!("{} invalid json: {}", method, line.trim_end()))
+                .map_err(transport_err)?;
+            // Subscriptions send notifications without an id in between responses.
+            if resp.id != Some(id) {
+                continue;
+            }
+            if let Some(err) = resp.error {
+                bail_kind!(ErrorKind::RpcError, "{} error: {}", method, err);
+            }
+            let result = resp.result
+                .ok_or_else(|| transport_err(anyhow::anyhow!("{} returned neither result nor error", method)))?;
+            return serde_json::from_value(result)
+                .with_context(|| format!("{} invalid result", method))
+                .context(ErrorKind::RpcTransport);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+/// Category of an error, which determines the exit code of the process.
+///
+/// Attached to an `anyhow::Error` as context, e.g. `.context(ErrorKind::InvalidInput)` or
+/// `bail_kind!(ErrorKind::InvalidInput, ...)`. If an error carries multiple kinds, the outermost wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Anything not covered by the other kinds, e.g. bugs or unexpected wallet behavior.
+    Internal,
+    /// Invalid command line arguments, e.g. a malformed address or secret.
+    InvalidInput,
+    /// The wallet daemon couldn't be reached or didn't respond properly.
+    RpcTransport,
+    /// The wallet daemon returned an error for an RPC call.
+    RpcError,
+    /// A transaction isn't a valid SLP transaction or doesn't hold the expected tokens.
+    InvalidSlp,
+    /// A contract or transaction doesn't match the given parameters or keys.
+    ContractMismatch,
+    /// The wallet doesn't have enough BCH to pay for the transaction.
+    InsufficientFunds,
+    /// The network rejected the transaction.
+    BroadcastRejected,
+    /// A signed transaction failed local verification before broadcast.
+    ScriptVerification,
+    /// Revealing the secret isn't safe, e.g. the funding tx lacks confirmations or the contract times out soon.
+    RevealUnsafe,
+    /// A refund's lock time hasn't been reached yet, so the network wouldn't accept it.
+    NotFinal,
+    /// A transaction's SPV proof from the Electrum server is invalid.
+    SpvVerification,
+}
+
+/// Machine-readable description of a failed command, printed to stderr as JSON.
+#[derive(serde::Serialize)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    pub exit_code: i32,
+    pub message: String,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::InvalidInput => 2,
+            ErrorKind::RpcTransport => 3,
+            ErrorKind::RpcError => 4,
+            ErrorKind::InvalidSlp => 5,
+            ErrorKind::ContractMismatch => 6,
+            ErrorKind::InsufficientFunds => 7,
+            ErrorKind::BroadcastRejected => 8,
+            ErrorKind::ScriptVerification => 9,
+            ErrorKind::RevealUnsafe => 10,
+            ErrorKind::NotFinal => 11,
+            ErrorKind::SpvVerification => 12,
+        }
+    }
+
+    /// The kind attached to `err`, or `Internal` if there is none.
+    pub fn of(err: &anyhow::Error) -> ErrorKind {
+        err.downcast_ref::<ErrorKind>().copied().unwrap_or(ErrorKind::Internal)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ErrorKind::Internal => "internal error",
+            ErrorKind::InvalidInput => "invalid input",
+            ErrorKind::RpcTransport => "RPC transport failure",
+            ErrorKind::RpcError => "RPC error",
+            ErrorKind::InvalidSlp => "invalid SLP",
+            ErrorKind::ContractMismatch => "contract mismatch",
+            ErrorKind::InsufficientFunds => "insufficient funds",
+            ErrorKind::BroadcastRejected => "broadcast rejected",
+            ErrorKind::ScriptVerification => "script verification failed",
+            ErrorKind::RevealUnsafe => "unsafe to reveal secret",
+            ErrorKind::NotFinal => "refund not final yet",
+            ErrorKind::SpvVerification => "SPV verification failed",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl ErrorReport {
+    pub fn new(err: &anyhow::Error) -> Self {
+        let kind = ErrorKind::of(err);
+        ErrorReport {
+            kind,
+            exit_code: kind.exit_code(),
+            message: format!("{:#}", err),
+        }
+    }
+
+    /// Prints the report as `{"error": {...}}` to stderr.
+    pub fn print(&self) {
+        #[derive(serde::Serialize)]
+        struct Output<'a> {
+            error: &'a ErrorReport,
+        }
+        eprintln!("{}", serde_json::to_string(&Output { error: self }).expect("infallible"));
+    }
+}
+
+/// Like `anyhow::bail!`, but tags the error with an `ErrorKind`.
+macro_rules! bail_kind {
+    ($kind:expr, $($arg:tt)*) => {
+        return Err(anyhow::anyhow!($($arg)*).context($kind))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, Result};
+
+    fn invalid_input() -> Result<()> {
+        bail_kind!(ErrorKind::InvalidInput, "Invalid secret {}", "xyz");
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let err = invalid_input().unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidInput);
+        assert_eq!(format!("{:#}", err), "invalid input: Invalid secret xyz");
+        // Plain context keeps the kind, the outermost kind wins.
+        let err = invalid_input().context("while redeeming").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidInput);
+        let err = invalid_input().context(ErrorKind::ContractMismatch).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ContractMismatch);
+        assert_eq!(ErrorKind::of(&anyhow::anyhow!("untagged")), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn test_error_report() {
+        let report = ErrorReport::new(&invalid_input().unwrap_err());
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"kind":"invalid_input","exit_code":2,"message":"invalid input: Invalid secret xyz"}"#,
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use clap::Clap;
+use bitcoin_cash::*;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chain::LOCKTIME_THRESHOLD;
+use crate::contract::HtlcDescriptor;
+use crate::ecs_client::ECSClient;
+use crate::electrum::{ElectrumClient, ElectrumOpts};
+use crate::error::ErrorKind;
+use crate::slp::TokenInfo;
+use crate::spv;
+
+/// Time assumed between two blocks when estimating how long until a block height timeout.
+const BLOCK_INTERVAL_SECS: i64 = 600;
+
+/// What the seller expects of the contracts before revealing the secret by redeeming them.
+#[derive(Clap)]
+pub struct ExpectOpts {
+    /// Refuse to redeem unless the contracts hold this token.
+    #[clap(long)]
+    expect_token_id: Option<String>,
+    /// Refuse to redeem unless the contracts hold exactly this amount in total,
+    /// in whole tokens unless --base-units is set.
+    #[clap(long)]
+    expect_amount: Option<String>,
+    /// Refuse to redeem unless every funding tx has at least this many confirmations.
+    /// With --electrum-server, they are verified with SPV instead of trusting the wallet daemon.
+    #[clap(long)]
+    min_confirmations: Option<u32>,
+    /// Refuse to redeem unless every contract times out in at least this many seconds.
+    /// Block height timeouts are estimated at 10 minutes per block.
+    #[clap(long)]
+    min_time_to_timeout: Option<i64>,
+    #[clap(flatten)]
+    electrum: ElectrumOpts,
+}
+
+impl ExpectOpts {
+    /// Checks the contracts, given with their token amounts, against the expectations.
+    /// Fails with `ContractMismatch` for the wrong token or amount, and `RevealUnsafe` if the funding
+    /// isn't confirmed deeply enough or a contract times out too soon.
+    pub fn check(
+        &self,
+        client: &ECSClient,
+        token_id_hex: &str,
+        token_info: &TokenInfo,
+        contracts: &[(&HtlcDescriptor, u64)],
+        base_units: bool,
+    ) -> Result<()> {
+        if let Some(expect_token_id) = &self.expect_token_id {
+            if expect_token_id.to_lowercase() != token_id_hex {
+                bail_kind!(ErrorKind::ContractMismatch, "Contracts hold token {}, expected {}", token_id_hex, expect_token_id);
+            }
+        }
+        if let Some(expect_amount) = &self.expect_amount {
+            let expect_amount = token_info.parse_amount(expect_amount, base_units)?;
+            let amount = contracts.iter().map(|(_, amount)| amount).sum::<u64>();
+            if amount != expect_amount {
+                bail_kind!(
+                    ErrorKind::ContractMismatch,
+                    "Contracts hold {}, expected {}",
+                    token_info.display_amount(amount), token_info.display_amount(expect_amount),
+                );
+            }
+        }
+        if self.min_confirmations.is_none() && self.min_time_to_timeout.is_none() {
+            return Ok(());
+        }
+
+        let tip_height = client.blockchain_height()?;
+        if let Some(min_confirmations) = self.min_confirmations {
+            let mut electrum = self.electrum.connect(client.retry_policy().timeout)?;
+            let mut checked = HashSet::new();
+            for (descriptor, _) in contracts {
+                let txid = descriptor.contract_utxo.tx_hash.to_hex_le();
+                if !checked.insert(txid.clone()) {
+                    continue;
+                }
+                let num_confirmations = match &mut electrum {
+                    Some(electrum) => spv_confirmations(electrum, client, descriptor)?,
+                    None => funding_confirmations(client, descriptor, tip_height)?,
+                };
+                if num_confirmations < min_confirmations {
+                    bail_kind!(
+                        ErrorKind::RevealUnsafe,
+                        "Funding tx {} has {} confirmations, need at least {} (see --min-confirmations)",
+                        txid, num_confirmations, min_confirmations,
+                    );
+                }
+            }
+        }
+        if let Some(min_time_to_timeout) = self.min_time_to_timeout {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+            for (descriptor, _) in contracts {
+                let time_left = time_to_timeout(descriptor.timeout, tip_height, now);
+                if time_left < min_time_to_timeout {
+                    bail_kind!(
+                        ErrorKind::RevealUnsafe,
+                        "Contract {} times out in about {} seconds, need at least {} (see --min-time-to-timeout)",
+                        descriptor, time_left, min_time_to_timeout,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of confirmations of the tx funding the contract, looked up in the history of the contract's address.
+fn funding_confirmations(client: &ECSClient, descriptor: &HtlcDescriptor, tip_height: u32) -> Result<u32> {
+    let txid = descriptor.contract_utxo.tx_hash.to_hex_le();
+    let address = Address::from_redeem_script(client.address_prefix(), descriptor.params()?.script().into())
+        .expect("infallible");
+    let height = client.getaddresshistory(address.cash_addr())?.into_iter()
+        .find(|(tx_hash, _)| *tx_hash == txid)
+        .map(|(_, height)| height);
+    match height {
+        Some(height) => Ok(confirmations(height, tip_height)),
+        None => bail_kind!(
+            ErrorKind::RevealUnsafe,
+            "Funding tx {} not found in the history of contract address {}", txid, address.cash_addr(),
+        ),
+    }
+}
+
+/// Number of confirmations of the tx funding the contract, verified with SPV against the daemon's chain.
+fn spv_confirmations(electrum: &mut ElectrumClient, client: &ECSClient, descriptor: &HtlcDescriptor) -> Result<u32> {
+    let script = descriptor.params()?.p2sh_script();
+    let proof = spv::verify_tx(electrum, client, &descriptor.contract_utxo.tx_hash, &script)?;
+    Ok(proof.map_or(0, |proof| proof.confirmations))
+}
+
+/// Confirmations of a tx mined at `height`, which is 0 or less for unconfirmed txs.
+fn confirmations(height: i32, tip_height: u32) -> u32 {
+    if height <= 0 {
+        return 0;
+    }
+    (tip_height + 1).saturating_sub(height as u32)
+}
+
+/// Estimated seconds until a contract with the given timeout can be refunded, zero or less if it already can.
+///
+/// Timestamp timeouts are compared against `now` rather than median time past, which lags behind,
+/// so the estimate errs on the short side.
+fn time_to_timeout(timeout: u32, tip_height: u32, now: i64) -> i64 {
+    if timeout < LOCKTIME_THRESHOLD {
+        // A tx with lock time `timeout` is final in the block after that height.
+        (timeout as i64 - tip_height as i64) * BLOCK_INTERVAL_SECS
+    } else {
+        timeout as i64 - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirmations() {
+        assert_eq!(confirmations(0, 700_000), 0);
+        assert_eq!(confirmations(-1, 700_000), 0);
+        assert_eq!(confirmations(700_000, 700_000), 1);
+        assert_eq!(confirmations(699_995, 700_000), 6);
+        // The daemon's tip may lag behind the history.
+        assert_eq!(confirmations(700_001, 700_000), 0);
+    }
+
+    #[test]
+    fn test_time_to_timeout() {
+        let now = 1_600_000_000;
+        assert_eq!(time_to_timeout(700_010, 700_000, now), 10 * 600);
+        assert_eq!(time_to_timeout(700_001, 700_000, now), 600);
+        assert_eq!(time_to_timeout(700_000, 700_000, now), 0);
+        assert_eq!(time_to_timeout(699_990, 700_000, now), -6000);
+        assert_eq!(time_to_timeout(1_600_003_600, 700_000, now), 3600);
+        assert_eq!(time_to_timeout(1_599_999_000, 700_000, now), -1000);
+    }
+}
@@ -0,0 +1,100 @@
+use clap::Clap;
+use bitcoin_cash::*;
+use anyhow::Result;
+
+use crate::ecs_client::*;
+use crate::error::ErrorKind;
+
+/// Fee rate used if neither --fee-rate is given nor the backend supports fee estimates.
+pub const DEFAULT_FEE_RATE: u64 = 1;
+
+#[derive(Clap)]
+pub struct FeeOpts {
+    /// Fee rate in sat/byte, defaults to the backend's fee estimate.
+    #[clap(long)]
+    fee_rate: Option<u64>,
+    /// Refuse to broadcast transactions paying more than this many sat/byte.
+    #[clap(long, default_value = "10")]
+    max_fee_rate: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct FeePolicy {
+    pub fee_rate: u64,
+    pub max_fee_rate: u64,
+}
+
+/// Size, fee and BCH change of a built transaction.
+pub struct TxSummary {
+    pub size: usize,
+    pub fee: u64,
+    pub change: u64,
+}
+
+impl FeeOpts {
+    /// Resolves the fee rate, asking the backend for an estimate if none was given.
+    pub fn resolve(&self, client: &ECSClient) -> Result<FeePolicy> {
+        let fee_rate = match self.fee_rate {
+            Some(fee_rate) => fee_rate,
+            None => match client.getfeerate()? {
+                // Round up to whole sat/byte, which is what the tx builder works with.
+                Some(fee_per_kb) => fee_per_kb.div_ceil(1000).max(DEFAULT_FEE_RATE),
+                None => {
+                    eprintln!("Warning: the wallet doesn't estimate fees, using {} sat/byte. Use --fee-rate to set it.", DEFAULT_FEE_RATE);
+                    DEFAULT_FEE_RATE
+                }
+            },
+        };
+        if fee_rate == 0 {
+            bail_kind!(ErrorKind::InvalidInput, "Fee rate must be at least 1 sat/byte");
+        }
+        if fee_rate > self.max_fee_rate {
+            bail_kind!(
+                ErrorKind::InvalidInput,
+                "Fee rate of {} sat/byte exceeds maximum of {} sat/byte (see --max-fee-rate)",
+                fee_rate, self.max_fee_rate,
+            );
+        }
+        Ok(FeePolicy {
+            fee_rate,
+            max_fee_rate: self.max_fee_rate,
+        })
+    }
+}
+
+impl FeePolicy {
+    pub fn fee_per_kb(&self) -> u64 {
+        self.fee_rate * 1000
+    }
+
+    /// Refuses transactions whose fee exceeds the maximum fee rate.
+    pub fn check(&self, summary: &TxSummary) -> Result<()> {
+        if summary.fee > summary.size as u64 * self.max_fee_rate {
+            bail_kind!(
+                ErrorKind::InvalidInput,
+                "Fee of {} sats for {} bytes exceeds maximum of {} sat/byte (see --max-fee-rate)",
+                summary.fee, summary.size, self.max_fee_rate,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl TxSummary {
+    /// Summarizes `tx`, given the outputs spent by its inputs and which of its outputs are BCH change.
+    pub fn new(tx: &UnhashedTx, prev_outputs: &[TxOutput], is_change: impl Fn(usize, &TxOutput) -> bool) -> Result<Self> {
+        let input_sum = prev_outputs.iter().map(|output| output.value).sum::<u64>();
+        let output_sum = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+        if output_sum > input_sum {
+            anyhow::bail!("Outputs ({} sats) exceed inputs ({} sats)", output_sum, input_sum);
+        }
+        Ok(TxSummary {
+            size: tx.ser().len(),
+            fee: input_sum - output_sum,
+            change: tx.outputs.iter().enumerate()
+                .filter(|(idx, output)| is_change(*idx, output))
+                .map(|(_, output)| output.value)
+                .sum(),
+        })
+    }
+}
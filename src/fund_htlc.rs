@@ -0,0 +1,169 @@
+use bitcoin_cash::*;
+use bitcoin_cash_slp::{slp_send_output, SlpTokenType, TokenId};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use crate::coin_selection::CoinSelection;
+use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::fee::*;
+use crate::keystore::Keys;
+use crate::output::TxReport;
+use crate::slp::{SlpMessage, TokenType};
+use crate::slp_validator::SlpValidity;
+use crate::spend_htlc::{TokenOutput, MAX_SLP_OUTPUTS};
+use crate::util;
+
+/// An SLP SEND locking tokens of the wallet into contracts.
+pub struct ContractFunding<'a> {
+    /// Token id (hex, as displayed).
+    pub token_id: &'a str,
+    /// Contract outputs, which end up at vouts 1 to n in this order.
+    pub contract_outputs: Vec<TokenOutput>,
+    /// Strategy for picking the wallet UTXOs that pay the fee.
+    pub coin_selection: CoinSelection,
+}
+
+/// A wallet UTXO holding tokens.
+struct TokenUtxo {
+    utxo: Utxo,
+    amount: u64,
+}
+
+/// Builds, signs, verifies and broadcasts `funding` from the token UTXOs of `keys`' funding source, paying
+/// the fee from its UTXOs picked by its `coin_selection`, signing with `keys`. Token change and leftover BCH
+/// go to `keys`' change address. With `dry_run`, the tx isn't broadcast.
+pub fn fund_contracts(
+    client: &ECSClient,
+    keys: &Keys,
+    ecc: &impl ECC,
+    slp_validity: &mut SlpValidity,
+    funding: ContractFunding,
+    fee_policy: &FeePolicy,
+    dry_run: bool,
+) -> Result<TxReport> {
+    let ContractFunding { token_id: token_id_hex, contract_outputs, coin_selection } = funding;
+    let token_id = Sha256d::from_hex_le(token_id_hex)
+        .with_context(|| format!("Invalid token id: {}", token_id_hex))
+        .context(ErrorKind::InvalidInput)?;
+    let amount = contract_outputs.iter().map(|output| output.amount).sum::<u64>();
+
+    // Largest first, so few token UTXOs are spent.
+    let mut token_utxos = token_utxos(client, keys, slp_validity, &token_id)?;
+    token_utxos.sort_by_key(|token_utxo| std::cmp::Reverse(token_utxo.amount));
+    let mut selected = Vec::new();
+    let mut input_amount = 0;
+    for token_utxo in token_utxos {
+        if input_amount >= amount {
+            break;
+        }
+        input_amount += token_utxo.amount;
+        selected.push(token_utxo);
+    }
+    if input_amount < amount {
+        bail_kind!(
+            ErrorKind::InsufficientFunds,
+            "Insufficient token funds, the wallet has {} base units of token {}, need {}", input_amount, token_id_hex, amount,
+        );
+    }
+
+    let mut amounts = contract_outputs.iter().map(|output| output.amount).collect::<Vec<_>>();
+    if input_amount > amount {
+        amounts.push(input_amount - amount);
+    }
+    if amounts.len() > MAX_SLP_OUTPUTS {
+        bail_kind!(
+            ErrorKind::InvalidInput,
+            "SLP SEND can have at most {} token outputs including token change, got {}", MAX_SLP_OUTPUTS, amounts.len(),
+        );
+    }
+    let change_script: Script = keys.change_address(client)?.p2pkh_script()?.into();
+    let mut token_inputs = Vec::with_capacity(selected.len());
+    for token_utxo in &selected {
+        let secret_key = keys.secret_key(client, &token_utxo.utxo.address)?;
+        token_inputs.push((token_utxo, ecc.derive_pubkey(&secret_key)?, secret_key));
+    }
+    let slp_token_id = TokenId::from_slice(&token_id.to_vec_le()).expect("infallible");
+
+    let make_tx_builder = || {
+        let mut tx_builder = TxBuilder::new_with_fee(1, 0, fee_policy.fee_per_kb());
+        for (token_utxo, pubkey, _) in &token_inputs {
+            tx_builder.add_input(
+                UnsignedTxInput {
+                    prev_out: token_utxo.utxo.outpoint.clone(),
+                    sequence: 0xffff_ffff,
+                    value: token_utxo.utxo.value,
+                },
+                token_utxo.utxo.address.p2pkh_script().expect("infallible"),
+                P2PKHSignatory {
+                    pubkey: *pubkey,
+                    sig_hash_flags: SigHashFlags::DEFAULT,
+                },
+            );
+        }
+        tx_builder.add_output(slp_send_output(SlpTokenType::Fungible, &slp_token_id, &amounts));
+        for output in &contract_outputs {
+            tx_builder.add_output(TxOutput {
+                script: output.script.clone(),
+                value: DUST_AMOUNT,
+            });
+        }
+        if amounts.len() > contract_outputs.len() {
+            tx_builder.add_output(TxOutput {
+                script: change_script.clone(),
+                value: DUST_AMOUNT,
+            });
+        }
+        tx_builder.add_leftover_output(change_script.clone());
+        tx_builder
+    };
+
+    let (mut unsigned_tx, gas_inputs) = util::add_gas_inputs(
+        client, keys, ecc, make_tx_builder, fee_policy.fee_rate, coin_selection,
+    )?;
+
+    // Token inputs come first, in the order selected.
+    for (idx, (_, _, secret_key)) in token_inputs.iter().enumerate() {
+        let token_ref = InputReference::<P2PKHSignatory>::new(idx);
+        let sig = ecc.sign(secret_key, Sha256d::digest(unsigned_tx.input_preimages(token_ref).ser()))?;
+        unsigned_tx.sign_input(token_ref, sig)?;
+    }
+    for (gas_ref, utxo_sk) in gas_inputs {
+        let gas_sig = ecc.sign(&utxo_sk, Sha256d::digest(unsigned_tx.input_preimages(gas_ref).ser()))?;
+        unsigned_tx.sign_input(gas_ref, gas_sig)?;
+    }
+
+    let tx = unsigned_tx.complete_tx();
+    let prev_outputs = util::prev_outputs(client, &tx)?;
+    // Everything except the SLP message and the contracts goes back to the wallet.
+    let summary = TxSummary::new(&tx, &prev_outputs, |idx, _| idx > contract_outputs.len())?;
+    fee_policy.check(&summary)?;
+    util::verify_and_broadcast(client, ecc, &tx, &prev_outputs, &summary, dry_run)
+}
+
+/// The funding UTXOs holding tokens of `token_id`, from valid SLP transactions, whose keys are in `keys`.
+/// Mint batons are left alone.
+fn token_utxos(client: &ECSClient, keys: &Keys, slp_validity: &mut SlpValidity, token_id: &Sha256d) -> Result<Vec<TokenUtxo>> {
+    let mut slp_messages = HashMap::new();
+    let mut token_utxos = Vec::new();
+    for utxo in keys.unspent(client)? {
+        let txid = utxo.outpoint.tx_hash.clone();
+        if !slp_messages.contains_key(&txid) {
+            let tx = util::get_tx(client, &txid.to_hex_le())?;
+            let slp_message = tx.outputs.first()
+                .and_then(|output| SlpMessage::parse(&output.script))
+                .and_then(|message| message.ok())
+                .filter(|message| message.token_type() == TokenType::Fungible && message.token_id(&txid) == *token_id);
+            slp_messages.insert(txid.clone(), slp_message);
+        }
+        let amount = match &slp_messages[&txid] {
+            Some(slp_message) => slp_message.token_amount(utxo.outpoint.vout),
+            None => None,
+        };
+        match amount {
+            Some(amount) if amount > 0 && keys.has_key(&utxo.address)? && slp_validity.is_valid(&txid)? => token_utxos.push(TokenUtxo { utxo, amount }),
+            _ => {}
+        }
+    }
+    Ok(token_utxos)
+}
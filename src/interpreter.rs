@@ -0,0 +1,602 @@
+use bitcoin_cash::*;
+use anyhow::{Context, Result};
+use std::fmt;
+
+use crate::chain::LOCKTIME_THRESHOLD;
+use crate::error::ErrorKind;
+use crate::util;
+
+/// Sequence number that disables the lock time of an input.
+const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+
+/// Maximum size of a number used by CHECKLOCKTIMEVERIFY.
+const MAX_LOCKTIME_NUM_SIZE: usize = 5;
+
+/// Outcome of evaluating one input of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputCheck {
+    /// All scripts succeeded and all signatures were checked.
+    Verified,
+    /// All scripts succeeded, but Schnorr signatures were assumed to be valid, as they can't be checked locally.
+    SchnorrUnchecked,
+}
+
+/// Evaluates all inputs of `tx` against the outputs they spend, given in the same order.
+/// Used as a guard before broadcasting, so that a failing script is reported with its failing op.
+pub fn verify_tx(ecc: &impl ECC, tx: &UnhashedTx, prev_outputs: &[TxOutput]) -> Result<Vec<InputCheck>> {
+    if tx.inputs.len() != prev_outputs.len() {
+        anyhow::bail!("Got {} spent outputs for {} inputs", prev_outputs.len(), tx.inputs.len());
+    }
+    let mut checks = Vec::with_capacity(tx.inputs.len());
+    for (input_idx, input) in tx.inputs.iter().enumerate() {
+        let check = eval_input(ecc, tx, prev_outputs, input_idx)
+            .with_context(|| format!(
+                "Input {} spending {}:{} failed verification", input_idx, input.prev_out.tx_hash, input.prev_out.vout,
+            ))
+            .context(ErrorKind::ScriptVerification)?;
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+/// Which of the scripts of an input failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptKind {
+    ScriptSig,
+    LockScript,
+    RedeemScript,
+}
+
+/// Why and where the evaluation of an input failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    pub script: ScriptKind,
+    /// Index of the failing op in its script, `None` if the script as a whole failed (e.g. left false on the stack).
+    pub op_idx: Option<usize>,
+    pub op: Option<Op>,
+    pub reason: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let script = match self.script {
+            ScriptKind::ScriptSig => "script signature",
+            ScriptKind::LockScript => "lock script",
+            ScriptKind::RedeemScript => "redeem script",
+        };
+        match (&self.op_idx, &self.op) {
+            (Some(op_idx), Some(op)) => write!(f, "{} failed at op {} ({}): {}", script, op_idx, op, self.reason),
+            _ => write!(f, "{} failed: {}", script, self.reason),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Evaluates the script signature of input `input_idx` of `tx` against the output it spends,
+/// like a node would, including the redeem script of P2SH outputs.
+///
+/// `prev_outputs` are the outputs spent by all inputs of `tx`, in order; they are needed for signature hashes.
+/// Only the opcodes used by P2PKH and HTLC scripts are supported; any other opcode fails the evaluation.
+pub fn eval_input(
+    ecc: &impl ECC,
+    tx: &UnhashedTx,
+    prev_outputs: &[TxOutput],
+    input_idx: usize,
+) -> Result<InputCheck, ScriptError> {
+    let mut interpreter = Interpreter {
+        ecc,
+        tx,
+        prev_outputs,
+        input_idx,
+        unchecked_schnorr: false,
+    };
+    let script_sig = ops(&tx.inputs[input_idx].script);
+    let fail_script = |script, reason: &str| ScriptError { script, op_idx: None, op: None, reason: reason.to_string() };
+    if let Some(op_idx) = script_sig.iter().position(|op| !is_push(op)) {
+        return Err(ScriptError {
+            script: ScriptKind::ScriptSig,
+            op_idx: Some(op_idx),
+            op: Some(script_sig[op_idx].clone()),
+            reason: "script signature must be push only".to_string(),
+        });
+    }
+    let mut stack = Vec::new();
+    interpreter.eval(ScriptKind::ScriptSig, &script_sig, &mut stack)?;
+    let sig_stack = stack.clone();
+
+    let lock_script = ops(&prev_outputs[input_idx].script);
+    interpreter.eval(ScriptKind::LockScript, &lock_script, &mut stack)?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(fail_script(ScriptKind::LockScript, "script evaluated to false"));
+    }
+
+    let mut final_kind = ScriptKind::LockScript;
+    if is_p2sh(&lock_script) {
+        stack = sig_stack;
+        let redeem_script = stack.pop().expect("P2SH lock script succeeded, so the stack isn't empty");
+        let redeem_script = util::deser_ops(&redeem_script)
+            .map_err(|_| fail_script(ScriptKind::RedeemScript, "redeem script can't be parsed"))?;
+        interpreter.eval(ScriptKind::RedeemScript, &redeem_script, &mut stack)?;
+        if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+            return Err(fail_script(ScriptKind::RedeemScript, "script evaluated to false"));
+        }
+        final_kind = ScriptKind::RedeemScript;
+    }
+    if stack.len() != 1 {
+        return Err(fail_script(final_kind, &format!("stack must be clean, has {} items", stack.len())));
+    }
+    Ok(match interpreter.unchecked_schnorr {
+        true => InputCheck::SchnorrUnchecked,
+        false => InputCheck::Verified,
+    })
+}
+
+struct Interpreter<'a, E: ECC> {
+    ecc: &'a E,
+    tx: &'a UnhashedTx,
+    prev_outputs: &'a [TxOutput],
+    input_idx: usize,
+    unchecked_schnorr: bool,
+}
+
+impl<E: ECC> Interpreter<'_, E> {
+    fn eval(&mut self, kind: ScriptKind, script: &[Op], stack: &mut Vec<Vec<u8>>) -> Result<(), ScriptError> {
+        // Whether each enclosing IF branch is executed.
+        let mut branches: Vec<bool> = Vec::new();
+        for (op_idx, op) in script.iter().enumerate() {
+            let fail = |reason: &str| ScriptError {
+                script: kind,
+                op_idx: Some(op_idx),
+                op: Some(op.clone()),
+                reason: reason.to_string(),
+            };
+            let executing = branches.iter().all(|&branch| branch);
+            self.eval_op(script, op, executing, &mut branches, stack).map_err(|reason| fail(&reason))?;
+        }
+        if !branches.is_empty() {
+            return Err(ScriptError {
+                script: kind,
+                op_idx: None,
+                op: None,
+                reason: "unbalanced conditional".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn eval_op(
+        &mut self,
+        script: &[Op],
+        op: &Op,
+        executing: bool,
+        branches: &mut Vec<bool>,
+        stack: &mut Vec<Vec<u8>>,
+    ) -> Result<(), String> {
+        use Opcode::*;
+        let code = match op {
+            Op::PushByteArray { array, .. } => {
+                if executing {
+                    stack.push(array.to_vec());
+                }
+                return Ok(());
+            }
+            Op::Code(code) => *code,
+            _ => return Err("invalid opcode".to_string()),
+        };
+        match code {
+            OP_IF | OP_NOTIF => {
+                let branch = match executing {
+                    true => {
+                        let condition = cast_to_bool(&pop(stack)?);
+                        if code == OP_IF { condition } else { !condition }
+                    }
+                    false => false,
+                };
+                branches.push(branch);
+                return Ok(());
+            }
+            OP_ELSE => {
+                let branch = branches.last_mut().ok_or("ELSE without IF")?;
+                *branch = !*branch;
+                return Ok(());
+            }
+            OP_ENDIF => {
+                branches.pop().ok_or("ENDIF without IF")?;
+                return Ok(());
+            }
+            _ if !executing => return Ok(()),
+            _ => {}
+        }
+        match code {
+            OP_0 => stack.push(vec![]),
+            OP_1NEGATE => stack.push(vec![0x81]),
+            code if (OP_1 as u8..=OP_16 as u8).contains(&(code as u8)) => {
+                stack.push(vec![code as u8 - OP_1 as u8 + 1]);
+            }
+            OP_NOP => {}
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err("VERIFY failed".to_string());
+                }
+            }
+            OP_DROP => {
+                pop(stack)?;
+            }
+            OP_DUP => stack.push(peek(stack, 0)?.clone()),
+            OP_OVER => stack.push(peek(stack, 1)?.clone()),
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let b = pop(stack)?;
+                let a = pop(stack)?;
+                if code == OP_EQUALVERIFY {
+                    if a != b {
+                        return Err(format!("{} != {}", hex::encode(a), hex::encode(b)));
+                    }
+                } else {
+                    stack.push(bool_to_item(a == b));
+                }
+            }
+            OP_HASH160 => {
+                let item = pop(stack)?;
+                stack.push(Hash160::digest_slice(&item).to_vec());
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let pubkey = pop(stack)?;
+                let sig = pop(stack)?;
+                let is_valid = self.check_sig(script, &sig, &pubkey)?;
+                if code == OP_CHECKSIGVERIFY {
+                    if !is_valid {
+                        return Err("CHECKSIGVERIFY failed".to_string());
+                    }
+                } else {
+                    stack.push(bool_to_item(is_valid));
+                }
+            }
+            OP_CHECKLOCKTIMEVERIFY => {
+                let lock_time = decode_num(peek(stack, 0)?, MAX_LOCKTIME_NUM_SIZE)?;
+                self.check_lock_time(lock_time)?;
+            }
+            _ => return Err("unsupported opcode".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Checks `sig` for `pubkey`, with `script` as script code.
+    /// Empty signatures are invalid, other invalid signatures fail the script (NULLFAIL).
+    fn check_sig(&mut self, script: &[Op], sig: &[u8], pubkey: &[u8]) -> Result<bool, String> {
+        let (&flags, sig) = match sig.split_last() {
+            Some(split) => split,
+            None => return Ok(false),
+        };
+        let sig_hash_flags = SigHashFlags::from_u8(flags);
+        let base_type = (sig_hash_flags & SigHashFlags::MASK).bits();
+        if !(SigHashFlags::ALL.bits()..=SigHashFlags::SINGLE.bits()).contains(&base_type)
+            || flags & !(SigHashFlags::MASK | SigHashFlags::FORKID | SigHashFlags::ANYONECANPAY).bits() as u8 != 0
+        {
+            return Err(format!("invalid sighash type 0x{:02x}", flags));
+        }
+        if !sig_hash_flags.contains(SigHashFlags::FORKID) {
+            return Err("signature must use SIGHASH_FORKID".to_string());
+        }
+        if pubkey.len() != 33 && pubkey.len() != 65 {
+            return Err(format!("invalid public key {}", hex::encode(pubkey)));
+        }
+        if sig.len() == 64 {
+            self.unchecked_schnorr = true;
+            return Ok(true);
+        }
+        let script_code = Script::from_ops(script.iter().cloned());
+        // Preimages need the value and script of every spent output, the signed input uses the script code.
+        let mut tx = self.tx.clone();
+        for (idx, (input, prev_output)) in tx.inputs.iter_mut().zip(self.prev_outputs).enumerate() {
+            input.value = Some(prev_output.value);
+            input.lock_script = Some(match idx == self.input_idx {
+                true => script_code.clone(),
+                false => prev_output.script.clone(),
+            });
+        }
+        let preimage = tx.preimages(&[sig_hash_flags]).swap_remove(self.input_idx).swap_remove(0);
+        let sig_hash = Sha256d::digest(preimage.ser());
+        match self.ecc.verify(pubkey, sig_hash.as_slice(), sig) {
+            Ok(true) => Ok(true),
+            Ok(false) => Err("signature doesn't match (NULLFAIL)".to_string()),
+            Err(err) => Err(format!("malformed signature or public key: {}", err)),
+        }
+    }
+
+    fn check_lock_time(&self, lock_time: i64) -> Result<(), String> {
+        let tx_lock_time = self.tx.lock_time as i64;
+        if lock_time < 0 {
+            return Err("negative lock time".to_string());
+        }
+        let threshold = LOCKTIME_THRESHOLD as i64;
+        if (lock_time < threshold) != (tx_lock_time < threshold) {
+            return Err(format!("lock time {} and tx lock time {} are of different types", lock_time, tx_lock_time));
+        }
+        if lock_time > tx_lock_time {
+            return Err(format!("tx lock time {} is before {}", tx_lock_time, lock_time));
+        }
+        if self.tx.inputs[self.input_idx].sequence == SEQUENCE_FINAL {
+            return Err("input sequence is final, which disables the lock time".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Ops of a script in their serialized form; ops of built scripts aren't normalized (e.g. pushed booleans).
+/// Push encodings are kept, since the script code of signatures must serialize to the exact spent script.
+fn ops(script: &Script) -> Vec<Op> {
+    util::deser_ops(&script.ser_ops()).unwrap_or_else(|_| vec![Op::Invalid(0xff)])
+}
+
+fn is_push(op: &Op) -> bool {
+    match op {
+        Op::PushByteArray { .. } => true,
+        Op::Code(code) => (*code as u8) <= Opcode::OP_16 as u8 && *code != Opcode::OP_RESERVED,
+        _ => false,
+    }
+}
+
+fn is_p2sh(script: &[Op]) -> bool {
+    use Opcode::*;
+    matches!(
+        script,
+        [Op::Code(OP_HASH160), Op::PushByteArray { array, .. }, Op::Code(OP_EQUAL)] if array.len() == 20
+    )
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+    stack.pop().ok_or_else(|| "stack underflow".to_string())
+}
+
+/// The item `depth` items below the top of the stack.
+fn peek(stack: &[Vec<u8>], depth: usize) -> Result<&Vec<u8>, String> {
+    stack.len().checked_sub(depth + 1)
+        .map(|idx| &stack[idx])
+        .ok_or_else(|| "stack underflow".to_string())
+}
+
+fn cast_to_bool(item: &[u8]) -> bool {
+    match item.split_last() {
+        // Negative zero is false.
+        Some((&last, rest)) => rest.iter().any(|&byte| byte != 0) || (last != 0 && last != 0x80),
+        None => false,
+    }
+}
+
+fn bool_to_item(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { vec![] }
+}
+
+/// Decodes a little endian sign-magnitude script number of at most `max_size` bytes.
+fn decode_num(item: &[u8], max_size: usize) -> Result<i64, String> {
+    if item.len() > max_size {
+        return Err(format!("number {} exceeds {} bytes", hex::encode(item), max_size));
+    }
+    let mut value = 0i64;
+    for (idx, &byte) in item.iter().enumerate() {
+        value |= (byte as i64) << (8 * idx);
+    }
+    match item.last() {
+        Some(&last) if last & 0x80 != 0 => Ok(-(value & !(0x80 << (8 * (item.len() - 1))))),
+        _ => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_cash_ecc::init_ecc;
+
+    use crate::contract::*;
+
+    use Opcode::*;
+
+    const SECRET_KEY: [u8; 32] = [0x11; 32];
+
+    fn prev_out(vout: u32) -> TxOutpoint {
+        TxOutpoint { tx_hash: Sha256d::new([0xaa; 32]), vout }
+    }
+
+    /// Signs a tx spending a P2PKH output of `SECRET_KEY`, returns it with the spent outputs.
+    fn p2pkh_tx(ecc: &impl ECC) -> (UnhashedTx, Vec<TxOutput>) {
+        let pubkey = ecc.derive_pubkey(&SECRET_KEY).unwrap();
+        let address = Address::from_pk("bitcoincash", &pubkey);
+        let mut tx_builder = TxBuilder::new_with_fee(1, 0, 1000);
+        let input_ref = tx_builder.add_input(
+            UnsignedTxInput { prev_out: prev_out(0), sequence: 0xffff_ffff, value: 10_000 },
+            address.p2pkh_script().unwrap(),
+            P2PKHSignatory { pubkey, sig_hash_flags: SigHashFlags::DEFAULT },
+        );
+        tx_builder.add_leftover_output(address.p2pkh_script().unwrap().into());
+        let mut unsigned_tx = tx_builder.build().unwrap();
+        let sig = ecc.sign(&SECRET_KEY, Sha256d::digest(unsigned_tx.input_preimages(input_ref).ser())).unwrap();
+        unsigned_tx.sign_input(input_ref, sig).unwrap();
+        let prev_outputs = vec![TxOutput { value: 10_000, script: address.p2pkh_script().unwrap().into() }];
+        (unsigned_tx.complete_tx(), prev_outputs)
+    }
+
+    #[test]
+    fn test_verify_p2pkh() {
+        let ecc = init_ecc();
+        let (tx, prev_outputs) = p2pkh_tx(&ecc);
+        assert_eq!(verify_tx(&ecc, &tx, &prev_outputs).unwrap(), vec![InputCheck::Verified]);
+
+        // Changing what was signed invalidates the signature.
+        let mut tampered = tx.clone();
+        tampered.outputs[0].value -= 1;
+        let err = verify_tx(&ecc, &tampered, &prev_outputs).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ScriptVerification);
+        let mut tampered_prev_outputs = prev_outputs.clone();
+        tampered_prev_outputs[0].value += 1;
+        assert!(verify_tx(&ecc, &tx, &tampered_prev_outputs).is_err());
+
+        // Spending someone else's output.
+        let other_pubkey = ecc.derive_pubkey(&[0x22; 32]).unwrap();
+        let other_outputs = vec![TxOutput {
+            value: 10_000,
+            script: Address::from_pk("bitcoincash", &other_pubkey).p2pkh_script().unwrap().into(),
+        }];
+        assert!(verify_tx(&ecc, &tx, &other_outputs).is_err());
+    }
+
+    #[test]
+    fn test_verify_htlc_redeem() {
+        let ecc = init_ecc();
+        let seller_pk = ecc.derive_pubkey(&SECRET_KEY).unwrap();
+        let secret = b"secret".to_vec();
+        let params = SlpHtlcParams {
+            secret_hash: Hash160::digest(secret.clone()),
+            seller_pkh: Address::from_pk("bitcoincash", &seller_pk).hash().clone(),
+            buyer_pkh: Hash160::new([0x33; 20]),
+            timeout: Integer::new(1000).unwrap(),
+        };
+        let mut tx_builder = TxBuilder::new_with_fee(1, 0, 1000);
+        let input_ref = tx_builder.add_input(
+            UnsignedTxInput { prev_out: prev_out(1), sequence: 0xffff_ffff, value: 10_000 },
+            params.script(),
+            SlpHtlcSignatory::Redeem { seller_pk, secret: secret.into(), sig_hash_flags: SigHashFlags::DEFAULT },
+        );
+        tx_builder.add_leftover_output(params.p2sh_script());
+        let mut unsigned_tx = tx_builder.build().unwrap();
+        let sig = ecc.sign(&SECRET_KEY, Sha256d::digest(unsigned_tx.input_preimages(input_ref).ser())).unwrap();
+        unsigned_tx.sign_input(input_ref, sig).unwrap();
+        let tx = unsigned_tx.complete_tx();
+        let prev_outputs = vec![TxOutput { value: 10_000, script: params.p2sh_script() }];
+        assert_eq!(verify_tx(&ecc, &tx, &prev_outputs).unwrap(), vec![InputCheck::Verified]);
+
+        // A contract with other parameters has a different P2SH script.
+        let other_params = SlpHtlcParams { timeout: Integer::new(1001).unwrap(), ..params };
+        let other_outputs = vec![TxOutput { value: 10_000, script: other_params.p2sh_script() }];
+        assert!(verify_tx(&ecc, &tx, &other_outputs).is_err());
+    }
+
+    #[test]
+    fn test_verify_non_minimal_push_script_code() {
+        // The script code of the signature is the spent script as serialized, including pushes that would
+        // be encoded more compactly, e.g. an empty push with OP_PUSHDATA1 instead of OP_0.
+        let ecc = init_ecc();
+        let pubkey = ecc.derive_pubkey(&SECRET_KEY).unwrap();
+        let mut raw_script = vec![OP_PUSHDATA1 as u8, 0, OP_DROP as u8, 0x01, 0x01, OP_DROP as u8, 33];
+        raw_script.extend_from_slice(pubkey.as_slice());
+        raw_script.push(OP_CHECKSIG as u8);
+        let lock_script = Script::from_ops(util::deser_ops(&raw_script).unwrap());
+        assert_eq!(lock_script.ser_ops().as_slice(), raw_script.as_slice());
+
+        let mut tx = UnhashedTx {
+            version: 1,
+            inputs: vec![TxInput::new(prev_out(0), Script::default(), 0xffff_ffff)],
+            outputs: vec![TxOutput { value: 9_000, script: lock_script.clone() }],
+            lock_time: 0,
+        };
+        let mut unsigned = tx.clone();
+        unsigned.inputs[0].value = Some(10_000);
+        unsigned.inputs[0].lock_script = Some(lock_script.clone());
+        let preimage = unsigned.preimages(&[SigHashFlags::DEFAULT]).swap_remove(0).swap_remove(0);
+        let mut sig = ecc.sign(&SECRET_KEY, Sha256d::digest(preimage.ser())).unwrap().to_vec();
+        sig.push(SigHashFlags::DEFAULT.bits() as u8);
+        tx.inputs[0].script = Script::from_ops(vec![push(&sig)]);
+        let prev_outputs = vec![TxOutput { value: 10_000, script: lock_script }];
+        assert_eq!(verify_tx(&ecc, &tx, &prev_outputs).unwrap(), vec![InputCheck::Verified]);
+    }
+
+    #[test]
+    fn test_verify_unsupported() {
+        let ecc = init_ecc();
+        let (tx, _) = p2pkh_tx(&ecc);
+        let op_return = vec![TxOutput { value: 0, script: Script::from_ops(vec![Op::Code(Opcode::OP_RETURN)]) }];
+        assert!(verify_tx(&ecc, &tx, &op_return).is_err());
+        assert!(verify_tx(&ecc, &tx, &[]).is_err());
+    }
+
+    fn push(data: &[u8]) -> Op {
+        Op::PushByteArray { array: data.to_vec().into(), is_minimal: true }
+    }
+
+    /// A tx with one input spending `lock_script` with `script_sig`.
+    fn eval(script_sig: Vec<Op>, lock_script: Vec<Op>, lock_time: u32, sequence: u32) -> Result<InputCheck, ScriptError> {
+        let tx = UnhashedTx {
+            version: 2,
+            inputs: vec![TxInput::new(
+                TxOutpoint { tx_hash: Sha256d::new([0xaa; 32]), vout: 0 },
+                Script::from_ops(script_sig),
+                sequence,
+            )],
+            outputs: vec![],
+            lock_time,
+        };
+        let prev_outputs = vec![TxOutput { value: 1000, script: Script::from_ops(lock_script) }];
+        eval_input(&init_ecc(), &tx, &prev_outputs, 0)
+    }
+
+    #[test]
+    fn test_decode_num() {
+        assert_eq!(decode_num(&[], 5), Ok(0));
+        assert_eq!(decode_num(&[0x01], 5), Ok(1));
+        assert_eq!(decode_num(&[0x81], 5), Ok(-1));
+        assert_eq!(decode_num(&[0xff, 0x00], 5), Ok(255));
+        assert_eq!(decode_num(&[0xff, 0x80], 5), Ok(-255));
+        assert_eq!(decode_num(&[0x00, 0x65, 0xcd, 0x1d], 5), Ok(500_000_000));
+        assert!(decode_num(&[1; 6], 5).is_err());
+    }
+
+    #[test]
+    fn test_cast_to_bool() {
+        assert!(!cast_to_bool(&[]));
+        assert!(!cast_to_bool(&[0, 0]));
+        assert!(!cast_to_bool(&[0, 0x80]));
+        assert!(cast_to_bool(&[1]));
+        assert!(cast_to_bool(&[0x80, 0]));
+    }
+
+    #[test]
+    fn test_eval_flow_control() {
+        let lock_script = vec![
+            Op::Code(OP_IF), push(b"a"), Op::Code(OP_ELSE), push(b"b"), Op::Code(OP_ENDIF), Op::Code(OP_EQUAL),
+        ];
+        assert!(eval(vec![push(b"a"), Op::Code(OP_1)], lock_script.clone(), 0, 0).is_ok());
+        assert!(eval(vec![push(b"b"), Op::Code(OP_0)], lock_script.clone(), 0, 0).is_ok());
+        let err = eval(vec![push(b"b"), Op::Code(OP_1)], lock_script, 0, 0).unwrap_err();
+        assert_eq!(err, ScriptError {
+            script: ScriptKind::LockScript,
+            op_idx: None,
+            op: None,
+            reason: "script evaluated to false".to_string(),
+        });
+        let err = eval(vec![Op::Code(OP_1)], vec![Op::Code(OP_IF)], 0, 0).unwrap_err();
+        assert_eq!(err.reason, "unbalanced conditional");
+    }
+
+    #[test]
+    fn test_eval_reports_failing_op() {
+        let lock_script = vec![Op::Code(OP_HASH160), push(&[0; 20]), Op::Code(OP_EQUALVERIFY), Op::Code(OP_1)];
+        let err = eval(vec![push(b"secret")], lock_script, 0, 0).unwrap_err();
+        assert_eq!(err.script, ScriptKind::LockScript);
+        assert_eq!(err.op_idx, Some(2));
+        assert_eq!(err.op, Some(Op::Code(OP_EQUALVERIFY)));
+        assert!(err.to_string().starts_with("lock script failed at op 2 (OP_EQUALVERIFY): "), "{}", err);
+
+        let err = eval(vec![Op::Code(OP_1)], vec![Op::Code(OP_SHA256)], 0, 0).unwrap_err();
+        assert_eq!((err.op_idx, err.reason.as_str()), (Some(0), "unsupported opcode"));
+        let err = eval(vec![Op::Code(OP_DUP)], vec![Op::Code(OP_1)], 0, 0).unwrap_err();
+        assert_eq!((err.script, err.op_idx), (ScriptKind::ScriptSig, Some(0)));
+        let err = eval(vec![], vec![Op::Code(OP_DROP)], 0, 0).unwrap_err();
+        assert_eq!(err.reason, "stack underflow");
+        let err = eval(vec![Op::Code(OP_1), Op::Code(OP_1)], vec![Op::Code(OP_1)], 0, 0).unwrap_err();
+        assert_eq!(err.reason, "stack must be clean, has 3 items");
+    }
+
+    #[test]
+    fn test_eval_lock_time() {
+        let lock_script = |lock_time: &[u8]| vec![push(lock_time), Op::Code(OP_CHECKLOCKTIMEVERIFY)];
+        assert!(eval(vec![], lock_script(&[100]), 100, 0).is_ok());
+        assert!(eval(vec![], lock_script(&[100]), 101, 0).is_ok());
+        assert!(eval(vec![], lock_script(&[100]), 99, 0).is_err());
+        // Block heights and timestamps don't mix.
+        assert!(eval(vec![], lock_script(&[0x00, 0x65, 0xcd, 0x1d]), 100, 0).is_err());
+        assert!(eval(vec![], lock_script(&[100]), 500_000_000, 0).is_err());
+        let err = eval(vec![], lock_script(&[100]), 100, SEQUENCE_FINAL).unwrap_err();
+        assert_eq!(err.reason, "input sequence is final, which disables the lock time");
+        assert!(eval(vec![], lock_script(&[0x81]), 100, 0).is_err());
+    }
+}
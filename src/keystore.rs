@@ -0,0 +1,578 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey};
+use bitcoin_cash::{Address, Hash160, Hashed, Pubkey};
+use clap::{ArgEnum, Clap};
+use rand::RngCore;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::ecs_client::{ECSClient, Utxo};
+use crate::error::ErrorKind;
+use crate::output::{KeystoreAddressReport, KeystoreReport, Report};
+use crate::util;
+
+/// Environment variable holding the keystore passphrase if no passphrase file is given.
+pub const PASSPHRASE_ENV: &str = "SLP_HTLC_KEYSTORE_PASSPHRASE";
+/// Account of an Electron Cash SLP wallet restored from the same mnemonic. Keys of its receive (0)
+/// and change (1) chains can sign for wallet UTXOs.
+const WALLET_ACCOUNT: &str = "m/44'/245'/0'";
+/// Account of contract keys, with buyer keys on chain 0 and seller keys on chain 1.
+const CONTRACT_ACCOUNT: &str = "m/44'/245'/1'";
+/// Node whose secret key is the HMAC key of HTLC secrets.
+const SECRET_ACCOUNT: &str = "m/44'/245'/2'";
+/// How many keys of each wallet chain are searched for the key of a wallet address.
+const WALLET_KEYS_PER_CHAIN: u32 = 100;
+const KEYSTORE_VERSION: u32 = 1;
+/// scrypt cost of new keystores: N = 2^15, r = 8, p = 1 takes 32 MiB and about 0.1 s.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+#[derive(Clap)]
+pub struct PassphraseOpts {
+    /// File holding the keystore passphrase, read from $SLP_HTLC_KEYSTORE_PASSPHRASE if not given.
+    #[clap(long)]
+    keystore_passphrase_file: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct KeyOpts {
+    /// Encrypted BIP39 keystore holding the contract keys, see create-keystore.
+    #[clap(long)]
+    keystore: Option<String>,
+    #[clap(flatten)]
+    passphrase: PassphraseOpts,
+    /// Export keys the keystore doesn't have from the wallet over RPC (getprivatekeys).
+    #[clap(long)]
+    allow_key_export: bool,
+    /// Fund txs from the UTXOs of this address instead of the wallet's, can be given multiple times.
+    /// Change goes to the first one. Together with --keystore, no wallet is needed.
+    #[clap(long = "funding-address")]
+    funding_addresses: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct CreateKeystore {
+    /// Path of the new keystore file.
+    #[clap(long)]
+    keystore: String,
+    /// Restore the keystore from a mnemonic read from stdin, instead of generating a new one.
+    #[clap(long)]
+    restore: bool,
+    #[clap(flatten)]
+    passphrase: PassphraseOpts,
+}
+
+#[derive(Clap)]
+pub struct KeystoreAddress {
+    #[clap(long)]
+    keystore: String,
+    /// Party of the contracts the address is for.
+    #[clap(long, arg_enum)]
+    role: Role,
+    #[clap(flatten)]
+    passphrase: PassphraseOpts,
+}
+
+/// Party of a contract, each has its own chain of keys.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Buyer,
+    Seller,
+}
+
+/// A BIP39 mnemonic encrypted with a passphrase, from which contract keys are derived with BIP32.
+pub struct Keystore {
+    path: PathBuf,
+    file: KeystoreFile,
+    master: ExtendedPrivKey,
+    /// Secret keys by public key hash, derived on first use.
+    keys: OnceCell<HashMap<Hash160, [u8; 32]>>,
+}
+
+/// The keys available for signing: those of a keystore and, if allowed, those exported by the wallet.
+/// Also knows where the UTXOs funding txs come from.
+pub struct Keys {
+    keystore: Option<Keystore>,
+    allow_key_export: bool,
+    /// Addresses funding txs instead of the wallet, if any.
+    funding_addresses: Vec<Address<'static>>,
+}
+
+/// On-disk format of a keystore.
+///
+/// The mnemonic is encrypted with AES-256-GCM, keyed by scrypt of the passphrase. The next unused index
+/// of each contract key chain is kept in the clear, so addresses can be handed out without reusing them.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    kdf: ScryptParams,
+    /// AES-256-GCM nonce (hex).
+    nonce: String,
+    /// Encrypted mnemonic, followed by the GCM tag (hex).
+    ciphertext: String,
+    next_index: NextIndex,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ScryptParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct NextIndex {
+    buyer: u32,
+    seller: u32,
+    #[serde(default)]
+    secret: u32,
+}
+
+impl PassphraseOpts {
+    pub fn passphrase(&self) -> Result<String> {
+        let passphrase = match &self.keystore_passphrase_file {
+            Some(file) => {
+                let passphrase = std::fs::read_to_string(file)
+                    .with_context(|| format!("Couldn't read keystore passphrase file {}", file))
+                    .context(ErrorKind::InvalidInput)?;
+                passphrase.trim_end_matches(&['\r', '\n'][..]).to_string()
+            }
+            None => std::env::var(PASSPHRASE_ENV)
+                .with_context(|| format!("Keystore passphrase not given, use --keystore-passphrase-file or {}", PASSPHRASE_ENV))
+                .context(ErrorKind::InvalidInput)?,
+        };
+        if passphrase.is_empty() {
+            bail_kind!(ErrorKind::InvalidInput, "Keystore passphrase must not be empty");
+        }
+        Ok(passphrase)
+    }
+}
+
+impl KeyOpts {
+    pub fn resolve(&self, prefix: &str) -> Result<Keys> {
+        let funding_addresses = self.funding_addresses.iter()
+            .map(|address| util::parse_p2pkh_address(address, prefix, "Funding"))
+            .collect::<Result<Vec<_>>>()?;
+        let keystore = match &self.keystore {
+            Some(path) => Some(Keystore::open(path, &self.passphrase.passphrase()?)?),
+            None => None,
+        };
+        Ok(Keys {
+            keystore,
+            allow_key_export: self.allow_key_export,
+            funding_addresses,
+        })
+    }
+}
+
+impl CreateKeystore {
+    pub fn run(&self) -> Result<Report> {
+        let (mnemonic, generated) = if self.restore {
+            let mut phrase = String::new();
+            std::io::stdin().lock().read_line(&mut phrase)
+                .with_context(|| "Couldn't read mnemonic from stdin")
+                .context(ErrorKind::InvalidInput)?;
+            let mnemonic = bip39::Mnemonic::parse(phrase.trim())
+                .with_context(|| "Invalid mnemonic")
+                .context(ErrorKind::InvalidInput)?;
+            (mnemonic, false)
+        } else {
+            let mut entropy = [0; 32];
+            rand::thread_rng().fill_bytes(&mut entropy);
+            (bip39::Mnemonic::from_entropy(&entropy).expect("infallible"), true)
+        };
+        Keystore::create(&self.keystore, &self.passphrase.passphrase()?, &mnemonic)?;
+        Ok(Report::CreateKeystore(KeystoreReport {
+            keystore: self.keystore.clone(),
+            mnemonic: Some(mnemonic.to_string()).filter(|_| generated),
+        }))
+    }
+}
+
+impl KeystoreAddress {
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        let mut keystore = Keystore::open(&self.keystore, &self.passphrase.passphrase()?)?;
+        let (path, address) = keystore.new_address(prefix, self.role)?;
+        Ok(Report::KeystoreAddress(KeystoreAddressReport {
+            address: address.cash_addr().to_string(),
+            path: path.to_string(),
+        }))
+    }
+}
+
+impl Keystore {
+    /// Writes a new keystore for `mnemonic` to `path`, which must not exist yet.
+    pub fn create(path: impl AsRef<Path>, passphrase: &str, mnemonic: &bip39::Mnemonic) -> Result<Keystore> {
+        let path = path.as_ref();
+        let mut salt = [0; 32];
+        let mut nonce = [0; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let kdf = ScryptParams {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+        };
+        let ciphertext = kdf.cipher(passphrase)?
+            .encrypt(Nonce::from_slice(&nonce), mnemonic.to_string().as_bytes())
+            .map_err(|_| anyhow::anyhow!("Encrypting the mnemonic failed"))?;
+        let file = KeystoreFile {
+            version: KEYSTORE_VERSION,
+            kdf,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+            next_index: NextIndex::default(),
+        };
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut writer = options.open(path)
+            .with_context(|| format!("Couldn't create keystore {}", path.display()))
+            .context(ErrorKind::InvalidInput)?;
+        writer.write_all(serde_json::to_string_pretty(&file)?.as_bytes())
+            .with_context(|| format!("Couldn't write keystore {}", path.display()))?;
+        Keystore::new(path, file, mnemonic)
+    }
+
+    pub fn open(path: impl AsRef<Path>, passphrase: &str) -> Result<Keystore> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read keystore {}", path.display()))
+            .context(ErrorKind::InvalidInput)?;
+        let file = serde_json::from_str::<KeystoreFile>(&json)
+            .with_context(|| format!("Invalid keystore {}", path.display()))
+            .context(ErrorKind::InvalidInput)?;
+        if file.version != KEYSTORE_VERSION {
+            bail_kind!(ErrorKind::InvalidInput, "Unsupported keystore version {}", file.version);
+        }
+        let nonce = hex::decode(&file.nonce).ok().filter(|nonce| nonce.len() == 12);
+        let ciphertext = hex::decode(&file.ciphertext).ok();
+        let (nonce, ciphertext) = match (nonce, ciphertext) {
+            (Some(nonce), Some(ciphertext)) => (nonce, ciphertext),
+            _ => bail_kind!(ErrorKind::InvalidInput, "Invalid keystore {}", path.display()),
+        };
+        let phrase = file.kdf.cipher(passphrase)?
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase for keystore {}, or it's corrupted", path.display()))
+            .context(ErrorKind::InvalidInput)?;
+        let mnemonic = std::str::from_utf8(&phrase).ok()
+            .and_then(|phrase| bip39::Mnemonic::parse(phrase).ok())
+            .ok_or_else(|| anyhow::anyhow!("Keystore {} holds an invalid mnemonic", path.display()))
+            .context(ErrorKind::InvalidInput)?;
+        Keystore::new(path, file, &mnemonic)
+    }
+
+    fn new(path: &Path, file: KeystoreFile, mnemonic: &bip39::Mnemonic) -> Result<Keystore> {
+        // Electron Cash only uses the seed, so a network is needed for the master key, but doesn't matter.
+        let master = ExtendedPrivKey::new_master(bitcoin::Network::Bitcoin, &mnemonic.to_seed(""))?;
+        Ok(Keystore {
+            path: path.to_path_buf(),
+            file,
+            master,
+            keys: OnceCell::new(),
+        })
+    }
+
+    /// Derives the next unused key of `role` and saves the keystore, returning its path and address.
+    pub fn new_address(&mut self, prefix: &str, role: Role) -> Result<(DerivationPath, Address<'static>)> {
+        let next_index = match role {
+            Role::Buyer => &mut self.file.next_index.buyer,
+            Role::Seller => &mut self.file.next_index.seller,
+        };
+        let index = *next_index;
+        *next_index += 1;
+        self.save()?;
+        self.keys = OnceCell::new();
+        let path = contract_path(role, index);
+        let (pubkey, _) = self.derive(&path)?;
+        Ok((path, Address::from_pk(prefix, &pubkey).to_owned_address()))
+    }
+
+    /// Derives the next unused HTLC secret and saves the keystore, returning its index and the secret.
+    pub fn new_secret(&mut self) -> Result<(u32, [u8; 32])> {
+        let index = self.file.next_index.secret;
+        self.file.next_index.secret += 1;
+        self.save()?;
+        Ok((index, self.secret(index)?))
+    }
+
+    /// The HTLC secret with the given index.
+    pub fn secret(&self, index: u32) -> Result<[u8; 32]> {
+        Ok(derive_secret(&self.secret_hmac_key()?, index))
+    }
+
+    /// Index of the secret whose hash is `secret_hash`, searching `lookahead` indices past the next unused one,
+    /// which is reset when a keystore is restored.
+    pub fn find_secret(&self, secret_hash: &Hash160, lookahead: u32) -> Result<Option<(u32, [u8; 32])>> {
+        let hmac_key = self.secret_hmac_key()?;
+        let found = (0..self.file.next_index.secret.saturating_add(lookahead))
+            .map(|index| (index, derive_secret(&hmac_key, index)))
+            .find(|(_, secret)| Hash160::digest(secret.to_vec()) == *secret_hash);
+        Ok(found)
+    }
+
+    fn secret_hmac_key(&self) -> Result<[u8; 32]> {
+        let path = DerivationPath::from_str(SECRET_ACCOUNT).expect("infallible");
+        Ok(self.derive(&path)?.1)
+    }
+
+    /// Secret key of `address`, if it belongs to a handed out contract key or one of the first wallet keys.
+    pub fn secret_key(&self, address: &Address) -> Result<Option<[u8; 32]>> {
+        let keys = match self.keys.get() {
+            Some(keys) => keys,
+            None => {
+                let keys = self.derive_keys()?;
+                self.keys.get_or_init(|| keys)
+            }
+        };
+        Ok(keys.get(address.hash()).copied())
+    }
+
+    fn derive_keys(&self) -> Result<HashMap<Hash160, [u8; 32]>> {
+        let secp = Secp256k1::signing_only();
+        let wallet_account = DerivationPath::from_str(WALLET_ACCOUNT).expect("infallible");
+        let contract_account = DerivationPath::from_str(CONTRACT_ACCOUNT).expect("infallible");
+        let chains = [
+            (wallet_account.child(ChildNumber::Normal { index: 0 }), WALLET_KEYS_PER_CHAIN),
+            (wallet_account.child(ChildNumber::Normal { index: 1 }), WALLET_KEYS_PER_CHAIN),
+            (contract_account.child(ChildNumber::Normal { index: 0 }), self.file.next_index.buyer),
+            (contract_account.child(ChildNumber::Normal { index: 1 }), self.file.next_index.seller),
+        ];
+        let mut keys = HashMap::new();
+        for (chain_path, num_keys) in &chains {
+            let chain = self.master.derive_priv(&secp, chain_path)?;
+            for index in 0..*num_keys {
+                let key = chain.ckd_priv(&secp, ChildNumber::Normal { index })?;
+                let pubkey = PublicKey::from_secret_key(&secp, &key.private_key.key);
+                let mut secret_key = [0; 32];
+                secret_key.copy_from_slice(&key.private_key.key[..]);
+                keys.insert(Hash160::digest(pubkey.serialize().to_vec()), secret_key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn derive(&self, path: &DerivationPath) -> Result<(Pubkey, [u8; 32])> {
+        let secp = Secp256k1::signing_only();
+        let key = self.master.derive_priv(&secp, path)?;
+        let pubkey = PublicKey::from_secret_key(&secp, &key.private_key.key);
+        let mut secret_key = [0; 32];
+        secret_key.copy_from_slice(&key.private_key.key[..]);
+        Ok((Pubkey::from_slice(&pubkey.serialize()), secret_key))
+    }
+
+    /// Replaces the keystore file, writing to a temporary file first so it's never left half-written.
+    fn save(&self) -> Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp_path)
+            .and_then(|mut writer| writer.write_all(serde_json::to_string_pretty(&self.file)?.as_bytes()))
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path))
+            .with_context(|| format!("Couldn't save keystore {}", self.path.display()))
+    }
+}
+
+impl ScryptParams {
+    fn cipher(&self, passphrase: &str) -> Result<Aes256Gcm> {
+        let salt = hex::decode(&self.salt)
+            .with_context(|| "Invalid keystore salt")
+            .context(ErrorKind::InvalidInput)?;
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|err| anyhow::anyhow!("Invalid keystore scrypt parameters: {}", err))
+            .context(ErrorKind::InvalidInput)?;
+        let mut key = [0; 32];
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut key).expect("infallible");
+        Ok(Aes256Gcm::new_from_slice(&key).expect("infallible"))
+    }
+}
+
+impl Keys {
+    /// Secret key of the wallet address `address`, from the keystore or, if allowed, exported by the wallet.
+    pub fn secret_key(&self, client: &ECSClient, address: &Address) -> Result<[u8; 32]> {
+        if let Some(keystore) = &self.keystore {
+            if let Some(secret_key) = keystore.secret_key(address)? {
+                return Ok(secret_key);
+            }
+        }
+        if !self.allow_key_export {
+            bail_kind!(
+                ErrorKind::InvalidInput,
+                "No key for {} in the keystore; to export it from the wallet, pass --allow-key-export",
+                address.cash_addr(),
+            );
+        }
+        client.getprivatekeys(address.cash_addr())
+    }
+
+    /// Whether `secret_key` can get the key of `address`, without asking the wallet.
+    pub fn has_key(&self, address: &Address) -> Result<bool> {
+        if self.allow_key_export {
+            return Ok(true);
+        }
+        match &self.keystore {
+            Some(keystore) => Ok(keystore.secret_key(address)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// The UTXOs funding txs: those of the --funding-address addresses if given, otherwise the wallet's.
+    pub fn unspent(&self, client: &ECSClient) -> Result<Vec<Utxo>> {
+        if self.funding_addresses.is_empty() {
+            return client.listunspent();
+        }
+        let mut utxos = Vec::new();
+        for address in &self.funding_addresses {
+            utxos.extend(client.getaddressunspent(address)?);
+        }
+        Ok(utxos)
+    }
+
+    /// Address receiving change: the first --funding-address if given, otherwise a new wallet address.
+    pub fn change_address(&self, client: &ECSClient) -> Result<Address<'static>> {
+        match self.funding_addresses.first() {
+            Some(address) => Ok(address.clone()),
+            None => client.createaddress(),
+        }
+    }
+
+    /// A new address for the contract key of `role`, from the keystore if there is one, otherwise from the wallet.
+    pub fn new_contract_address(&mut self, client: &ECSClient, prefix: &str, role: Role) -> Result<Address<'static>> {
+        match &mut self.keystore {
+            Some(keystore) => Ok(keystore.new_address(prefix, role)?.1),
+            None => client.createaddress(),
+        }
+    }
+}
+
+/// HTLC secret `index`: HMAC-SHA256 keyed by the secret key of `SECRET_ACCOUNT`, of the index as 4 bytes big endian.
+fn derive_secret(hmac_key: &[u8; 32], index: u32) -> [u8; 32] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(hmac_key);
+    engine.input(&index.to_be_bytes());
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+/// BIP32 path of the contract key of `role` with the given index.
+fn contract_path(role: Role, index: u32) -> DerivationPath {
+    let chain = match role {
+        Role::Buyer => 0,
+        Role::Seller => 1,
+    };
+    DerivationPath::from_str(CONTRACT_ACCOUNT).expect("infallible")
+        .child(ChildNumber::Normal { index: chain })
+        .child(ChildNumber::Normal { index })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn keystore_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("slp-htlc-keystore-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_derivation_vectors() {
+        let path = keystore_path("vectors");
+        let mnemonic = bip39::Mnemonic::parse(MNEMONIC).unwrap();
+        let mut keystore = Keystore::create(&path, "passphrase", &mnemonic).unwrap();
+        let (buyer_path, buyer_address) = keystore.new_address("simpleledger", Role::Buyer).unwrap();
+        let (seller_path, seller_address) = keystore.new_address("simpleledger", Role::Seller).unwrap();
+        let (_, second_seller_address) = keystore.new_address("simpleledger", Role::Seller).unwrap();
+        assert_eq!(buyer_path.to_string(), "m/44'/245'/1'/0/0");
+        assert_eq!(seller_path.to_string(), "m/44'/245'/1'/1/0");
+        // Computed independently from the BIP39 seed with BIP32.
+        assert_eq!(buyer_address.hash().to_hex_be(), "f6b36ca70a334fc56efc8cc824bc28397229e1dc");
+        assert_eq!(seller_address.hash().to_hex_be(), "9aae86677f79d0553278a8a418516d2812190bf4");
+        assert_eq!(second_seller_address.hash().to_hex_be(), "f174592cee00f2fee3b077ea5fdf9b7b21c24f35");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_secret_vectors() {
+        let path = keystore_path("secrets");
+        let mnemonic = bip39::Mnemonic::parse(MNEMONIC).unwrap();
+        let mut keystore = Keystore::create(&path, "passphrase", &mnemonic).unwrap();
+        // Computed independently: HMAC-SHA256 keyed by the secret key of m/44'/245'/2', of the index (big endian).
+        let secret_0 = "0c2539db72f3c02d9818f6aa8e194515fe56e7a72d485eb1ecd7b6c7618e73ab";
+        let secret_1 = "f1fbd6a8eacd3fee4b85790eadbb8982c86864acf8a70d5213d38a934c201295";
+        let secret_1000 = "2a55a6cd713fa9da46d3291f8fcbbff38523759eadf7fe9175fde2e0c200f1fa";
+        assert_eq!(hex::encode(keystore.secret(1000).unwrap()), secret_1000);
+        assert_eq!(keystore.new_secret().map(|(index, secret)| (index, hex::encode(secret))).unwrap(), (0, secret_0.to_string()));
+        assert_eq!(keystore.new_secret().map(|(index, secret)| (index, hex::encode(secret))).unwrap(), (1, secret_1.to_string()));
+
+        // The next index was saved, and secrets are found by their hash.
+        let keystore = Keystore::open(&path, "passphrase").unwrap();
+        assert_eq!(keystore.file.next_index.secret, 2);
+        let hash_1 = Hash160::from_hex_be("cfdc68b0a0b217a8b40d0f9dcae6abf2e43de990").unwrap();
+        assert_eq!(keystore.find_secret(&hash_1, 0).unwrap().map(|(index, _)| index), Some(1));
+        let hash_1000 = Hash160::from_hex_be("ae065be52bc351812d85ef50d89c5c05dc1d8c53").unwrap();
+        assert_eq!(keystore.find_secret(&hash_1000, 998).unwrap(), None);
+        assert_eq!(keystore.find_secret(&hash_1000, 999).unwrap().map(|(index, _)| index), Some(1000));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keystore_round_trip() {
+        let path = keystore_path("round-trip");
+        let mnemonic = bip39::Mnemonic::parse(MNEMONIC).unwrap();
+        let mut keystore = Keystore::create(&path, "passphrase", &mnemonic).unwrap();
+        let (_, address) = keystore.new_address("slptest", Role::Seller).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("abandon"));
+        let err = Keystore::create(&path, "passphrase", &mnemonic).err().unwrap();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidInput);
+
+        let err = Keystore::open(&path, "wrong passphrase").err().unwrap();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidInput);
+        let mut keystore = Keystore::open(&path, "passphrase").unwrap();
+        let secret_key = keystore.secret_key(&address).unwrap().unwrap();
+        let (pubkey, _) = keystore.derive(&contract_path(Role::Seller, 0)).unwrap();
+        assert_eq!(Address::from_pk("slptest", &pubkey).cash_addr(), address.cash_addr());
+        assert_eq!(keystore.derive(&contract_path(Role::Seller, 0)).unwrap().1, secret_key);
+        // The next index was saved, so the next address is a new one.
+        let (_, next_address) = keystore.new_address("slptest", Role::Seller).unwrap();
+        assert_ne!(next_address.cash_addr(), address.cash_addr());
+        assert!(keystore.secret_key(&next_address).unwrap().is_some());
+        // Keys that weren't handed out aren't found.
+        let (unused_pubkey, _) = keystore.derive(&contract_path(Role::Seller, 2)).unwrap();
+        assert!(keystore.secret_key(&Address::from_pk("slptest", &unused_pubkey)).unwrap().is_none());
+        // Wallet keys of Electron Cash are.
+        let wallet_path = DerivationPath::from_str("m/44'/245'/0'/1/99").unwrap();
+        let (wallet_pubkey, wallet_key) = keystore.derive(&wallet_path).unwrap();
+        assert_eq!(keystore.secret_key(&Address::from_pk("slptest", &wallet_pubkey)).unwrap(), Some(wallet_key));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tampered_keystore() {
+        let path = keystore_path("tampered");
+        let mnemonic = bip39::Mnemonic::parse(MNEMONIC).unwrap();
+        Keystore::create(&path, "passphrase", &mnemonic).unwrap();
+        let mut file = serde_json::from_str::<KeystoreFile>(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let mut ciphertext = hex::decode(&file.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        file.ciphertext = hex::encode(ciphertext);
+        std::fs::write(&path, serde_json::to_string(&file).unwrap()).unwrap();
+        let err = Keystore::open(&path, "passphrase").err().unwrap();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidInput);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,52 @@
-use bitcoin_cash::{Hash160, Hashed};
+#![allow(clippy::needless_return)]
+
 use clap::Clap;
 
+#[macro_use]
+mod error;
+
+mod amount;
+mod chain;
+mod coin_selection;
 mod contract;
 mod ecs_client;
+mod electrum;
+mod expect;
+mod fee;
+mod fund_htlc;
+mod interpreter;
+mod keystore;
+mod output;
+mod post_office;
 mod send_htlc;
+mod send_htlc_batch;
+mod spend_htlc;
 mod redeem_htlc;
+mod redact;
+mod secret;
+mod slp;
+mod slp_validator;
+mod sponsor;
+mod spv;
 mod timeout_htlc;
 mod util;
 
+use error::{ErrorKind, ErrorReport};
+use keystore::{CreateKeystore, KeystoreAddress};
+use output::OutputFormat;
 use send_htlc::*;
+use send_htlc_batch::*;
 use redeem_htlc::*;
+use secret::{GenSecret, RecoverSecret};
+use sponsor::*;
 use timeout_htlc::*;
 
 #[derive(Clap)]
 #[clap(version = "0.1", author = "Tobias Ruck <contact@be.cash>")]
 struct Opts {
+    /// Print the result as human readable lines or as a single JSON object.
+    #[clap(long, arg_enum, default_value = "text", global = true)]
+    output: OutputFormat,
     #[clap(subcommand)]
     cmd: HtlcCommand,
 }
@@ -22,39 +54,73 @@ struct Opts {
 #[derive(Clap)]
 enum HtlcCommand {
     SendHtlc(SendHtlc),
+    SendHtlcBatch(SendHtlcBatch),
     RedeemHtlc(RedeemHtlc),
+    RedeemHtlcBatch(RedeemHtlcBatch),
     TimeoutHtlc(TimeoutHtlc),
-    GenSecret,
+    TimeoutHtlcBatch(TimeoutHtlcBatch),
+    Sponsor(Sponsor),
+    GenSecret(GenSecret),
+    RecoverSecret(RecoverSecret),
+    CreateKeystore(CreateKeystore),
+    KeystoreAddress(KeystoreAddress),
 }
 
 fn main() {
-    let opts: Opts = Opts::parse();
+    let opts = match Opts::try_parse() {
+        Ok(opts) => opts,
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            let message = err.to_string().trim().trim_start_matches("error: ").to_string();
+            let err = anyhow::Error::msg(message).context(ErrorKind::InvalidInput);
+            let report = ErrorReport::new(&err);
+            report.print();
+            std::process::exit(report.exit_code);
+        }
+    };
     let prefix = "slptest";
     let result = match &opts.cmd {
         HtlcCommand::SendHtlc(make_htlc) => {
             make_htlc.run(prefix)
         }
+        HtlcCommand::SendHtlcBatch(send_htlc_batch) => {
+            send_htlc_batch.run(prefix)
+        }
         HtlcCommand::RedeemHtlc(redeem_htlc) => {
             redeem_htlc.run(prefix)
         }
+        HtlcCommand::RedeemHtlcBatch(redeem_htlc_batch) => {
+            redeem_htlc_batch.run(prefix)
+        }
         HtlcCommand::TimeoutHtlc(timeout_htlc) => {
             timeout_htlc.run(prefix)
         }
-        HtlcCommand::GenSecret => {
-            use rand::RngCore;
-            let mut rng = rand::thread_rng();
-            let mut secret = [0; 32];
-            rng.fill_bytes(&mut secret);
-            println!("secret: {}", hex::encode(&secret));
-            println!("secret hash: {}", hex::encode(&Hash160::digest_slice(&secret)));
-            Ok(())
+        HtlcCommand::TimeoutHtlcBatch(timeout_htlc_batch) => {
+            timeout_htlc_batch.run(prefix)
+        }
+        HtlcCommand::Sponsor(sponsor) => {
+            sponsor.run(prefix)
+        }
+        HtlcCommand::GenSecret(gen_secret) => {
+            gen_secret.run()
+        }
+        HtlcCommand::RecoverSecret(recover_secret) => {
+            recover_secret.run()
+        }
+        HtlcCommand::CreateKeystore(create_keystore) => {
+            create_keystore.run()
+        }
+        HtlcCommand::KeystoreAddress(keystore_address) => {
+            keystore_address.run(prefix)
         }
     };
 
     match result {
-        Ok(()) => {}
+        Ok(report) => report.print(opts.output),
         Err(err) => {
-            eprintln!("Error creating HTLC:\n{:?}", err);
+            let report = ErrorReport::new(&err);
+            report.print();
+            std::process::exit(report.exit_code);
         }
     }
 }
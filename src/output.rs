@@ -0,0 +1,586 @@
+use clap::ArgEnum;
+use bitcoin_cash::*;
+use serde::Serialize;
+
+use crate::contract::HtlcDescriptor;
+use crate::fee::TxSummary;
+use crate::slp::TokenInfo;
+use crate::interpreter::InputCheck;
+
+/// How the result of a command is printed to stdout.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable `key: value` lines.
+    Text,
+    /// A single JSON object, see `Report`.
+    Json,
+}
+
+/// Result of a successful command.
+///
+/// In JSON, the command name is given by the `command` field, e.g. `{"command": "send-htlc", ...}`.
+/// Fields are only ever added to this schema, never renamed or removed.
+#[derive(Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Report {
+    SendHtlc(SendReport),
+    SendHtlcBatch(SendReport),
+    RedeemHtlc(SpendReport),
+    RedeemHtlcBatch(SpendReport),
+    TimeoutHtlc(SpendReport),
+    TimeoutHtlcBatch(SpendReport),
+    Sponsor(SponsorReport),
+    GenSecret(SecretReport),
+    RecoverSecret(SecretReport),
+    CreateKeystore(KeystoreReport),
+    KeystoreAddress(KeystoreAddressReport),
+}
+
+/// A signed transaction.
+#[derive(Serialize)]
+pub struct TxReport {
+    pub txid: String,
+    pub hex: String,
+    pub size: usize,
+    pub fee: u64,
+    pub change: u64,
+    /// False for --dry-run.
+    pub broadcast: bool,
+    /// False for partially signed txs still missing gas inputs from a sponsor.
+    pub complete: bool,
+    pub inputs: Vec<TxInputReport>,
+    pub outputs: Vec<TxOutputReport>,
+}
+
+#[derive(Serialize)]
+pub struct TxInputReport {
+    pub prev_out: String,
+    pub value: u64,
+    pub sequence: u32,
+    pub script_sig: String,
+    /// Whether the signature was verified locally, false for Schnorr signatures.
+    pub verified: bool,
+}
+
+#[derive(Serialize)]
+pub struct TxOutputReport {
+    pub value: u64,
+    pub script: String,
+    /// Address of P2PKH and P2SH outputs.
+    pub address: Option<String>,
+}
+
+/// A token and its GENESIS metadata.
+#[derive(Serialize)]
+pub struct TokenReport {
+    /// Token id in hex, as displayed by explorers.
+    pub token_id: String,
+    pub ticker: String,
+    pub name: String,
+    pub decimals: u32,
+    pub document_uri: Option<String>,
+    pub document_hash: Option<String>,
+}
+
+/// A token amount, both in base units and in whole tokens.
+#[derive(Serialize)]
+pub struct AmountReport {
+    pub base_units: u64,
+    pub tokens: String,
+}
+
+/// An HTLC, with its parameters and the descriptor accepted by the batch commands.
+#[derive(Serialize)]
+pub struct ContractReport {
+    pub contract_utxo: String,
+    pub amount: AmountReport,
+    pub buyer_address: String,
+    pub seller_address: String,
+    pub secret_hash: String,
+    pub timeout: u32,
+    pub descriptor: String,
+}
+
+/// Result of send-htlc and send-htlc-batch.
+#[derive(Serialize)]
+pub struct SendReport {
+    pub tx: TxReport,
+    pub token: TokenReport,
+    pub buyer_address: String,
+    pub contracts: Vec<ContractReport>,
+}
+
+/// Result of the redeem and timeout commands.
+#[derive(Serialize)]
+pub struct SpendReport {
+    pub tx: TxReport,
+    pub token: TokenReport,
+    /// The spent contracts.
+    pub contracts: Vec<ContractReport>,
+    /// New HTLC holding the remainder of a partial redeem.
+    pub remainder: Option<ContractReport>,
+    /// Tokens paid to the post office, see --post-office.
+    pub postage: Option<AmountReport>,
+}
+
+/// Result of sponsor.
+#[derive(Serialize)]
+pub struct SponsorReport {
+    /// The spender's tx, completed with gas inputs.
+    pub tx: TxReport,
+    pub token: TokenReport,
+}
+
+/// Result of gen-secret and recover-secret.
+#[derive(Serialize)]
+pub struct SecretReport {
+    pub secret: String,
+    pub secret_hash: String,
+    /// Index of a secret derived from a keystore, `None` for random secrets.
+    pub index: Option<u32>,
+}
+
+/// Result of create-keystore.
+#[derive(Serialize)]
+pub struct KeystoreReport {
+    pub keystore: String,
+    /// The generated mnemonic, the only backup of the keys; absent with --restore.
+    pub mnemonic: Option<String>,
+}
+
+/// Result of keystore-address.
+#[derive(Serialize)]
+pub struct KeystoreAddressReport {
+    pub address: String,
+    /// BIP32 derivation path of the address' key.
+    pub path: String,
+}
+
+impl Report {
+    pub fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => self.print_text(),
+            OutputFormat::Json => println!("{}", serde_json::to_string(self).expect("infallible")),
+        }
+    }
+
+    fn print_text(&self) {
+        match self {
+            Report::SendHtlc(report) => {
+                report.token.print();
+                for contract in &report.contracts {
+                    println!("amount: {}", report.token.display_amount(&contract.amount));
+                }
+                report.tx.print_summary();
+                println!("buyer address: {}", report.buyer_address);
+                for contract in &report.contracts {
+                    println!("timeout: {}", contract.timeout);
+                    println!("contract UTXO: {}", contract.contract_utxo);
+                    println!("contract descriptor: {}", contract.descriptor);
+                }
+            }
+            Report::SendHtlcBatch(report) => {
+                report.token.print();
+                for contract in &report.contracts {
+                    println!("amount: {}", report.token.display_amount(&contract.amount));
+                }
+                report.tx.print_summary();
+                println!("buyer address: {}", report.buyer_address);
+                for contract in &report.contracts {
+                    println!("contract descriptor: {}", contract.descriptor);
+                }
+            }
+            Report::RedeemHtlc(report) | Report::TimeoutHtlc(report) => {
+                report.print_text(false);
+            }
+            Report::RedeemHtlcBatch(report) | Report::TimeoutHtlcBatch(report) => {
+                report.print_text(true);
+            }
+            Report::Sponsor(report) => {
+                println!("token_id: {}", report.token.token_id);
+                report.token.print();
+                report.tx.print_summary();
+                println!("{}", report.tx.txid);
+            }
+            Report::GenSecret(report) | Report::RecoverSecret(report) => {
+                println!("secret: {}", report.secret);
+                println!("secret hash: {}", report.secret_hash);
+                if let Some(index) = report.index {
+                    println!("secret index: {}", index);
+                }
+            }
+            Report::CreateKeystore(report) => {
+                println!("keystore: {}", report.keystore);
+                if let Some(mnemonic) = &report.mnemonic {
+                    println!("mnemonic: {}", mnemonic);
+                    println!("Write down the mnemonic, it's the only backup of the keystore's keys.");
+                }
+            }
+            Report::KeystoreAddress(report) => {
+                println!("address: {}", report.address);
+                println!("path: {}", report.path);
+            }
+        }
+    }
+}
+
+impl TxReport {
+    /// Decodes `tx`, which spends `prev_outputs` and whose inputs were checked with the results `checks`.
+    /// The tx is marked as not broadcast.
+    pub fn new(tx: &UnhashedTx, prev_outputs: &[TxOutput], checks: &[InputCheck], summary: &TxSummary, prefix: &str) -> Self {
+        let raw_tx = tx.ser();
+        TxReport {
+            txid: Sha256d::digest(raw_tx.clone()).to_hex_le(),
+            hex: hex::encode(&raw_tx),
+            size: summary.size,
+            fee: summary.fee,
+            change: summary.change,
+            broadcast: false,
+            complete: true,
+            inputs: tx.inputs.iter().zip(prev_outputs).zip(checks)
+                .map(|((input, prev_output), check)| TxInputReport {
+                    prev_out: format!("{}:{}", input.prev_out.tx_hash, input.prev_out.vout),
+                    value: prev_output.value,
+                    sequence: input.sequence,
+                    script_sig: input.script.ser_ops().hex(),
+                    verified: *check == InputCheck::Verified,
+                })
+                .collect(),
+            outputs: tx.outputs.iter()
+                .map(|output| TxOutputReport {
+                    value: output.value,
+                    script: output.script.ser_ops().hex(),
+                    address: script_address(&output.script, prefix),
+                })
+                .collect(),
+        }
+    }
+
+    fn print_summary(&self) {
+        println!("tx_size: {}", self.size);
+        println!("fee: {}", self.fee);
+        println!("change: {}", self.change);
+        if self.broadcast {
+            return;
+        }
+        if self.complete {
+            println!("dry run, not broadcast");
+        } else {
+            println!("partially signed, not broadcast, needs gas from a sponsor");
+        }
+        println!("tx_hex: {}", self.hex);
+        for (idx, input) in self.inputs.iter().enumerate() {
+            let verified = if input.verified { "verified" } else { "unverified" };
+            println!("input {}: {} {} sats ({})", idx, input.prev_out, input.value, verified);
+        }
+        for (idx, output) in self.outputs.iter().enumerate() {
+            println!("output {}: {} sats to {}", idx, output.value, output.address.as_ref().unwrap_or(&output.script));
+        }
+    }
+}
+
+/// The address of a P2PKH or P2SH output script.
+fn script_address(script: &Script, prefix: &str) -> Option<String> {
+    use Opcode::*;
+    let ops = deserialize_ops(&script.ser_ops()).ok()?;
+    let (addr_type, hash) = match ops.as_slice() {
+        [Op::Code(OP_DUP), Op::Code(OP_HASH160), Op::PushByteArray { array, .. }, Op::Code(OP_EQUALVERIFY), Op::Code(OP_CHECKSIG)] => {
+            (AddressType::P2PKH, array)
+        }
+        [Op::Code(OP_HASH160), Op::PushByteArray { array, .. }, Op::Code(OP_EQUAL)] => (AddressType::P2SH, array),
+        _ => return None,
+    };
+    let hash = Hash160::from_slice(hash).ok()?;
+    Some(Address::from_hash(prefix, addr_type, hash).cash_addr().to_string())
+}
+
+impl TokenReport {
+    pub fn new(token_id: String, token_info: &TokenInfo) -> Self {
+        TokenReport {
+            token_id,
+            ticker: token_info.ticker.clone(),
+            name: token_info.name.clone(),
+            decimals: token_info.decimals,
+            document_uri: Some(token_info.document_uri.clone()).filter(|uri| !uri.is_empty()),
+            document_hash: token_info.document_hash.as_ref().map(hex::encode),
+        }
+    }
+
+    /// Same format as `TokenInfo::display_amount`.
+    fn display_amount(&self, amount: &AmountReport) -> String {
+        format!("{} ({} {})", amount.base_units, amount.tokens, self.ticker)
+    }
+
+    fn print(&self) {
+        println!("token_ticker: {}", self.ticker);
+        println!("token_name: {}", self.name);
+        println!("token_decimals: {}", self.decimals);
+        if let Some(document_uri) = &self.document_uri {
+            println!("token_document_uri: {}", document_uri);
+        }
+        if let Some(document_hash) = &self.document_hash {
+            println!("token_document_hash: {}", document_hash);
+        }
+    }
+}
+
+impl AmountReport {
+    pub fn new(amount: u64, token_info: &TokenInfo) -> Self {
+        AmountReport {
+            base_units: amount,
+            tokens: token_info.format_amount(amount),
+        }
+    }
+}
+
+impl ContractReport {
+    pub fn new(descriptor: &HtlcDescriptor, amount: u64, token_info: &TokenInfo) -> Self {
+        ContractReport {
+            contract_utxo: format!("{}:{}", descriptor.contract_utxo.tx_hash, descriptor.contract_utxo.vout),
+            amount: AmountReport::new(amount, token_info),
+            buyer_address: descriptor.buyer_address.cash_addr().to_string(),
+            seller_address: descriptor.seller_address.cash_addr().to_string(),
+            secret_hash: descriptor.secret_hash.to_hex_be(),
+            timeout: descriptor.timeout,
+            descriptor: descriptor.to_string(),
+        }
+    }
+}
+
+impl SpendReport {
+    /// Batch commands print the UTXO of each contract, single commands only the amount.
+    fn print_text(&self, batch: bool) {
+        if !batch {
+            for contract in &self.contracts {
+                println!("contract_amount: {}", self.token.display_amount(&contract.amount));
+            }
+        }
+        println!("token_id: {}", self.token.token_id);
+        self.token.print();
+        if batch {
+            for contract in &self.contracts {
+                println!("contract_amount: {} ({})", self.token.display_amount(&contract.amount), contract.contract_utxo);
+            }
+        }
+        self.tx.print_summary();
+        println!("{}", self.tx.txid);
+        if let Some(postage) = &self.postage {
+            println!("postage: {}", self.token.display_amount(postage));
+        }
+        if let Some(remainder) = &self.remainder {
+            println!("remainder_amount: {}", self.token.display_amount(&remainder.amount));
+            println!("remainder contract descriptor: {}", remainder.descriptor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn token() -> TokenReport {
+        TokenReport::new(
+            "bb309e48930671582bea508f9a1d9b491e49b69be3d6f372dc08da2ac6e90eb7".to_string(),
+            &TokenInfo {
+                ticker: "TST".to_string(),
+                name: "Test Token".to_string(),
+                document_uri: String::new(),
+                document_hash: Some(vec![0xab; 32]),
+                decimals: 4,
+            },
+        )
+    }
+
+    fn tx() -> TxReport {
+        TxReport {
+            txid: "6912c3a61f715dba3067e0a17e5613f9d19edeea593b9456f952bd34de06faa5".to_string(),
+            hex: "0100".to_string(),
+            size: 480,
+            fee: 480,
+            change: 9000,
+            broadcast: true,
+            complete: true,
+            inputs: vec![TxInputReport {
+                prev_out: format!("{}:0", "aa".repeat(32)),
+                value: 10_000,
+                sequence: 0xffff_ffff,
+                script_sig: "00".to_string(),
+                verified: true,
+            }],
+            outputs: vec![TxOutputReport {
+                value: 9_520,
+                script: "6a".to_string(),
+                address: None,
+            }],
+        }
+    }
+
+    fn contract() -> ContractReport {
+        ContractReport {
+            contract_utxo: "6912c3a61f715dba3067e0a17e5613f9d19edeea593b9456f952bd34de06faa5:1".to_string(),
+            amount: AmountReport { base_units: 15000, tokens: "1.5".to_string() },
+            buyer_address: "slptest:qbuyer".to_string(),
+            seller_address: "slptest:qseller".to_string(),
+            secret_hash: "6af9c9b8635b453c9ce522bf44a11f0afcd8ad9d".to_string(),
+            timeout: 1000,
+            descriptor: "descriptor".to_string(),
+        }
+    }
+
+    fn contract_json() -> serde_json::Value {
+        json!({
+            "contract_utxo": "6912c3a61f715dba3067e0a17e5613f9d19edeea593b9456f952bd34de06faa5:1",
+            "amount": {"base_units": 15000, "tokens": "1.5"},
+            "buyer_address": "slptest:qbuyer",
+            "seller_address": "slptest:qseller",
+            "secret_hash": "6af9c9b8635b453c9ce522bf44a11f0afcd8ad9d",
+            "timeout": 1000,
+            "descriptor": "descriptor",
+        })
+    }
+
+    fn tx_json() -> serde_json::Value {
+        json!({
+            "txid": "6912c3a61f715dba3067e0a17e5613f9d19edeea593b9456f952bd34de06faa5",
+            "hex": "0100",
+            "size": 480,
+            "fee": 480,
+            "change": 9000,
+            "broadcast": true,
+            "complete": true,
+            "inputs": [{
+                "prev_out": format!("{}:0", "aa".repeat(32)),
+                "value": 10_000,
+                "sequence": 0xffff_ffffu32,
+                "script_sig": "00",
+                "verified": true,
+            }],
+            "outputs": [{"value": 9_520, "script": "6a", "address": null}],
+        })
+    }
+
+    fn token_json() -> serde_json::Value {
+        json!({
+            "token_id": "bb309e48930671582bea508f9a1d9b491e49b69be3d6f372dc08da2ac6e90eb7",
+            "ticker": "TST",
+            "name": "Test Token",
+            "decimals": 4,
+            "document_uri": null,
+            "document_hash": "ab".repeat(32),
+        })
+    }
+
+    #[test]
+    fn test_send_report_schema() {
+        let report = Report::SendHtlc(SendReport {
+            tx: tx(),
+            token: token(),
+            buyer_address: "slptest:qbuyer".to_string(),
+            contracts: vec![contract()],
+        });
+        assert_eq!(serde_json::to_value(&report).unwrap(), json!({
+            "command": "send-htlc",
+            "tx": tx_json(),
+            "token": token_json(),
+            "buyer_address": "slptest:qbuyer",
+            "contracts": [contract_json()],
+        }));
+    }
+
+    #[test]
+    fn test_spend_report_schema() {
+        let report = Report::RedeemHtlc(SpendReport {
+            tx: tx(),
+            token: token(),
+            contracts: vec![contract()],
+            remainder: Some(contract()),
+            postage: None,
+        });
+        assert_eq!(serde_json::to_value(&report).unwrap(), json!({
+            "command": "redeem-htlc",
+            "tx": tx_json(),
+            "token": token_json(),
+            "contracts": [contract_json()],
+            "remainder": contract_json(),
+            "postage": null,
+        }));
+        let report = Report::TimeoutHtlcBatch(SpendReport {
+            tx: tx(),
+            token: token(),
+            contracts: vec![contract(), contract()],
+            remainder: None,
+            postage: Some(AmountReport { base_units: 150, tokens: "0.015".to_string() }),
+        });
+        assert_eq!(serde_json::to_value(&report).unwrap(), json!({
+            "command": "timeout-htlc-batch",
+            "tx": tx_json(),
+            "token": token_json(),
+            "contracts": [contract_json(), contract_json()],
+            "remainder": null,
+            "postage": {"base_units": 150, "tokens": "0.015"},
+        }));
+    }
+
+    #[test]
+    fn test_secret_report_schema() {
+        let report = Report::GenSecret(SecretReport {
+            secret: "00".repeat(32),
+            secret_hash: "6af9c9b8635b453c9ce522bf44a11f0afcd8ad9d".to_string(),
+            index: None,
+        });
+        assert_eq!(serde_json::to_value(&report).unwrap(), json!({
+            "command": "gen-secret",
+            "secret": "00".repeat(32),
+            "secret_hash": "6af9c9b8635b453c9ce522bf44a11f0afcd8ad9d",
+            "index": null,
+        }));
+        let report = Report::RecoverSecret(SecretReport {
+            secret: "00".repeat(32),
+            secret_hash: "6af9c9b8635b453c9ce522bf44a11f0afcd8ad9d".to_string(),
+            index: Some(7),
+        });
+        assert_eq!(serde_json::to_value(&report).unwrap(), json!({
+            "command": "recover-secret",
+            "secret": "00".repeat(32),
+            "secret_hash": "6af9c9b8635b453c9ce522bf44a11f0afcd8ad9d",
+            "index": 7,
+        }));
+    }
+
+    #[test]
+    fn test_tx_report_decode() {
+        let pkh = Hash160::new([0x11; 20]);
+        let p2pkh = Address::from_hash("slptest", AddressType::P2PKH, pkh.clone());
+        let p2sh = Address::from_hash("slptest", AddressType::P2SH, pkh);
+        let tx = UnhashedTx {
+            version: 1,
+            inputs: vec![TxInput::new(
+                TxOutpoint { tx_hash: Sha256d::new([0xaa; 32]), vout: 2 },
+                Script::from_ops(vec![Op::Code(Opcode::OP_0)]),
+                0xffff_fffe,
+            )],
+            outputs: vec![
+                TxOutput { value: 0, script: Script::from_ops(vec![Op::Code(Opcode::OP_RETURN)]) },
+                TxOutput { value: 546, script: p2pkh.clone().into() },
+                TxOutput { value: 1000, script: p2sh.clone().into() },
+            ],
+            lock_time: 0,
+        };
+        let prev_outputs = vec![TxOutput { value: 2000, script: p2pkh.clone().into() }];
+        let summary = TxSummary::new(&tx, &prev_outputs, |idx, _| idx == 2).unwrap();
+        let report = TxReport::new(&tx, &prev_outputs, &[InputCheck::SchnorrUnchecked], &summary, "slptest");
+        assert_eq!(report.txid, Sha256d::digest(tx.ser()).to_hex_le());
+        assert_eq!(report.hex, hex::encode(tx.ser()));
+        assert_eq!((report.size, report.fee, report.change), (tx.ser().len(), 454, 1000));
+        assert!(!report.broadcast);
+        assert_eq!(report.inputs.len(), 1);
+        assert_eq!(report.inputs[0].prev_out, format!("{}:2", "aa".repeat(32)));
+        assert_eq!((report.inputs[0].value, report.inputs[0].sequence), (2000, 0xffff_fffe));
+        assert_eq!(report.inputs[0].script_sig, "00");
+        assert!(!report.inputs[0].verified);
+        let addresses = report.outputs.iter().map(|output| output.address.clone()).collect::<Vec<_>>();
+        assert_eq!(addresses, vec![None, Some(p2pkh.cash_addr().to_string()), Some(p2sh.cash_addr().to_string())]);
+        assert_eq!(report.outputs[0].script, "6a");
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{Context, Result};
+use chttp::{http::StatusCode, prelude::*};
+use std::io::Read;
+use std::time::Duration;
+
+use crate::error::ErrorKind;
+use crate::redact::{self, Redactor};
+
+const PAYMENT_CONTENT_TYPE: &str = "application/simpleledger-payment";
+const PAYMENT_ACK_CONTENT_TYPE: &str = "application/simpleledger-paymentack";
+
+/// Client of an SLP post office, which adds the BCH inputs of a tx in exchange for a token output.
+///
+/// The tx is submitted as a BIP70 `Payment` message; the post office adds its inputs, broadcasts
+/// the tx and returns it in a `PaymentACK`.
+pub struct PostOffice {
+    url: String,
+    timeout: Duration,
+    /// Masks the URL's credentials and secrets the post office echoes in errors.
+    redactor: Redactor,
+}
+
+/// Rates of a post office, as served at `<url>/postage`.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+pub struct Postage {
+    /// SLP address receiving the token fee.
+    pub address: String,
+    /// Satoshis paid by one stamp, i.e. bytes at 1 sat/byte.
+    pub weight: u64,
+    pub stamps: Vec<Stamp>,
+}
+
+/// A token accepted for postage.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Stamp {
+    /// Token id in hex, as displayed by explorers.
+    pub token_id: String,
+    /// Base units of the token per stamp.
+    pub rate: u64,
+}
+
+impl PostOffice {
+    pub fn new(url: &str, timeout: Duration) -> Self {
+        PostOffice {
+            url: url.trim_end_matches('/').to_string(),
+            timeout,
+            redactor: Redactor::new(url),
+        }
+    }
+
+    pub fn postage(&self) -> Result<Postage> {
+        let (url, shown_url) = self.endpoint();
+        let mut response = Request::get(&url)
+            .timeout(self.timeout)
+            .connect_timeout(self.timeout)
+            .body(())
+            .expect("infallible")
+            .send()
+            .with_context(|| format!("Fetching postage from {} failed", shown_url))
+            .context(ErrorKind::RpcTransport)?;
+        let response_text = response.text()
+            .with_context(|| format!("Reading postage from {} failed", shown_url))
+            .context(ErrorKind::RpcTransport)?;
+        let shown_text = self.redactor.text(&response_text);
+        if response.status() != StatusCode::OK {
+            bail_kind!(ErrorKind::RpcTransport, "Post office {} returned {}: {}", shown_url, response.status(), shown_text);
+        }
+        let postage = serde_json::from_str::<Postage>(&response_text)
+            .with_context(|| format!("Post office {} returned invalid postage: {}", shown_url, shown_text))
+            .context(ErrorKind::RpcTransport)?;
+        if postage.weight == 0 {
+            bail_kind!(ErrorKind::RpcTransport, "Post office {} returned a weight of 0", shown_url);
+        }
+        Ok(postage)
+    }
+
+    /// Submits the raw tx, returning the txs the post office broadcast, and its memo.
+    pub fn submit(&self, raw_tx: &[u8]) -> Result<(Vec<Vec<u8>>, Option<String>)> {
+        let (url, shown_url) = self.endpoint();
+        let mut response = Request::post(&url)
+            .timeout(self.timeout)
+            .connect_timeout(self.timeout)
+            .header("Content-Type", PAYMENT_CONTENT_TYPE)
+            .header("Accept", PAYMENT_ACK_CONTENT_TYPE)
+            .body(encode_payment(raw_tx))
+            .expect("infallible")
+            .send()
+            .with_context(|| format!("Submitting tx to {} failed", shown_url))
+            .context(ErrorKind::RpcTransport)?;
+        let mut body = Vec::new();
+        response.body_mut().read_to_end(&mut body)
+            .with_context(|| format!("Reading response of {} failed", shown_url))
+            .context(ErrorKind::RpcTransport)?;
+        if response.status() != StatusCode::OK {
+            // The rejection may quote the tx, which holds the secret of a redeem.
+            bail_kind!(
+                ErrorKind::BroadcastRejected,
+                "Post office {} rejected the tx ({}): {}",
+                shown_url, response.status(), self.redactor.text(&String::from_utf8_lossy(&body)),
+            );
+        }
+        decode_payment_ack(&body)
+            .with_context(|| format!("Post office {} returned an invalid PaymentACK", shown_url))
+            .context(ErrorKind::RpcTransport)
+    }
+
+    /// URL of the postage endpoint, and the same without credentials for messages.
+    fn endpoint(&self) -> (String, String) {
+        let url = format!("{}/postage", self.url);
+        let shown_url = redact::uri(&url);
+        (url, shown_url)
+    }
+}
+
+impl Postage {
+    /// Rate of the token with the given id (hex, as displayed, in any case), if the post office accepts it.
+    pub fn rate(&self, token_id_hex: &str) -> Result<u64> {
+        self.stamps.iter()
+            .find(|stamp| stamp.token_id.eq_ignore_ascii_case(token_id_hex))
+            .map(|stamp| stamp.rate)
+            .ok_or_else(|| anyhow::anyhow!("Post office doesn't accept token {} as postage", token_id_hex))
+            .context(ErrorKind::InvalidInput)
+    }
+
+    /// Stamps needed for a tx of `size` bytes whose outputs exceed its inputs by `shortfall` sats.
+    ///
+    /// The post office pays the shortfall and a fee of 1 sat/byte for the submitted tx; the inputs it adds
+    /// are priced into the rate.
+    pub fn num_stamps(&self, size: usize, shortfall: u64) -> u64 {
+        (size as u64 + shortfall).div_ceil(self.weight)
+    }
+}
+
+/// Encodes a BIP70 `Payment` with a single tx.
+pub fn encode_payment(raw_tx: &[u8]) -> Vec<u8> {
+    let mut payment = Vec::with_capacity(raw_tx.len() + 6);
+    // Field 2, `repeated bytes transactions`.
+    encode_bytes_field(&mut payment, 2, raw_tx);
+    payment
+}
+
+/// Decodes a BIP70 `PaymentACK` into the txs of its `Payment` and its memo.
+pub fn decode_payment_ack(ack: &[u8]) -> Result<(Vec<Vec<u8>>, Option<String>)> {
+    let mut txs = Vec::new();
+    let mut memo = None;
+    for (field, value) in decode_fields(ack)? {
+        match (field, value) {
+            (1, FieldValue::Bytes(payment)) => {
+                for (field, value) in decode_fields(payment)? {
+                    if let (2, FieldValue::Bytes(tx)) = (field, value) {
+                        txs.push(tx.to_vec());
+                    }
+                }
+            }
+            (2, FieldValue::Bytes(text)) => {
+                memo = Some(String::from_utf8(text.to_vec()).context("Memo isn't UTF-8")?);
+            }
+            _ => {}
+        }
+    }
+    Ok((txs, memo))
+}
+
+/// Value of a protobuf field; only length-delimited values are kept.
+enum FieldValue<'a> {
+    Bytes(&'a [u8]),
+    Other,
+}
+
+fn encode_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    encode_varint(out, field << 3 | 2);
+    encode_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn encode_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or_else(|| anyhow::anyhow!("Truncated varint"))?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("Varint too long")
+}
+
+/// Splits a protobuf message into its fields, in order.
+fn decode_fields(mut data: &[u8]) -> Result<Vec<(u64, FieldValue<'_>)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = decode_varint(&mut data)?;
+        let value = match key & 7 {
+            0 => {
+                decode_varint(&mut data)?;
+                FieldValue::Other
+            }
+            1 | 5 => {
+                let len = if key & 7 == 1 { 8 } else { 4 };
+                if data.len() < len {
+                    anyhow::bail!("Truncated fixed field {}", key >> 3);
+                }
+                data = &data[len..];
+                FieldValue::Other
+            }
+            2 => {
+                let len = decode_varint(&mut data)? as usize;
+                if data.len() < len {
+                    anyhow::bail!("Truncated field {}", key >> 3);
+                }
+                let (bytes, rest) = data.split_at(len);
+                data = rest;
+                FieldValue::Bytes(bytes)
+            }
+            wire_type => anyhow::bail!("Unsupported wire type {} of field {}", wire_type, key >> 3),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn postage(weight: u64) -> Postage {
+        Postage {
+            address: "slptest:qq".to_string(),
+            weight,
+            stamps: vec![Stamp { token_id: "aa".repeat(32), rate: 150 }],
+        }
+    }
+
+    #[test]
+    fn test_parse_postage() {
+        let json = r#"{
+            "version": 1,
+            "address": "slptest:qq",
+            "weight": 365,
+            "transactionttl": 30,
+            "stamps": [{"name": "Test", "symbol": "TST", "decimals": 2, "tokenId": "aaaa", "rate": 150}]
+        }"#;
+        let postage = serde_json::from_str::<Postage>(json).unwrap();
+        assert_eq!(postage, Postage {
+            address: "slptest:qq".to_string(),
+            weight: 365,
+            stamps: vec![Stamp { token_id: "aaaa".to_string(), rate: 150 }],
+        });
+    }
+
+    #[test]
+    fn test_num_stamps() {
+        let postage = postage(500);
+        assert_eq!(postage.num_stamps(0, 0), 0);
+        assert_eq!(postage.num_stamps(300, 200), 1);
+        assert_eq!(postage.num_stamps(300, 201), 2);
+        assert_eq!(postage.num_stamps(400, 546), 2);
+        assert_eq!(postage.rate(&"aa".repeat(32)).unwrap(), 150);
+        assert_eq!(postage.rate(&"AA".repeat(32)).unwrap(), 150);
+        assert_eq!(ErrorKind::of(&postage.rate(&"bb".repeat(32)).unwrap_err()), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_payment_round_trip() {
+        let raw_tx = vec![0x5a; 300];
+        let payment = encode_payment(&raw_tx);
+        // Key of field 2 with wire type 2, then 300 as a varint.
+        assert_eq!(payment[..3], [0x12, 0xac, 0x02]);
+        assert_eq!(payment[3..], raw_tx[..]);
+
+        let mut ack = Vec::new();
+        encode_bytes_field(&mut ack, 1, &payment);
+        encode_bytes_field(&mut ack, 2, b"Thanks");
+        assert_eq!(decode_payment_ack(&ack).unwrap(), (vec![raw_tx], Some("Thanks".to_string())));
+    }
+
+    #[test]
+    fn test_decode_skips_other_fields() {
+        let mut payment = vec![0x08, 0x96, 0x01]; // Field 1, varint 150.
+        payment.extend_from_slice(&[0x29, 0, 0, 0, 0, 0, 0, 0, 0]); // Field 5, fixed64.
+        encode_bytes_field(&mut payment, 2, b"tx");
+        let mut ack = Vec::new();
+        encode_bytes_field(&mut ack, 1, &payment);
+        assert_eq!(decode_payment_ack(&ack).unwrap(), (vec![b"tx".to_vec()], None));
+        assert!(decode_payment_ack(&[0x0a, 0x05, 0x12]).is_err());
+        assert!(decode_payment_ack(&[0x0b]).is_err());
+    }
+}
@@ -1,11 +1,19 @@
 use clap::Clap;
 use bitcoin_cash::*;
 use bitcoin_cash_ecc::init_ecc;
-use bitcoin_cash_slp::{slp_send_output, SlpTokenType};
 use anyhow::{Context, Result};
+use std::collections::HashMap;
 
 use crate::contract::*;
 use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::expect::ExpectOpts;
+use crate::fee::*;
+use crate::keystore::KeyOpts;
+use crate::output::*;
+use crate::slp::TokenInfo;
+use crate::slp_validator::SlpValidationOpts;
+use crate::spend_htlc::*;
 use crate::util;
 
 #[derive(Clap)]
@@ -22,67 +30,119 @@ pub struct RedeemHtlc {
     seller_secret_key: Option<String>,
     #[clap(long)]
     seller_address: Option<String>,
+    /// Only redeem this many tokens (whole tokens unless --base-units is set); the remainder is
+    /// locked into a new HTLC with the same buyer and timeout. Requires --remainder-secret-hash.
+    /// Can't be sponsored, as the sponsor's gas inputs change the txid of the remainder contract.
+    #[clap(long, conflicts_with = "sponsored")]
+    partial_amount: Option<String>,
+    /// Interpret --partial-amount and --expect-amount in base units of the token instead of whole tokens.
+    #[clap(long)]
+    base_units: bool,
+    /// Secret hash of the new HTLC holding the remainder of a partial redeem.
+    #[clap(long)]
+    remainder_secret_hash: Option<String>,
+    #[clap(flatten)]
+    expect: ExpectOpts,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    #[clap(flatten)]
+    destination: DestinationOpts,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    #[clap(flatten)]
+    gas: GasOpts,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
+    #[clap(long)]
+    uri: String,
+}
+
+#[derive(Clap)]
+pub struct RedeemHtlcBatch {
+    /// Contract descriptor as printed by send-htlc, can be given multiple times.
+    #[clap(long = "contract", required = true)]
+    contracts: Vec<String>,
+    /// Secret (preimage) for the contracts, can be given multiple times.
+    /// Secrets are matched to contracts by their hash.
+    #[clap(long = "secret", required = true)]
+    secrets: Vec<String>,
+    #[clap(long)]
+    seller_secret_key: Option<String>,
+    /// Send all redeemed tokens to a single output instead of one output per contract.
+    #[clap(long)]
+    consolidate: bool,
+    #[clap(flatten)]
+    expect: ExpectOpts,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    /// Interpret --expect-amount in base units of the token instead of whole tokens.
+    #[clap(long)]
+    base_units: bool,
+    #[clap(flatten)]
+    destination: DestinationOpts,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    #[clap(flatten)]
+    gas: GasOpts,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
     #[clap(long)]
     uri: String,
 }
 
 impl RedeemHtlc {
-    pub fn run(&self, prefix: &str) -> Result<()> {
-        let client = ECSClient::new(self.uri.clone(), prefix);
-        let utxo_msg = "Invalid contract UTXO, must be of form <txid>:<vout>";
-        let utxo_err = anyhow::anyhow!(utxo_msg);
-        let mut contract_utxo_split = self.contract_utxo.splitn(2, ":");
-        let contract_tx_hash_hex = contract_utxo_split.next().expect("infallible");
-        let contract_vout = contract_utxo_split.next().ok_or_else(|| utxo_err)?;
-        let contract_tx_hash = Sha256d::from_hex_le(contract_tx_hash_hex).with_context(|| utxo_msg)?;
-        let contract_vout: u32 = contract_vout.parse().with_context(|| utxo_msg)?;
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        self.gas.check_secret_reveal()?;
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let contract_utxo = util::parse_outpoint(&self.contract_utxo)?;
+        let keys = self.keys.resolve(prefix)?;
         let ecc = init_ecc();
-        let (seller_address, seller_pk, seller_sk) = match (self.seller_secret_key.as_ref(), self.seller_address.as_ref()) {
-            (Some(_), Some(_)) | (None, None) => {
-                anyhow::bail!("Either seller secret key or seller address must be set, but not both.");
-            }
-            (Some(seller_secret_key), None) => {
-                let seller_sk = hex::decode(seller_secret_key)
-                    .with_context(|| "Invalid seller secret key")?;
-                let seller_pk = ecc.derive_pubkey(&seller_sk)?;
-                (Address::from_pk(prefix, &seller_pk), seller_pk, seller_sk)
-            }
-            (None, Some(seller_address)) => {
-                let seller_address = Address::from_cash_addr(seller_address)
-                    .with_context(|| "Invalid seller address")?;
-                if seller_address.prefix_str() != prefix {
-                    anyhow::bail!("Seller address must start with {}.", prefix)
-                }
-                if seller_address.addr_type() != AddressType::P2PKH {
-                    anyhow::bail!("Seller address must be P2PKH")
-                }
-                let seller_sk = client.getprivatekeys(seller_address.cash_addr())
-                    .with_context(|| format!("Address {} not part of wallet", seller_address.cash_addr()))?;
-                let seller_pk = ecc.derive_pubkey(&seller_sk)?;
-                (seller_address, seller_pk, seller_sk.to_vec())
-            }
-        };
-        let buyer_address = Address::from_cash_addr(&self.buyer_address).with_context(
-            || "Invalid buyer address: {}"
+        let (seller_address, seller_pk, seller_sk) = util::resolve_key(
+            &client,
+            &keys,
+            &ecc,
+            prefix,
+            "Seller",
+            self.seller_secret_key.as_ref(),
+            self.seller_address.as_ref(),
         )?;
-        if buyer_address.prefix_str() != prefix {
-            anyhow::bail!("Buyer address must start with {}.", prefix)
-        }
-        if buyer_address.addr_type() != AddressType::P2PKH {
-            anyhow::bail!("Buyer address must be P2PKH")
-        }
-        let secret = hex::decode(&self.secret).with_context(|| "Invalid secret")?;
+        let buyer_address = util::parse_p2pkh_address(&self.buyer_address, prefix, "Buyer")?;
+        let secret = hex::decode(&self.secret)
+            .with_context(|| "Invalid secret")
+            .context(ErrorKind::InvalidInput)?;
         let secret_hash = Hash160::digest(secret.clone());
         let timeout = Integer::new(self.timeout)
-            .with_context(|| format!("Invalid timeout: {}", self.timeout))?;
-
-        if !client.slpvalidate(contract_tx_hash_hex)? {
-            anyhow::bail!("Contract tx is not a valid SLP transaction.");
-        }
+            .with_context(|| format!("Invalid timeout: {}", self.timeout))
+            .context(ErrorKind::InvalidInput)?;
+        let remainder_secret_hash = match (self.partial_amount.as_ref(), self.remainder_secret_hash.as_ref()) {
+            (Some(_), Some(remainder_secret_hash)) => {
+                let remainder_secret_hash = Hash160::from_hex_be(remainder_secret_hash)
+                    .with_context(|| format!("Invalid remainder secret hash: {}", remainder_secret_hash))
+                    .context(ErrorKind::InvalidInput)?;
+                if remainder_secret_hash == secret_hash {
+                    bail_kind!(ErrorKind::InvalidInput, "Remainder secret hash must differ, the secret is revealed by this redeem.");
+                }
+                Some(remainder_secret_hash)
+            }
+            (None, None) => None,
+            _ => bail_kind!(ErrorKind::InvalidInput, "Partial amount and remainder secret hash must be set together."),
+        };
 
-        let (token_id, contract_amount) = util::get_utxo_token_amount(&client, contract_tx_hash_hex, contract_vout)?;
-        println!("contract_amount: {}", contract_amount);
-        println!("token_id: {:?}", token_id);
+        let (token_id, contract_amounts) = contract_token_amounts(&client, &mut self.slp_validation.validity(&client)?, &[&contract_utxo])?;
+        let contract_amount = contract_amounts[0];
+        let token_id_hex = hex::encode(token_id.to_vec());
+        let token_info = TokenInfo::fetch(&client, &token_id_hex)?;
 
         let params = SlpHtlcParams {
             buyer_pkh: buyer_address.hash().clone(),
@@ -90,55 +150,204 @@ impl RedeemHtlc {
             secret_hash,
             timeout,
         };
-        let recipient_address = client.createaddress()?;
-        let recipient_script: Script = recipient_address.into();
-        let mut tx_builder = TxBuilder::new_simple();
-        let contract_ref = tx_builder.add_input(
-            UnsignedTxInput {
-                prev_out: TxOutpoint { tx_hash: contract_tx_hash, vout: contract_vout },
-                sequence: 0xffff_ffff,
-                value: DUST_AMOUNT,
-            },
-            params.script(),
-            SlpHtlcSignatory::Redeem {
+        let (recipient_script, change_script) = self.destination.resolve(&client, &keys, prefix)?;
+        let redeem_amount = match &self.partial_amount {
+            Some(partial_amount) => token_info.parse_amount(partial_amount, self.base_units)?,
+            None => contract_amount,
+        };
+        if redeem_amount == 0 || redeem_amount > contract_amount {
+            bail_kind!(
+                ErrorKind::InvalidInput,
+                "Partial amount must be between {} and {}, got {}",
+                token_info.display_amount(1), token_info.display_amount(contract_amount), token_info.display_amount(redeem_amount),
+            );
+        }
+        let mut token_outputs = vec![TokenOutput {
+            script: recipient_script,
+            amount: redeem_amount,
+        }];
+        // The remainder goes back into an HTLC between the same parties, with a fresh secret.
+        let remainder = match remainder_secret_hash {
+            Some(remainder_secret_hash) if redeem_amount < contract_amount => {
+                let remainder_params = SlpHtlcParams {
+                    buyer_pkh: buyer_address.hash().clone(),
+                    seller_pkh: seller_address.hash().clone(),
+                    secret_hash: remainder_secret_hash.clone(),
+                    timeout,
+                };
+                let remainder_address = Address::from_redeem_script(prefix, remainder_params.script().into())
+                    .expect("infallible");
+                token_outputs.push(TokenOutput {
+                    script: remainder_address.into(),
+                    amount: contract_amount - redeem_amount,
+                });
+                Some((remainder_secret_hash, token_outputs.len() as u32))
+            }
+            _ => None,
+        };
+        let descriptor = HtlcDescriptor {
+            contract_utxo: contract_utxo.clone(),
+            buyer_address: buyer_address.clone(),
+            seller_address: seller_address.clone(),
+            secret_hash: params.secret_hash.clone(),
+            timeout: self.timeout,
+        };
+        // The secret is revealed by broadcasting, so check everything beforehand.
+        self.expect.check(&client, &token_id_hex, &token_info, &[(&descriptor, contract_amount)], self.base_units)?;
+        let contract = ContractReport::new(&descriptor, contract_amount, &token_info);
+        let contract_input = ContractInput {
+            contract_utxo,
+            params,
+            signatory: SlpHtlcSignatory::Redeem {
                 secret: secret.into(),
-                seller_pk
+                seller_pk,
+                sig_hash_flags: SigHashFlags::DEFAULT,
             },
-        );
-
-        tx_builder.add_output(
-            slp_send_output(SlpTokenType::Fungible, &token_id, &[contract_amount])
-        );
+            secret_key: seller_sk,
+            token_amount: contract_amount,
+        };
 
-        tx_builder.add_output(TxOutput {
-            script: recipient_script.clone(),
-            value: DUST_AMOUNT,
-        });
+        let (tx, postage) = spend_contracts(
+            &client,
+            &keys,
+            &ecc,
+            ContractSpend {
+                token_id,
+                contract_inputs: vec![contract_input],
+                token_outputs,
+                change_script,
+                lock_time: None,
+                gas: self.gas.resolve(self.retry.policy()),
+            },
+            &fee_policy,
+            self.dry_run,
+        )?;
 
-        tx_builder.add_leftover_output_bounded(0, u64::MAX, 0, recipient_script);
+        let remainder = match remainder {
+            Some((remainder_secret_hash, remainder_vout)) => {
+                let descriptor = HtlcDescriptor {
+                    contract_utxo: TxOutpoint {
+                        tx_hash: Sha256d::from_hex_le(&tx.txid)
+                            .with_context(|| format!("Broadcast returned invalid txid: {}", tx.txid))?,
+                        vout: remainder_vout,
+                    },
+                    buyer_address,
+                    seller_address,
+                    secret_hash: remainder_secret_hash,
+                    timeout: self.timeout,
+                };
+                Some(ContractReport::new(&descriptor, contract_amount - redeem_amount, &token_info))
+            }
+            None => None,
+        };
 
-        let (mut unsigned_tx, gas_inputs) = util::add_gas_inputs(&client, &ecc, tx_builder)?;
+        Ok(Report::RedeemHtlc(SpendReport {
+            tx,
+            token: TokenReport::new(token_id_hex, &token_info),
+            contracts: vec![contract],
+            remainder,
+            postage: postage.map(|postage| AmountReport::new(postage, &token_info)),
+        }))
+    }
+}
 
-        let contract_sig = ecc.sign(&seller_sk, Sha256d::digest(unsigned_tx.input_preimages(contract_ref).ser()))?;
-        unsigned_tx.sign_input(contract_ref, contract_sig)?;
-        for (gas_ref, utxo_sk) in gas_inputs {
-            let gas_sig = ecc.sign(&utxo_sk, Sha256d::digest(unsigned_tx.input_preimages(gas_ref).ser()))?;
-            unsigned_tx.sign_input(gas_ref, gas_sig)?;
+impl RedeemHtlcBatch {
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        self.gas.check_secret_reveal()?;
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let keys = self.keys.resolve(prefix)?;
+        let ecc = init_ecc();
+        let descriptors = self.contracts.iter()
+            .map(|contract| HtlcDescriptor::parse(contract, prefix))
+            .collect::<Result<Vec<_>>>()?;
+        let mut secrets = HashMap::new();
+        for secret in &self.secrets {
+            let secret = hex::decode(secret)
+                .with_context(|| "Invalid secret")
+                .context(ErrorKind::InvalidInput)?;
+            secrets.insert(Hash160::digest(secret.clone()), secret);
         }
 
-        let htlc_tx = unsigned_tx.complete_tx();
-        let htlc_raw_tx = htlc_tx.ser();
-        let htlc_tx_hex = hex::encode(&htlc_raw_tx);
+        let seller_key = match &self.seller_secret_key {
+            Some(seller_secret_key) => Some(util::resolve_key(
+                &client, &keys, &ecc, prefix, "Seller", Some(seller_secret_key), None,
+            )?),
+            None => None,
+        };
+        let contract_keys = contract_keys(&descriptors, |descriptor| &descriptor.seller_address, |descriptor| {
+            match &seller_key {
+                Some((seller_address, seller_pk, seller_sk)) => {
+                    if seller_address.hash() != descriptor.seller_address.hash() {
+                        bail_kind!(
+                            ErrorKind::ContractMismatch,
+                            "Seller secret key doesn't match seller address {} of contract {}",
+                            descriptor.seller_address.cash_addr(), descriptor,
+                        );
+                    }
+                    Ok((*seller_pk, seller_sk.clone()))
+                }
+                None => {
+                    let (_, seller_pk, seller_sk) = util::resolve_key(
+                        &client, &keys, &ecc, prefix, "Seller", None, Some(&descriptor.seller_address.cash_addr().to_string()),
+                    )?;
+                    Ok((seller_pk, seller_sk))
+                }
+            }
+        })?;
 
-        let tx_hash = client.broadcast(&htlc_tx_hex)
-            .with_context(|| format!("invalid tx: {}", htlc_tx_hex))?;
+        let contract_utxos = descriptors.iter().map(|descriptor| &descriptor.contract_utxo).collect::<Vec<_>>();
+        let (token_id, contract_amounts) = contract_token_amounts(&client, &mut self.slp_validation.validity(&client)?, &contract_utxos)?;
+        let token_id_hex = hex::encode(token_id.to_vec());
+        let token_info = TokenInfo::fetch(&client, &token_id_hex)?;
+        let expected_contracts = descriptors.iter().zip(contract_amounts.iter().cloned()).collect::<Vec<_>>();
+        self.expect.check(&client, &token_id_hex, &token_info, &expected_contracts, self.base_units)?;
+        let contracts = descriptors.iter().zip(&contract_amounts)
+            .map(|(descriptor, &contract_amount)| ContractReport::new(descriptor, contract_amount, &token_info))
+            .collect();
 
-        if tx_hash.starts_with("error") {
-            anyhow::bail!("invalid tx: {}", htlc_tx_hex)
+        let (recipient_script, change_script) = self.destination.resolve(&client, &keys, prefix)?;
+        let mut contract_inputs = Vec::with_capacity(descriptors.len());
+        for ((descriptor, contract_amount), (seller_pk, seller_sk)) in descriptors.iter().zip(contract_amounts).zip(contract_keys) {
+            let secret = secrets.get(&descriptor.secret_hash)
+                .ok_or_else(|| anyhow::anyhow!("No secret given for contract {}", descriptor))
+                .context(ErrorKind::InvalidInput)?;
+            contract_inputs.push(ContractInput {
+                contract_utxo: descriptor.contract_utxo.clone(),
+                params: descriptor.params()?,
+                signatory: SlpHtlcSignatory::Redeem {
+                    secret: secret.clone().into(),
+                    seller_pk,
+                    sig_hash_flags: SigHashFlags::DEFAULT,
+                },
+                secret_key: seller_sk,
+                token_amount: contract_amount,
+            });
         }
+        let token_outputs = batch_token_outputs(&contract_inputs, &recipient_script, self.consolidate);
 
-        println!("{}", tx_hash);
+        let (tx, postage) = spend_contracts(
+            &client,
+            &keys,
+            &ecc,
+            ContractSpend {
+                token_id,
+                contract_inputs,
+                token_outputs,
+                change_script,
+                lock_time: None,
+                gas: self.gas.resolve(self.retry.policy()),
+            },
+            &fee_policy,
+            self.dry_run,
+        )?;
 
-        Ok(())
-    } 
+        Ok(Report::RedeemHtlcBatch(SpendReport {
+            tx,
+            token: TokenReport::new(token_id_hex, &token_info),
+            contracts,
+            remainder: None,
+            postage: postage.map(|postage| AmountReport::new(postage, &token_info)),
+        }))
+    }
 }
@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use bitcoin_cash::{Hash160, Hashed};
+use clap::Clap;
+use rand::RngCore;
+
+use crate::error::ErrorKind;
+use crate::keystore::{Keystore, PassphraseOpts};
+use crate::output::{Report, SecretReport};
+
+#[derive(Clap)]
+pub struct GenSecret {
+    /// Derive the secret from this keystore, so it can be recovered with recover-secret. Without it,
+    /// the secret is random and lost with the terminal.
+    #[clap(long)]
+    keystore: Option<String>,
+    #[clap(flatten)]
+    passphrase: PassphraseOpts,
+}
+
+#[derive(Clap)]
+pub struct RecoverSecret {
+    #[clap(long)]
+    keystore: String,
+    #[clap(flatten)]
+    passphrase: PassphraseOpts,
+    /// Index of the secret, as printed by gen-secret.
+    #[clap(long, required_unless_present = "secret-hash", conflicts_with = "secret-hash")]
+    index: Option<u32>,
+    /// Hash of the secret, e.g. from a contract descriptor; the keystore's secrets are searched for it.
+    #[clap(long)]
+    secret_hash: Option<String>,
+    /// How many secrets past the keystore's next unused index to search, e.g. after restoring it.
+    #[clap(long, default_value = "1000")]
+    lookahead: u32,
+}
+
+impl GenSecret {
+    pub fn run(&self) -> Result<Report> {
+        let (index, secret) = match &self.keystore {
+            Some(keystore) => {
+                let (index, secret) = Keystore::open(keystore, &self.passphrase.passphrase()?)?.new_secret()?;
+                (Some(index), secret)
+            }
+            None => {
+                let mut secret = [0; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                (None, secret)
+            }
+        };
+        Ok(Report::GenSecret(SecretReport::new(&secret, index)))
+    }
+}
+
+impl RecoverSecret {
+    pub fn run(&self) -> Result<Report> {
+        let keystore = Keystore::open(&self.keystore, &self.passphrase.passphrase()?)?;
+        let (index, secret) = match (self.index, &self.secret_hash) {
+            (Some(index), _) => (index, keystore.secret(index)?),
+            (None, Some(secret_hash)) => {
+                let secret_hash = Hash160::from_hex_be(secret_hash)
+                    .with_context(|| format!("Invalid secret hash: {}", secret_hash))
+                    .context(ErrorKind::InvalidInput)?;
+                keystore.find_secret(&secret_hash, self.lookahead)?
+                    .ok_or_else(|| anyhow::anyhow!(
+                        "No secret of the keystore has hash {}, try a larger --lookahead", secret_hash.to_hex_be(),
+                    ))
+                    .context(ErrorKind::InvalidInput)?
+            }
+            (None, None) => unreachable!("clap requires --index or --secret-hash"),
+        };
+        Ok(Report::RecoverSecret(SecretReport::new(&secret, Some(index))))
+    }
+}
+
+impl SecretReport {
+    fn new(secret: &[u8], index: Option<u32>) -> Self {
+        SecretReport {
+            secret: hex::encode(secret),
+            secret_hash: hex::encode(Hash160::digest_slice(secret)),
+            index,
+        }
+    }
+}
@@ -1,65 +1,109 @@
 use clap::Clap;
 use bitcoin_cash::*;
+use bitcoin_cash_ecc::init_ecc;
 use anyhow::{Context, Result};
 
+use crate::coin_selection::CoinSelection;
 use crate::contract::*;
 use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::fee::*;
+use crate::fund_htlc::{fund_contracts, ContractFunding};
+use crate::keystore::{KeyOpts, Role};
+use crate::output::*;
+use crate::slp::*;
+use crate::slp_validator::SlpValidationOpts;
+use crate::spend_htlc::TokenOutput;
+use crate::util;
 
 #[derive(Clap)]
 pub struct SendHtlc {
     #[clap(long)]
     token_id: String,
+    /// Amount of tokens to lock, in whole tokens (e.g. 1.5) unless --base-units is set.
     #[clap(long)]
     amount: String,
+    /// Interpret --amount in base units of the token instead of whole tokens.
+    #[clap(long)]
+    base_units: bool,
     #[clap(long)]
     seller_address: String,
     #[clap(long)]
     secret_hash: String,
     #[clap(long)]
     timeout: u32,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    /// Strategy for picking the wallet UTXOs that pay the fee.
+    #[clap(long, arg_enum, default_value = "branch-and-bound")]
+    coin_selection: CoinSelection,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
     #[clap(long)]
     uri: String,
 }
 
 impl SendHtlc {
-    pub fn run(&self, prefix: &str) -> Result<()> {
-        let client = ECSClient::new(self.uri.clone(), prefix);
-        let buyer_address = client.createaddress().with_context(|| "Couldnt create buyer address")?;
-        let seller_address = Address::from_cash_addr(&self.seller_address).with_context(
-            || "Invalid seller address: {}"
-        )?;
-        if seller_address.prefix_str() != prefix {
-            anyhow::bail!("Seller address must start with {}.", prefix)
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let token_info = TokenInfo::fetch(&client, &self.token_id)?;
+        let amount = token_info.parse_amount(&self.amount, self.base_units)?;
+        if amount == 0 {
+            bail_kind!(ErrorKind::InvalidInput, "Amount must be positive");
         }
-        if seller_address.addr_type() != AddressType::P2PKH {
-            anyhow::bail!("Seller address must be P2PKH")
-        }
-        let secret_hash = Hash160::from_hex_be(&self.secret_hash).with_context(
-            || format!("Invalid secret hash")
-        )?;
+        let mut keys = self.keys.resolve(prefix)?;
+        let buyer_address = keys.new_contract_address(&client, prefix, Role::Buyer)
+            .with_context(|| "Couldnt create buyer address")?;
+        let seller_address = util::parse_p2pkh_address(&self.seller_address, prefix, "Seller")?;
+        let secret_hash = Hash160::from_hex_be(&self.secret_hash)
+            .with_context(|| format!("Invalid secret hash: {}", self.secret_hash))
+            .context(ErrorKind::InvalidInput)?;
         let params = SlpHtlcParams {
             seller_pkh: seller_address.hash().clone(),
             buyer_pkh: buyer_address.hash().clone(),
             secret_hash,
             timeout: Integer::new(self.timeout)
-                .with_context(|| format!("Invalid timeout: {}", self.timeout))?,
+                .with_context(|| format!("Invalid timeout: {}", self.timeout))
+                .context(ErrorKind::InvalidInput)?,
         };
-        let script = params.script();
-        let p2sh_address = Address::from_redeem_script(prefix, script.into()).expect("infallible");
-        let p2sh: Script = p2sh_address.clone().into();
-        let tx_hex = client.payto_slp(&self.token_id, &self.amount, p2sh_address.cash_addr())?;
-        let tx_hex = client.signtransaction(&tx_hex)?;
-        let tx_hash = client.broadcast(&tx_hex)?;
-        let raw_tx = hex::decode(&tx_hex)?;
-        let (tx, _): (UnhashedTx, _) = UnhashedTx::deser(raw_tx.clone().into())?;
-        for (idx, output) in tx.outputs.iter().enumerate() {
-            if output.script.ser_ops() == p2sh.ser_ops() {
-                println!("buyer address: {}", buyer_address.cash_addr());
-                println!("timeout: {}", self.timeout);
-                println!("contract UTXO: {}:{}", tx_hash, idx);
-                return Ok(());
-            }
-        }
-        anyhow::bail!("Invalid tx {}, could not find {}.", tx_hex, p2sh.ser_ops().hex());
+        // The contract is the first output after the SLP message.
+        let tx_report = fund_contracts(
+            &client,
+            &keys,
+            &init_ecc(),
+            &mut self.slp_validation.validity(&client)?,
+            ContractFunding {
+                token_id: &self.token_id,
+                contract_outputs: vec![TokenOutput { script: params.p2sh_script(), amount }],
+                coin_selection: self.coin_selection,
+            },
+            &fee_policy,
+            self.dry_run,
+        )?;
+        let descriptor = HtlcDescriptor {
+            contract_utxo: TxOutpoint {
+                tx_hash: Sha256d::from_hex_le(&tx_report.txid)
+                    .with_context(|| format!("Broadcast returned invalid txid: {}", tx_report.txid))?,
+                vout: 1,
+            },
+            buyer_address: buyer_address.clone(),
+            seller_address,
+            secret_hash: params.secret_hash.clone(),
+            timeout: self.timeout,
+        };
+        Ok(Report::SendHtlc(SendReport {
+            token: TokenReport::new(self.token_id.to_lowercase(), &token_info),
+            buyer_address: buyer_address.cash_addr().to_string(),
+            contracts: vec![ContractReport::new(&descriptor, amount, &token_info)],
+            tx: tx_report,
+        }))
     }
-}
\ No newline at end of file
+}
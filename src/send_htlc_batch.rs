@@ -0,0 +1,138 @@
+use clap::Clap;
+use bitcoin_cash::*;
+use bitcoin_cash_ecc::init_ecc;
+use anyhow::{Context, Result};
+
+use crate::coin_selection::CoinSelection;
+use crate::contract::*;
+use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::fee::*;
+use crate::fund_htlc::{fund_contracts, ContractFunding};
+use crate::keystore::{KeyOpts, Role};
+use crate::output::*;
+use crate::slp::*;
+use crate::slp_validator::SlpValidationOpts;
+use crate::spend_htlc::TokenOutput;
+use crate::util;
+
+#[derive(Clap)]
+pub struct SendHtlcBatch {
+    #[clap(long)]
+    token_id: String,
+    /// One HTLC to fund, of form <seller-address>,<amount>,<secret-hash>,<timeout>.
+    /// Can be given multiple times; all HTLCs share one SLP SEND transaction.
+    #[clap(long = "htlc", required = true)]
+    htlcs: Vec<String>,
+    /// Interpret HTLC amounts in base units of the token instead of whole tokens.
+    #[clap(long)]
+    base_units: bool,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    /// Strategy for picking the wallet UTXOs that pay the fee.
+    #[clap(long, arg_enum, default_value = "branch-and-bound")]
+    coin_selection: CoinSelection,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
+    #[clap(long)]
+    uri: String,
+}
+
+struct BatchEntry {
+    seller_address: Address<'static>,
+    amount: u64,
+    secret_hash: Hash160,
+    timeout: u32,
+}
+
+impl SendHtlcBatch {
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let token_info = TokenInfo::fetch(&client, &self.token_id)?;
+        let entries = self.htlcs.iter()
+            .map(|htlc| parse_entry(htlc, prefix, &token_info, self.base_units))
+            .collect::<Result<Vec<_>>>()?;
+        let mut keys = self.keys.resolve(prefix)?;
+        let buyer_address = keys.new_contract_address(&client, prefix, Role::Buyer)
+            .with_context(|| "Couldnt create buyer address")?;
+        let mut contract_outputs = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            let params = SlpHtlcParams {
+                seller_pkh: entry.seller_address.hash().clone(),
+                buyer_pkh: buyer_address.hash().clone(),
+                secret_hash: entry.secret_hash.clone(),
+                timeout: Integer::new(entry.timeout)
+                    .with_context(|| format!("Invalid timeout: {}", entry.timeout))
+                    .context(ErrorKind::InvalidInput)?,
+            };
+            contract_outputs.push(TokenOutput { script: params.p2sh_script(), amount: entry.amount });
+        }
+        // Contracts are the outputs after the SLP message, in the order given.
+        let tx_report = fund_contracts(
+            &client,
+            &keys,
+            &init_ecc(),
+            &mut self.slp_validation.validity(&client)?,
+            ContractFunding {
+                token_id: &self.token_id,
+                contract_outputs,
+                coin_selection: self.coin_selection,
+            },
+            &fee_policy,
+            self.dry_run,
+        )?;
+        let tx_hash = Sha256d::from_hex_le(&tx_report.txid)
+            .with_context(|| format!("Broadcast returned invalid txid: {}", tx_report.txid))?;
+
+        let contracts = entries.iter().zip(1..)
+            .map(|(entry, vout)| {
+                let descriptor = HtlcDescriptor {
+                    contract_utxo: TxOutpoint { tx_hash: tx_hash.clone(), vout },
+                    buyer_address: buyer_address.clone(),
+                    seller_address: entry.seller_address.clone(),
+                    secret_hash: entry.secret_hash.clone(),
+                    timeout: entry.timeout,
+                };
+                ContractReport::new(&descriptor, entry.amount, &token_info)
+            })
+            .collect();
+
+        Ok(Report::SendHtlcBatch(SendReport {
+            token: TokenReport::new(self.token_id.to_lowercase(), &token_info),
+            buyer_address: buyer_address.cash_addr().to_string(),
+            contracts,
+            tx: tx_report,
+        }))
+    }
+}
+
+fn parse_entry(htlc: &str, prefix: &str, token_info: &TokenInfo, base_units: bool) -> Result<BatchEntry> {
+    let parts = htlc.split(',').collect::<Vec<_>>();
+    if parts.len() != 4 {
+        bail_kind!(
+            ErrorKind::InvalidInput,
+            "Invalid HTLC {:?}, must be of form <seller-address>,<amount>,<secret-hash>,<timeout>", htlc,
+        );
+    }
+    Ok(BatchEntry {
+        seller_address: util::parse_p2pkh_address(parts[0], prefix, "Seller")?,
+        amount: match token_info.parse_amount(parts[1], base_units)? {
+            0 => bail_kind!(ErrorKind::InvalidInput, "Invalid HTLC {:?}, amount must be positive", htlc),
+            amount => amount,
+        },
+        secret_hash: Hash160::from_hex_be(parts[2])
+            .with_context(|| format!("Invalid secret hash: {}", parts[2]))
+            .context(ErrorKind::InvalidInput)?,
+        timeout: parts[3].parse()
+            .with_context(|| format!("Invalid timeout: {}", parts[3]))
+            .context(ErrorKind::InvalidInput)?,
+    })
+}
@@ -0,0 +1,325 @@
+use bitcoin_cash::*;
+use anyhow::{Context, Result};
+
+use crate::amount;
+use crate::ecs_client::ECSClient;
+use crate::error::ErrorKind;
+use crate::util;
+
+const LOKAD_ID: &[u8] = b"SLP\0";
+
+/// Maximum number of decimals a token can declare in its GENESIS.
+const MAX_DECIMALS: u32 = 9;
+
+/// Token types of SLP messages understood by this tool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TokenType {
+    /// Type 1 fungible tokens.
+    Fungible,
+    /// NFT1 group tokens, which work like type 1 tokens and are spent to create NFT1 children.
+    Nft1Group,
+    /// NFT1 children, unique tokens with a quantity of 1 and no mint baton.
+    Nft1Child,
+}
+
+/// The parts of an SLP OP_RETURN message that determine which outputs carry tokens,
+/// plus the token's metadata for GENESIS messages.
+pub enum SlpMessage {
+    Genesis {
+        token_type: TokenType,
+        token_info: TokenInfo,
+        mint_baton_vout: Option<u8>,
+        amount: u64,
+    },
+    Mint {
+        token_type: TokenType,
+        token_id: Sha256d,
+        mint_baton_vout: Option<u8>,
+        amount: u64,
+    },
+    Send {
+        token_type: TokenType,
+        token_id: Sha256d,
+        amounts: Vec<u64>,
+    },
+}
+
+impl SlpMessage {
+    /// Parses the SLP message of a transaction's first output.
+    /// Returns `None` if the script is not an SLP message at all.
+    pub fn parse(script: &Script) -> Option<Result<SlpMessage>> {
+        let ops = script.ops();
+        if ops.len() < 2 || ops[0].op != Op::Code(Opcode::OP_RETURN) {
+            return None;
+        }
+        match &ops[1].op {
+            Op::PushByteArray { array, .. } if array.as_slice() == LOKAD_ID => {}
+            _ => return None,
+        }
+        let mut pushes = Vec::with_capacity(ops.len() - 2);
+        for op in &ops[2..] {
+            match &op.op {
+                Op::PushByteArray { array, .. } => pushes.push(array.as_slice()),
+                op => {
+                    let err = anyhow::anyhow!("Invalid SLP message, non-push op {}", op);
+                    return Some(Err(err.context(ErrorKind::InvalidSlp)));
+                }
+            }
+        }
+        Some(parse_pushes(&pushes).context(ErrorKind::InvalidSlp))
+    }
+
+    pub fn token_type(&self) -> TokenType {
+        match self {
+            SlpMessage::Genesis { token_type, .. } | SlpMessage::Mint { token_type, .. } | SlpMessage::Send { token_type, .. } => {
+                *token_type
+            }
+        }
+    }
+
+    /// Id of the token, given the txid of the transaction carrying the message.
+    pub fn token_id(&self, txid: &Sha256d) -> Sha256d {
+        match self {
+            SlpMessage::Genesis { .. } => txid.clone(),
+            SlpMessage::Mint { token_id, .. } | SlpMessage::Send { token_id, .. } => token_id.clone(),
+        }
+    }
+
+    /// Token amount of output `vout`, if it carries tokens; mint batons carry none.
+    pub fn token_amount(&self, vout: u32) -> Option<u64> {
+        match self {
+            SlpMessage::Genesis { amount, .. } | SlpMessage::Mint { amount, .. } => Some(*amount).filter(|_| vout == 1),
+            SlpMessage::Send { amounts, .. } => vout.checked_sub(1).and_then(|idx| amounts.get(idx as usize).cloned()),
+        }
+    }
+
+    /// Whether output `vout` of the transaction carries tokens or a mint baton.
+    pub fn is_token_output(&self, vout: u32) -> bool {
+        match self {
+            SlpMessage::Genesis { mint_baton_vout, .. } | SlpMessage::Mint { mint_baton_vout, .. } => {
+                vout == 1 || *mint_baton_vout == Some(vout as u8)
+            }
+            SlpMessage::Send { amounts, .. } => {
+                vout >= 1 && amounts.get(vout as usize - 1).is_some_and(|&amount| amount > 0)
+            }
+        }
+    }
+}
+
+/// Metadata of a token, as declared in its GENESIS transaction.
+pub struct TokenInfo {
+    pub ticker: String,
+    pub name: String,
+    pub document_uri: String,
+    pub document_hash: Option<Vec<u8>>,
+    pub decimals: u32,
+}
+
+impl TokenInfo {
+    /// Fetches and parses the GENESIS transaction of the token with the given id (hex, as displayed).
+    pub fn fetch(client: &ECSClient, token_id: &str) -> Result<TokenInfo> {
+        let tx_hex = client.gettransaction(token_id)
+            .with_context(|| format!("Couldn't fetch GENESIS of token {}", token_id))?;
+        let raw_tx = hex::decode(&tx_hex)?;
+        // The token id is the GENESIS txid, make sure we got the right tx.
+        if Sha256d::digest(raw_tx.as_slice()).to_hex_le() != token_id.to_lowercase() {
+            bail_kind!(ErrorKind::InvalidSlp, "Transaction returned for token {} has a different txid", token_id);
+        }
+        let tx = util::deser_tx(&raw_tx)?;
+        let slp_message = tx.outputs.first().and_then(|output| SlpMessage::parse(&output.script));
+        match slp_message {
+            Some(Ok(SlpMessage::Genesis { token_info, .. })) => Ok(token_info),
+            Some(Err(err)) => Err(err.context(format!("Invalid GENESIS of token {}", token_id))),
+            _ => bail_kind!(ErrorKind::InvalidSlp, "Transaction {} is not an SLP GENESIS", token_id),
+        }
+    }
+
+    /// Parses a token amount into base units; in whole tokens unless `base_units` is set.
+    pub fn parse_amount(&self, amount: &str, base_units: bool) -> Result<u64> {
+        let decimals = if base_units { 0 } else { self.decimals };
+        amount::parse_amount(amount, decimals)
+            .with_context(|| format!("Invalid amount of {} (which has {} decimals)", self.ticker, self.decimals))
+            .context(ErrorKind::InvalidInput)
+    }
+
+    /// Formats an amount in base units as whole tokens, e.g. `1.5` for 150 base units with 2 decimals.
+    pub fn format_amount(&self, amount: u64) -> String {
+        amount::format_amount(amount, self.decimals)
+    }
+
+    /// Formats an amount in base units both in base units and in whole tokens, e.g. `150 (1.5 TKN)`.
+    pub fn display_amount(&self, amount: u64) -> String {
+        format!("{} ({} {})", amount, self.format_amount(amount), self.ticker)
+    }
+}
+
+fn parse_pushes(pushes: &[&[u8]]) -> Result<SlpMessage> {
+    if pushes.len() < 2 {
+        anyhow::bail!("Invalid SLP message, too few pushes");
+    }
+    let token_type = match pushes[0] {
+        [0x01] | [0x00, 0x01] => TokenType::Fungible,
+        [0x81] | [0x00, 0x81] => TokenType::Nft1Group,
+        [0x41] | [0x00, 0x41] => TokenType::Nft1Child,
+        token_type => anyhow::bail!("Unsupported SLP token type {}", hex::encode(token_type)),
+    };
+    match pushes[1] {
+        b"GENESIS" => {
+            if pushes.len() != 9 {
+                anyhow::bail!("Invalid SLP GENESIS, expected 9 pushes, got {}", pushes.len());
+            }
+            let document_hash = match pushes[5] {
+                [] => None,
+                hash if hash.len() == 32 => Some(hash.to_vec()),
+                hash => anyhow::bail!("Invalid SLP document hash {}", hex::encode(hash)),
+            };
+            let decimals = match pushes[6] {
+                &[decimals] if decimals as u32 <= MAX_DECIMALS => decimals as u32,
+                decimals => anyhow::bail!("Invalid SLP decimals {}", hex::encode(decimals)),
+            };
+            let mint_baton_vout = parse_mint_baton_vout(pushes[7])?;
+            let amount = parse_amount(pushes[8])?;
+            if token_type == TokenType::Nft1Child && (decimals != 0 || mint_baton_vout.is_some() || amount != 1) {
+                anyhow::bail!("Invalid NFT1 child GENESIS, must have 0 decimals, no mint baton and a quantity of 1");
+            }
+            Ok(SlpMessage::Genesis {
+                token_type,
+                token_info: TokenInfo {
+                    ticker: String::from_utf8_lossy(pushes[2]).into_owned(),
+                    name: String::from_utf8_lossy(pushes[3]).into_owned(),
+                    document_uri: String::from_utf8_lossy(pushes[4]).into_owned(),
+                    document_hash,
+                    decimals,
+                },
+                mint_baton_vout,
+                amount,
+            })
+        }
+        b"MINT" => {
+            if token_type == TokenType::Nft1Child {
+                anyhow::bail!("NFT1 children can't be minted");
+            }
+            if pushes.len() != 5 {
+                anyhow::bail!("Invalid SLP MINT, expected 5 pushes, got {}", pushes.len());
+            }
+            Ok(SlpMessage::Mint {
+                token_type,
+                token_id: parse_token_id(pushes[2])?,
+                mint_baton_vout: parse_mint_baton_vout(pushes[3])?,
+                amount: parse_amount(pushes[4])?,
+            })
+        }
+        b"SEND" => {
+            if pushes.len() < 4 || pushes.len() > 22 {
+                anyhow::bail!("Invalid SLP SEND, expected 4 to 22 pushes, got {}", pushes.len());
+            }
+            Ok(SlpMessage::Send {
+                token_type,
+                token_id: parse_token_id(pushes[2])?,
+                amounts: pushes[3..].iter().map(|amount| parse_amount(amount)).collect::<Result<_>>()?,
+            })
+        }
+        tx_type => anyhow::bail!("Unknown SLP transaction type {:?}", String::from_utf8_lossy(tx_type)),
+    }
+}
+
+fn parse_token_id(token_id: &[u8]) -> Result<Sha256d> {
+    if token_id.len() != 32 {
+        anyhow::bail!("Invalid SLP token id {}", hex::encode(token_id));
+    }
+    // Token ids are pushed in display order, i.e. byte-reversed compared to the txid hash.
+    Ok(Sha256d::from_slice_le(token_id)?)
+}
+
+fn parse_amount(amount: &[u8]) -> Result<u64> {
+    if amount.len() != 8 {
+        anyhow::bail!("Invalid SLP amount {}", hex::encode(amount));
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(amount);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn parse_mint_baton_vout(vout: &[u8]) -> Result<Option<u8>> {
+    match vout {
+        [] => Ok(None),
+        [vout] if *vout >= 2 => Ok(Some(*vout)),
+        _ => anyhow::bail!("Invalid SLP mint baton vout {}", hex::encode(vout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(hex: &str) -> Script {
+        Script::from_ops(deserialize_ops(&hex::decode(hex).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_parse_genesis() {
+        let genesis = script(concat!(
+            "6a", "04534c5000", "0101", "0747454e45534953",
+            "03545354", "0a5465737420546f6b656e", "4c00", "4c00", "0104", "0102", "080000000000002710",
+        ));
+        let (token_info, mint_baton_vout, amount) = match SlpMessage::parse(&genesis) {
+            Some(Ok(SlpMessage::Genesis { token_type: TokenType::Fungible, token_info, mint_baton_vout, amount })) => {
+                (token_info, mint_baton_vout, amount)
+            }
+            _ => panic!("expected GENESIS"),
+        };
+        assert_eq!(amount, 10_000);
+        assert_eq!(token_info.ticker, "TST");
+        assert_eq!(token_info.name, "Test Token");
+        assert_eq!(token_info.document_uri, "");
+        assert_eq!(token_info.document_hash, None);
+        assert_eq!(token_info.decimals, 4);
+        assert_eq!(mint_baton_vout, Some(2));
+        assert_eq!(token_info.parse_amount("1.5", false).unwrap(), 15_000);
+        assert_eq!(token_info.parse_amount("15000", true).unwrap(), 15_000);
+        assert!(token_info.parse_amount("1.00001", false).is_err());
+        assert!(token_info.parse_amount("1.5", true).is_err());
+        assert_eq!(token_info.display_amount(15_000), "15000 (1.5 TST)");
+    }
+
+    #[test]
+    fn test_parse_invalid_genesis() {
+        // 10 decimals
+        let genesis = script(concat!(
+            "6a", "04534c5000", "0101", "0747454e45534953",
+            "03545354", "4c00", "4c00", "4c00", "010a", "4c00", "080000000000002710",
+        ));
+        assert!(matches!(SlpMessage::parse(&genesis), Some(Err(_))));
+        // NFT1 child with a quantity of 2
+        let genesis = script(concat!(
+            "6a", "04534c5000", "0141", "0747454e45534953",
+            "03545354", "4c00", "4c00", "4c00", "0100", "4c00", "080000000000000002",
+        ));
+        assert!(matches!(SlpMessage::parse(&genesis), Some(Err(_))));
+        // Not SLP at all
+        assert!(SlpMessage::parse(&script("6a0401020304")).is_none());
+    }
+
+    #[test]
+    fn test_send_token_outputs() {
+        let send = script(concat!(
+            "6a", "04534c5000", "0101", "0453454e44",
+            "20", "bb309e48930671582bea508f9a1d9b491e49b69be3d6f372dc08da2ac6e90eb7",
+            "080000000000002710", "080000000000000000", "080000000000000001",
+        ));
+        let message = SlpMessage::parse(&send).unwrap().unwrap();
+        assert_eq!(message.token_type(), TokenType::Fungible);
+        match &message {
+            SlpMessage::Send { token_id, amounts, .. } => {
+                assert_eq!(token_id.to_hex_le(), "bb309e48930671582bea508f9a1d9b491e49b69be3d6f372dc08da2ac6e90eb7");
+                assert_eq!(amounts, &[10_000, 0, 1]);
+            }
+            _ => panic!("expected SEND"),
+        }
+        assert!(!message.is_token_output(0));
+        assert!(message.is_token_output(1));
+        assert!(!message.is_token_output(2));
+        assert!(message.is_token_output(3));
+        assert!(!message.is_token_output(4));
+    }
+}
@@ -0,0 +1,517 @@
+use clap::Clap;
+use bitcoin_cash::*;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::ecs_client::ECSClient;
+use crate::error::ErrorKind;
+use crate::slp::{SlpMessage, TokenType};
+use crate::util;
+
+#[derive(Clap)]
+pub struct SlpValidationOpts {
+    /// Trust the wallet daemon's slpvalidate instead of validating the token DAG locally.
+    #[clap(long)]
+    trust_daemon_slp_validation: bool,
+    /// File caching the results of local SLP validation, so the DAG isn't walked again.
+    #[clap(long)]
+    slp_cache: Option<PathBuf>,
+}
+
+/// Looks up transactions by txid, e.g. from a wallet daemon or an indexer.
+pub trait TxSource {
+    fn get_tx(&mut self, txid: &Sha256d) -> Result<UnhashedTx>;
+}
+
+impl TxSource for &ECSClient<'_> {
+    fn get_tx(&mut self, txid: &Sha256d) -> Result<UnhashedTx> {
+        util::get_tx(self, &txid.to_hex_le())
+    }
+}
+
+/// Decides whether transactions are valid SLP transactions.
+pub enum SlpValidity<'c, 'p> {
+    Daemon(&'c ECSClient<'p>),
+    Local(SlpValidator<&'c ECSClient<'p>>),
+}
+
+impl SlpValidationOpts {
+    pub fn validity<'c, 'p>(&self, client: &'c ECSClient<'p>) -> Result<SlpValidity<'c, 'p>> {
+        if self.trust_daemon_slp_validation {
+            return Ok(SlpValidity::Daemon(client));
+        }
+        let mut validator = SlpValidator::new(client);
+        if let Some(slp_cache) = &self.slp_cache {
+            validator = validator.with_cache(slp_cache)?;
+        }
+        Ok(SlpValidity::Local(validator))
+    }
+}
+
+impl SlpValidity<'_, '_> {
+    pub fn is_valid(&mut self, txid: &Sha256d) -> Result<bool> {
+        match self {
+            SlpValidity::Daemon(client) => client.slpvalidate(&txid.to_hex_le()),
+            SlpValidity::Local(validator) => validator.is_valid(txid),
+        }
+    }
+}
+
+/// What an output of a valid SLP transaction carries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SlpOutput {
+    Tokens(u64),
+    MintBaton,
+}
+
+/// The parts of a transaction relevant for SLP validation.
+struct ParsedTx {
+    inputs: Vec<TxOutpoint>,
+    num_outputs: usize,
+    /// `None` for non-SLP transactions and malformed SLP messages, which carry no tokens.
+    message: Option<SlpMessage>,
+    token_id: Option<Sha256d>,
+}
+
+/// Validates SLP transactions (type 1 and NFT1) by walking the token DAG back to the GENESIS.
+///
+/// A GENESIS is valid by itself, except for NFT1 children, whose first input must spend NFT1 group
+/// tokens. A MINT must spend a mint baton of its token, and a SEND must spend at least as many tokens
+/// as it sends, only counting inputs of the same token id and type. Tokens spent by anything else,
+/// including invalid SLP transactions, are burned.
+pub struct SlpValidator<S> {
+    source: S,
+    txs: HashMap<Sha256d, Rc<ParsedTx>>,
+    validity: HashMap<Sha256d, bool>,
+    cache: Option<ValidityCache>,
+}
+
+/// Validity of transactions, persisted as lines of `<txid> valid` or `<txid> invalid`.
+struct ValidityCache {
+    entries: HashMap<Sha256d, bool>,
+    file: File,
+}
+
+impl<S: TxSource> SlpValidator<S> {
+    pub fn new(source: S) -> Self {
+        SlpValidator {
+            source,
+            txs: HashMap::new(),
+            validity: HashMap::new(),
+            cache: None,
+        }
+    }
+
+    /// Reads and appends results to the cache file at `path`, which is created if it doesn't exist.
+    pub fn with_cache(mut self, path: &Path) -> Result<Self> {
+        self.cache = Some(ValidityCache::open(path)?);
+        Ok(self)
+    }
+
+    /// Whether `txid` is a valid SLP transaction.
+    pub fn is_valid(&mut self, txid: &Sha256d) -> Result<bool> {
+        // Walk the DAG depth-first without recursion, it can be arbitrarily deep.
+        let mut stack = vec![(txid.clone(), false)];
+        while let Some((txid, parents_done)) = stack.pop() {
+            if self.validity.contains_key(&txid) {
+                continue;
+            }
+            if let Some(&valid) = self.cache.as_ref().and_then(|cache| cache.entries.get(&txid)) {
+                self.validity.insert(txid, valid);
+                continue;
+            }
+            let tx = self.parsed_tx(&txid)?;
+            if !parents_done {
+                let parents = self.relevant_parents(&tx)?;
+                stack.push((txid, true));
+                stack.extend(parents.into_iter().map(|parent| (parent, false)));
+                continue;
+            }
+            let valid = self.check(&tx)?;
+            if let Some(cache) = &mut self.cache {
+                cache.insert(&txid, valid)?;
+            }
+            self.validity.insert(txid, valid);
+        }
+        Ok(self.validity[txid])
+    }
+
+    fn parsed_tx(&mut self, txid: &Sha256d) -> Result<Rc<ParsedTx>> {
+        if let Some(tx) = self.txs.get(txid) {
+            return Ok(Rc::clone(tx));
+        }
+        let tx = self.source.get_tx(txid)
+            .with_context(|| format!("Couldn't fetch tx {} for SLP validation", txid.to_hex_le()))?;
+        let message = tx.outputs.first()
+            .and_then(|output| SlpMessage::parse(&output.script))
+            .and_then(|message| message.ok());
+        let token_id = message.as_ref().map(|message| message.token_id(txid));
+        let parsed = Rc::new(ParsedTx {
+            inputs: tx.inputs.iter().map(|input| input.prev_out.clone()).collect(),
+            num_outputs: tx.outputs.len(),
+            message,
+            token_id,
+        });
+        self.txs.insert(txid.clone(), Rc::clone(&parsed));
+        Ok(parsed)
+    }
+
+    /// The parent transactions whose validity decides the validity of `tx`.
+    fn relevant_parents(&mut self, tx: &ParsedTx) -> Result<Vec<Sha256d>> {
+        let inputs = match &tx.message {
+            None | Some(SlpMessage::Genesis { token_type: TokenType::Fungible, .. })
+                | Some(SlpMessage::Genesis { token_type: TokenType::Nft1Group, .. }) => return Ok(vec![]),
+            Some(SlpMessage::Genesis { token_type: TokenType::Nft1Child, .. }) => &tx.inputs[..tx.inputs.len().min(1)],
+            Some(SlpMessage::Mint { .. }) | Some(SlpMessage::Send { .. }) => &tx.inputs[..],
+        };
+        let mut parents = Vec::new();
+        let mut seen = HashSet::new();
+        for input in inputs {
+            // Coinbase inputs don't spend anything.
+            if input.tx_hash == Sha256d::new([0; 32]) || !seen.insert(input.tx_hash.clone()) {
+                continue;
+            }
+            let parent = self.parsed_tx(&input.tx_hash)?;
+            let is_relevant = match &tx.message {
+                Some(SlpMessage::Genesis { .. }) => parent.message.is_some(),
+                _ => parent.token_id.is_some() && parent.token_id == tx.token_id,
+            };
+            if is_relevant {
+                parents.push(input.tx_hash.clone());
+            }
+        }
+        Ok(parents)
+    }
+
+    /// Whether `tx` is valid, given the validity of its relevant parents.
+    fn check(&self, tx: &ParsedTx) -> Result<bool> {
+        let (token_id, message) = match (&tx.token_id, &tx.message) {
+            (Some(token_id), Some(message)) => (token_id, message),
+            _ => return Ok(false),
+        };
+        let token_type = message.token_type();
+        let valid = match message {
+            SlpMessage::Genesis { token_type: TokenType::Nft1Child, .. } => {
+                match tx.inputs.first().and_then(|input| self.output(input)) {
+                    Some((_, TokenType::Nft1Group, SlpOutput::Tokens(amount))) => amount > 0,
+                    _ => false,
+                }
+            }
+            SlpMessage::Genesis { .. } => true,
+            SlpMessage::Mint { .. } => tx.inputs.iter()
+                .any(|input| self.output(input) == Some((token_id.clone(), token_type, SlpOutput::MintBaton))),
+            SlpMessage::Send { amounts, .. } => {
+                let input_amount = tx.inputs.iter()
+                    .filter_map(|input| match self.output(input) {
+                        Some((input_token_id, input_token_type, SlpOutput::Tokens(amount)))
+                            if input_token_id == *token_id && input_token_type == token_type => Some(amount as u128),
+                        _ => None,
+                    })
+                    .sum::<u128>();
+                let output_amount = amounts.iter().map(|&amount| amount as u128).sum::<u128>();
+                // Every amount needs an output after the OP_RETURN.
+                amounts.len() < tx.num_outputs && input_amount >= output_amount
+            }
+        };
+        Ok(valid)
+    }
+
+    /// Token id, token type and content of the output spent by an input, if it's an output of a valid SLP tx.
+    fn output(&self, outpoint: &TxOutpoint) -> Option<(Sha256d, TokenType, SlpOutput)> {
+        if self.validity.get(&outpoint.tx_hash) != Some(&true) {
+            return None;
+        }
+        let tx = self.txs.get(&outpoint.tx_hash)?;
+        let message = tx.message.as_ref()?;
+        let vout = outpoint.vout as usize;
+        if vout == 0 || vout >= tx.num_outputs {
+            return None;
+        }
+        let output = match message {
+            SlpMessage::Genesis { mint_baton_vout, amount, .. } | SlpMessage::Mint { mint_baton_vout, amount, .. } => {
+                if vout == 1 {
+                    SlpOutput::Tokens(*amount)
+                } else if *mint_baton_vout == Some(vout as u8) {
+                    SlpOutput::MintBaton
+                } else {
+                    return None;
+                }
+            }
+            SlpMessage::Send { amounts, .. } => SlpOutput::Tokens(*amounts.get(vout - 1)?),
+        };
+        Some((tx.token_id.clone()?, message.token_type(), output))
+    }
+}
+
+impl ValidityCache {
+    fn open(path: &Path) -> Result<ValidityCache> {
+        let cache_err = || format!("Invalid SLP cache {}", path.display());
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let file = File::open(path).with_context(cache_err).context(ErrorKind::InvalidInput)?;
+            for line in BufReader::new(file).lines() {
+                let line = line.with_context(cache_err).context(ErrorKind::InvalidInput)?;
+                let (txid, validity) = line.split_once(' ')
+                    .with_context(|| format!("Invalid line: {}", line))
+                    .with_context(cache_err)
+                    .context(ErrorKind::InvalidInput)?;
+                let valid = match validity {
+                    "valid" => true,
+                    "invalid" => false,
+                    _ => bail_kind!(ErrorKind::InvalidInput, "{}, invalid line: {}", cache_err(), line),
+                };
+                let txid = Sha256d::from_hex_le(txid).with_context(cache_err).context(ErrorKind::InvalidInput)?;
+                entries.insert(txid, valid);
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Couldn't open SLP cache {}", path.display()))
+            .context(ErrorKind::InvalidInput)?;
+        Ok(ValidityCache { entries, file })
+    }
+
+    fn insert(&mut self, txid: &Sha256d, valid: bool) -> Result<()> {
+        writeln!(self.file, "{} {}", txid.to_hex_le(), if valid { "valid" } else { "invalid" })
+            .context("Couldn't write SLP cache")?;
+        self.entries.insert(txid.clone(), valid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENESIS: &[u8] = b"GENESIS";
+    const MINT: &[u8] = b"MINT";
+    const SEND: &[u8] = b"SEND";
+    const FUNGIBLE: &[u8] = &[0x01];
+    const NFT1_GROUP: &[u8] = &[0x81];
+    const NFT1_CHILD: &[u8] = &[0x41];
+
+    /// A set of fixture transactions, counting lookups.
+    #[derive(Default)]
+    struct Fixtures {
+        txs: HashMap<Sha256d, UnhashedTx>,
+        num_lookups: usize,
+    }
+
+    impl TxSource for &mut Fixtures {
+        fn get_tx(&mut self, txid: &Sha256d) -> Result<UnhashedTx> {
+            self.num_lookups += 1;
+            self.txs.get(txid).cloned().ok_or_else(|| anyhow::anyhow!("Unknown tx {}", txid.to_hex_le()))
+        }
+    }
+
+    impl Fixtures {
+        /// Adds a tx spending `inputs` with an OP_RETURN of `pushes` (unless empty) and `num_outputs` more outputs.
+        fn add(&mut self, inputs: &[(&Sha256d, u32)], pushes: &[&[u8]], num_outputs: usize) -> Sha256d {
+            let mut outputs = Vec::with_capacity(num_outputs + 1);
+            if !pushes.is_empty() {
+                outputs.push(TxOutput { value: 0, script: op_return(pushes) });
+            }
+            for _ in 0..num_outputs {
+                outputs.push(TxOutput { value: 546, script: Script::default() });
+            }
+            let inputs = if inputs.is_empty() {
+                // Unique coinbase-like input.
+                vec![TxInput::new(TxOutpoint { tx_hash: Sha256d::new([0; 32]), vout: self.txs.len() as u32 }, Script::default(), 0)]
+            } else {
+                inputs.iter()
+                    .map(|(tx_hash, vout)| {
+                        TxInput::new(TxOutpoint { tx_hash: (*tx_hash).clone(), vout: *vout }, Script::default(), 0xffff_ffff)
+                    })
+                    .collect()
+            };
+            let tx = UnhashedTx { version: 1, inputs, outputs, lock_time: 0 };
+            // A digest keeps its preimage, which refers to the parent txids' preimages in turn. Copy the
+            // hash out so dropping a long chain of txs doesn't recurse through all of them.
+            let txid = Sha256d::from_slice(Sha256d::digest(tx.ser()).as_slice()).unwrap();
+            self.txs.insert(txid.clone(), tx);
+            txid
+        }
+
+        fn genesis(&mut self, token_type: &[u8], inputs: &[(&Sha256d, u32)], mint_baton_vout: Option<u8>, amount: u64) -> Sha256d {
+            let mint_baton_vout = mint_baton_vout.map(|vout| vec![vout]).unwrap_or_default();
+            self.add(inputs, &[token_type, GENESIS, b"TST", b"", b"", b"", &[0], &mint_baton_vout, &amount.to_be_bytes()], 3)
+        }
+
+        fn mint(&mut self, token_type: &[u8], token_id: &Sha256d, inputs: &[(&Sha256d, u32)], mint_baton_vout: u8, amount: u64) -> Sha256d {
+            self.add(inputs, &[token_type, MINT, &token_id_push(token_id), &[mint_baton_vout], &amount.to_be_bytes()], 3)
+        }
+
+        fn send(&mut self, token_type: &[u8], token_id: &Sha256d, inputs: &[(&Sha256d, u32)], amounts: &[u64]) -> Sha256d {
+            let token_id = token_id_push(token_id);
+            let amounts = amounts.iter().map(|amount| amount.to_be_bytes()).collect::<Vec<_>>();
+            let mut pushes = vec![token_type, SEND, &token_id];
+            pushes.extend(amounts.iter().map(|amount| &amount[..]));
+            self.add(inputs, &pushes, amounts.len())
+        }
+
+        fn is_valid(&mut self, txid: &Sha256d) -> bool {
+            SlpValidator::new(self).is_valid(txid).unwrap()
+        }
+    }
+
+    fn token_id_push(token_id: &Sha256d) -> Vec<u8> {
+        let mut token_id = token_id.as_slice().to_vec();
+        token_id.reverse();
+        token_id
+    }
+
+    fn op_return(pushes: &[&[u8]]) -> Script {
+        let mut raw = vec![0x6a, 4];
+        raw.extend_from_slice(b"SLP\0");
+        for push in pushes {
+            match push.len() {
+                0 => raw.extend_from_slice(&[0x4c, 0]),
+                len => {
+                    raw.push(len as u8);
+                    raw.extend_from_slice(push);
+                }
+            }
+        }
+        Script::from_ops(deserialize_ops(&raw).unwrap())
+    }
+
+    #[test]
+    fn test_send() {
+        let mut fixtures = Fixtures::default();
+        let genesis = fixtures.genesis(FUNGIBLE, &[], None, 1000);
+        let send = fixtures.send(FUNGIBLE, &genesis, &[(&genesis, 1)], &[600, 400]);
+        assert!(fixtures.is_valid(&genesis));
+        assert!(fixtures.is_valid(&send));
+
+        // Sending less than the inputs burns the rest, sending more is invalid.
+        let partial = fixtures.send(FUNGIBLE, &genesis, &[(&send, 1)], &[500]);
+        assert!(fixtures.is_valid(&partial));
+        let overspend = fixtures.send(FUNGIBLE, &genesis, &[(&send, 1)], &[601]);
+        assert!(!fixtures.is_valid(&overspend));
+        let combined = fixtures.send(FUNGIBLE, &genesis, &[(&send, 1), (&send, 2)], &[1000]);
+        assert!(fixtures.is_valid(&combined));
+
+        // Outputs of invalid txs carry no tokens.
+        let after_overspend = fixtures.send(FUNGIBLE, &genesis, &[(&overspend, 1)], &[1]);
+        assert!(!fixtures.is_valid(&after_overspend));
+        // Nor do outputs without an amount.
+        let empty_output = fixtures.send(FUNGIBLE, &genesis, &[(&genesis, 2)], &[1]);
+        assert!(!fixtures.is_valid(&empty_output));
+
+        // Every amount needs an output.
+        let mut missing_outputs = fixtures.txs[&send].clone();
+        missing_outputs.outputs.truncate(2);
+        let missing_outputs_txid = Sha256d::digest(missing_outputs.ser());
+        fixtures.txs.insert(missing_outputs_txid.clone(), missing_outputs);
+        assert!(!fixtures.is_valid(&missing_outputs_txid));
+    }
+
+    #[test]
+    fn test_burn() {
+        let mut fixtures = Fixtures::default();
+        let genesis_a = fixtures.genesis(FUNGIBLE, &[], None, 1000);
+        let genesis_b = fixtures.genesis(FUNGIBLE, &[], None, 1000);
+        let non_slp = fixtures.add(&[(&genesis_a, 1)], &[], 1);
+        let after_burn = fixtures.send(FUNGIBLE, &genesis_a, &[(&non_slp, 0)], &[1000]);
+        assert!(!fixtures.is_valid(&after_burn));
+
+        // A SEND of token B burns the token A inputs, but stays valid.
+        let send_b = fixtures.send(FUNGIBLE, &genesis_b, &[(&genesis_a, 1), (&genesis_b, 1)], &[1000]);
+        assert!(fixtures.is_valid(&send_b));
+        let wrong_token = fixtures.send(FUNGIBLE, &genesis_a, &[(&send_b, 1)], &[1]);
+        assert!(!fixtures.is_valid(&wrong_token));
+    }
+
+    #[test]
+    fn test_mint() {
+        let mut fixtures = Fixtures::default();
+        let genesis = fixtures.genesis(FUNGIBLE, &[], Some(2), 1000);
+        let mint = fixtures.mint(FUNGIBLE, &genesis, &[(&genesis, 2)], 3, 500);
+        assert!(fixtures.is_valid(&mint));
+        // The baton moved to output 3.
+        let mint_again = fixtures.mint(FUNGIBLE, &genesis, &[(&mint, 3)], 2, 500);
+        assert!(fixtures.is_valid(&mint_again));
+        let stale_baton = fixtures.mint(FUNGIBLE, &genesis, &[(&mint, 2)], 2, 500);
+        assert!(!fixtures.is_valid(&stale_baton));
+        let no_baton = fixtures.mint(FUNGIBLE, &genesis, &[(&genesis, 1)], 2, 500);
+        assert!(!fixtures.is_valid(&no_baton));
+
+        // Minted tokens can be sent, the baton is no token amount.
+        let send = fixtures.send(FUNGIBLE, &genesis, &[(&genesis, 1), (&mint, 1)], &[1500]);
+        assert!(fixtures.is_valid(&send));
+        let send_baton = fixtures.send(FUNGIBLE, &genesis, &[(&mint_again, 2)], &[1]);
+        assert!(!fixtures.is_valid(&send_baton));
+
+        // A baton of another token doesn't count.
+        let other = fixtures.genesis(FUNGIBLE, &[], Some(2), 1000);
+        let wrong_baton = fixtures.mint(FUNGIBLE, &genesis, &[(&other, 2)], 2, 500);
+        assert!(!fixtures.is_valid(&wrong_baton));
+    }
+
+    #[test]
+    fn test_nft1() {
+        let mut fixtures = Fixtures::default();
+        let group = fixtures.genesis(NFT1_GROUP, &[], None, 10);
+        let group_send = fixtures.send(NFT1_GROUP, &group, &[(&group, 1)], &[1, 9]);
+        assert!(fixtures.is_valid(&group_send));
+
+        // A child GENESIS must spend group tokens with its first input.
+        let child = fixtures.genesis(NFT1_CHILD, &[(&group_send, 1)], None, 1);
+        assert!(fixtures.is_valid(&child));
+        let child_send = fixtures.send(NFT1_CHILD, &child, &[(&child, 1)], &[1]);
+        assert!(fixtures.is_valid(&child_send));
+        let orphan = fixtures.genesis(NFT1_CHILD, &[(&group_send, 3)], None, 1);
+        assert!(!fixtures.is_valid(&orphan));
+        let second_input = fixtures.genesis(NFT1_CHILD, &[(&child, 1), (&group_send, 2)], None, 1);
+        assert!(!fixtures.is_valid(&second_input));
+        let fungible = fixtures.genesis(FUNGIBLE, &[], None, 10);
+        let fungible_parent = fixtures.genesis(NFT1_CHILD, &[(&fungible, 1)], None, 1);
+        assert!(!fixtures.is_valid(&fungible_parent));
+
+        // Token types don't mix, even with the same token id.
+        let wrong_type = fixtures.send(FUNGIBLE, &group, &[(&group_send, 2)], &[9]);
+        assert!(!fixtures.is_valid(&wrong_type));
+    }
+
+    #[test]
+    fn test_deep_dag() {
+        let mut fixtures = Fixtures::default();
+        let genesis = fixtures.genesis(FUNGIBLE, &[], None, 1000);
+        let mut txid = genesis.clone();
+        for _ in 0..5000 {
+            txid = fixtures.send(FUNGIBLE, &genesis, &[(&txid, 1)], &[1000]);
+        }
+        assert!(fixtures.is_valid(&txid));
+    }
+
+    #[test]
+    fn test_cache() {
+        let path = std::env::temp_dir().join(format!("slp-htlc-test-cache-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut fixtures = Fixtures::default();
+        let genesis = fixtures.genesis(FUNGIBLE, &[], None, 1000);
+        let send = fixtures.send(FUNGIBLE, &genesis, &[(&genesis, 1)], &[1000]);
+        let overspend = fixtures.send(FUNGIBLE, &genesis, &[(&send, 1)], &[1001]);
+        {
+            let mut validator = SlpValidator::new(&mut fixtures).with_cache(&path).unwrap();
+            assert!(validator.is_valid(&send).unwrap());
+            assert!(!validator.is_valid(&overspend).unwrap());
+        }
+        assert_eq!(fixtures.num_lookups, 3);
+
+        // Cached results need no lookups.
+        let mut empty = Fixtures::default();
+        let mut validator = SlpValidator::new(&mut empty).with_cache(&path).unwrap();
+        assert!(validator.is_valid(&send).unwrap());
+        assert!(!validator.is_valid(&overspend).unwrap());
+        drop(validator);
+        assert_eq!(empty.num_lookups, 0);
+
+        std::fs::write(&path, "not a cache\n").unwrap();
+        assert!(SlpValidator::new(&mut empty).with_cache(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
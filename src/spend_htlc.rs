@@ -0,0 +1,624 @@
+use clap::Clap;
+use bitcoin_cash::*;
+use bitcoin_cash_slp::{slp_send_output, SlpTokenType, TokenId};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use crate::coin_selection::CoinSelection;
+use crate::contract::*;
+use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::fee::*;
+use crate::keystore::Keys;
+use crate::output::TxReport;
+use crate::post_office::PostOffice;
+use crate::slp_validator::SlpValidity;
+use crate::util;
+
+/// Maximum number of token outputs a single SLP SEND message can carry.
+pub const MAX_SLP_OUTPUTS: usize = 19;
+
+/// Sighash flags of contract inputs of sponsored spends, which leave the other inputs open.
+pub const SPONSORED_SIG_HASH_FLAGS: SigHashFlags = SigHashFlags::from_bits_truncate(
+    SigHashFlags::DEFAULT.bits() | SigHashFlags::ANYONECANPAY.bits(),
+);
+
+#[derive(Clap)]
+pub struct DestinationOpts {
+    /// SLP address (P2PKH or P2SH) receiving the tokens, defaults to a new wallet address.
+    #[clap(long)]
+    destination: Option<String>,
+    /// Address receiving leftover BCH, defaults to a new wallet address.
+    #[clap(long)]
+    change_address: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct GasOpts {
+    /// Strategy for picking the wallet UTXOs that pay the fee.
+    #[clap(long, arg_enum, default_value = "branch-and-bound")]
+    coin_selection: CoinSelection,
+    /// Leave the fee to a sponsor: sign the contract inputs with ALL|ANYONECANPAY, add no gas and print
+    /// the partially signed transaction instead of broadcasting it, see the sponsor command.
+    /// WARNING: a sponsored redeem hands the secret to the sponsor, who can delay the redeem and race
+    /// the seller with it, e.g. to claim the other side of a swap while the contract times out.
+    #[clap(long)]
+    sponsored: bool,
+    /// Confirm that the sponsor of a sponsored redeem learns the secret, see --sponsored.
+    #[clap(long, requires = "sponsored")]
+    accept_secret_reveal: bool,
+    /// URL of an SLP post office, which pays the fee in exchange for tokens taken from the first token
+    /// output. The contract inputs are signed with ALL|ANYONECANPAY and the post office broadcasts the tx.
+    #[clap(long, conflicts_with = "sponsored")]
+    post_office: Option<String>,
+}
+
+/// Who pays the fee of a contract spend.
+pub enum Gas {
+    /// Wallet UTXOs picked by the given strategy, with leftover BCH going to the change script.
+    Wallet(CoinSelection),
+    /// A sponsor, who gets the partially signed tx, see the sponsor command.
+    Sponsor,
+    /// An SLP post office, paid with a token output.
+    PostOffice(PostOffice),
+}
+
+pub struct ContractInput {
+    pub contract_utxo: TxOutpoint,
+    pub params: SlpHtlcParams,
+    pub signatory: SlpHtlcSignatory,
+    pub secret_key: Vec<u8>,
+    pub token_amount: u64,
+}
+
+pub struct TokenOutput {
+    pub script: Script,
+    pub amount: u64,
+}
+
+/// A transaction spending contract UTXOs of one token into token outputs.
+///
+/// Timeout spends need a `lock_time` of at least the contracts' timeout; redeem spends use `None`.
+/// Spends whose gas isn't paid by the wallet get no gas inputs and no change.
+pub struct ContractSpend {
+    pub token_id: TokenId,
+    pub contract_inputs: Vec<ContractInput>,
+    pub token_outputs: Vec<TokenOutput>,
+    pub change_script: Script,
+    pub lock_time: Option<u32>,
+    pub gas: Gas,
+}
+
+impl DestinationOpts {
+    /// Returns the token destination and BCH change scripts, which default to the change address of `keys`.
+    pub fn resolve(&self, client: &ECSClient, keys: &Keys, prefix: &str) -> Result<(Script, Script)> {
+        let wallet_address = match (&self.destination, &self.change_address) {
+            (Some(_), Some(_)) => None,
+            _ => Some(keys.change_address(client)?),
+        };
+        let destination = match &self.destination {
+            Some(destination) => util::parse_address(destination, &[prefix], "Destination")?,
+            None => wallet_address.clone().expect("infallible"),
+        };
+        let change_address = match &self.change_address {
+            Some(change_address) => {
+                util::parse_address(change_address, &[prefix, util::bch_prefix(prefix)], "Change")?
+            }
+            None => wallet_address.expect("infallible"),
+        };
+        Ok((destination.into(), change_address.into()))
+    }
+}
+
+impl GasOpts {
+    pub fn resolve(&self, retry_policy: RetryPolicy) -> Gas {
+        match &self.post_office {
+            Some(url) => Gas::PostOffice(PostOffice::new(url, retry_policy.timeout)),
+            None if self.sponsored => Gas::Sponsor,
+            None => Gas::Wallet(self.coin_selection),
+        }
+    }
+
+    /// Whether the tx is left to a sponsor instead of being broadcast.
+    pub fn is_sponsored(&self) -> bool {
+        self.sponsored
+    }
+
+    /// Refuses a sponsored redeem, which reveals the secret to the sponsor, unless --accept-secret-reveal is set.
+    pub fn check_secret_reveal(&self) -> Result<()> {
+        if self.sponsored && !self.accept_secret_reveal {
+            bail_kind!(
+                ErrorKind::RevealUnsafe,
+                "A sponsored redeem hands the secret to the sponsor, who can race the seller with it. \
+                 Pass --accept-secret-reveal if you trust the sponsor.",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Validates the SLP transaction of each contract UTXO and returns the common token id
+/// together with the token amount of every contract UTXO, in order.
+pub fn contract_token_amounts(
+    client: &ECSClient,
+    slp_validity: &mut SlpValidity,
+    contract_utxos: &[&TxOutpoint],
+) -> Result<(TokenId, Vec<u64>)> {
+    let mut validated = HashMap::new();
+    let mut utxo_tokens = Vec::with_capacity(contract_utxos.len());
+    for contract_utxo in contract_utxos {
+        let tx_hash_hex = contract_utxo.tx_hash.to_hex_le();
+        if !validated.contains_key(&tx_hash_hex) {
+            let is_valid = slp_validity.is_valid(&contract_utxo.tx_hash)?;
+            validated.insert(tx_hash_hex.clone(), is_valid);
+        }
+        if !validated[&tx_hash_hex] {
+            bail_kind!(ErrorKind::InvalidSlp, "Contract tx {} is not a valid SLP transaction.", tx_hash_hex);
+        }
+        utxo_tokens.push(util::get_utxo_token_amount(client, contract_utxo)?);
+    }
+    common_token(contract_utxos, utxo_tokens)
+}
+
+/// The token id shared by all contract UTXOs, given the token id and amount each holds, and their amounts.
+fn common_token(contract_utxos: &[&TxOutpoint], utxo_tokens: Vec<(TokenId, u64)>) -> Result<(TokenId, Vec<u64>)> {
+    let mut token_id: Option<TokenId> = None;
+    let mut amounts = Vec::with_capacity(utxo_tokens.len());
+    for (contract_utxo, (utxo_token_id, amount)) in contract_utxos.iter().zip(utxo_tokens) {
+        match &token_id {
+            Some(token_id) if token_id.as_slice_be() != utxo_token_id.as_slice_be() => {
+                bail_kind!(
+                    ErrorKind::ContractMismatch,
+                    "Contract UTXO {}:{} holds token {}, expected {}",
+                    contract_utxo.tx_hash, contract_utxo.vout,
+                    hex::encode(utxo_token_id.to_vec()), hex::encode(token_id.to_vec()),
+                );
+            }
+            Some(_) => {}
+            None => token_id = Some(utxo_token_id),
+        }
+        amounts.push(amount);
+    }
+    let token_id = token_id
+        .ok_or_else(|| anyhow::anyhow!("No contract UTXOs given"))
+        .context(ErrorKind::InvalidInput)?;
+    Ok((token_id, amounts))
+}
+
+/// The key of each contract, in order, for the party whose address `party` returns. Contracts of the same
+/// address share a key, which `resolve` is called for only once.
+pub fn contract_keys<K: Clone>(
+    descriptors: &[HtlcDescriptor],
+    party: impl Fn(&HtlcDescriptor) -> &Address<'static>,
+    mut resolve: impl FnMut(&HtlcDescriptor) -> Result<K>,
+) -> Result<Vec<K>> {
+    let mut keys = HashMap::new();
+    let mut contract_keys = Vec::with_capacity(descriptors.len());
+    for descriptor in descriptors {
+        let pkh = party(descriptor).hash();
+        let key = match keys.get(pkh) {
+            Some(key) => K::clone(key),
+            None => {
+                let key = resolve(descriptor)?;
+                keys.insert(pkh.clone(), key.clone());
+                key
+            }
+        };
+        contract_keys.push(key);
+    }
+    Ok(contract_keys)
+}
+
+/// Signs, verifies and broadcasts `spend`, paying the fee as given by its `gas`. With `dry_run`, the tx isn't
+/// broadcast or submitted. Returns the tx and, if a post office paid the fee, the postage in base units.
+///
+/// Wallet gas comes from UTXOs picked by the coin selection and signed with `keys`, and BCH leftover above dust goes to the change
+/// script. Otherwise the contract inputs are signed with `SPONSORED_SIG_HASH_FLAGS`: sponsored txs are
+/// returned partially signed without broadcasting them, post office txs get a token output paying the postage,
+/// taken from the first token output, and are submitted to the post office, which adds gas and broadcasts them.
+pub fn spend_contracts(
+    client: &ECSClient,
+    keys: &Keys,
+    ecc: &impl ECC,
+    spend: ContractSpend,
+    fee_policy: &FeePolicy,
+    dry_run: bool,
+) -> Result<(TxReport, Option<u64>)> {
+    let ContractSpend { token_id, mut contract_inputs, mut token_outputs, change_script, lock_time, gas } = spend;
+    if token_outputs.is_empty() || token_outputs.len() > MAX_SLP_OUTPUTS {
+        bail_kind!(
+            ErrorKind::InvalidInput,
+            "SLP SEND must have between 1 and {} token outputs, got {}", MAX_SLP_OUTPUTS, token_outputs.len(),
+        );
+    }
+    let input_amount = contract_inputs.iter().map(|input| input.token_amount).sum::<u64>();
+    let output_amount = token_outputs.iter().map(|output| output.amount).sum::<u64>();
+    if input_amount != output_amount {
+        anyhow::bail!("Token outputs ({}) don't add up to contract amounts ({})", output_amount, input_amount);
+    }
+    for input in &contract_inputs {
+        let contract_output = util::get_output(client, &input.contract_utxo)?;
+        if contract_output.script.ser_ops() != input.params.p2sh_script().ser_ops() {
+            bail_kind!(
+                ErrorKind::ContractMismatch,
+                "Contract UTXO {}:{} isn't locked by an HTLC with the given parameters",
+                input.contract_utxo.tx_hash, input.contract_utxo.vout,
+            );
+        }
+    }
+
+    if !matches!(gas, Gas::Wallet(_)) {
+        for input in &mut contract_inputs {
+            input.signatory = input.signatory.clone().with_sig_hash_flags(SPONSORED_SIG_HASH_FLAGS);
+        }
+    }
+    let coin_selection = match gas {
+        Gas::Wallet(coin_selection) => coin_selection,
+        Gas::Sponsor => {
+            let tx = sign_without_gas(ecc, &contract_inputs, &token_id, &token_outputs, lock_time)?;
+            return Ok((partial_report(client, ecc, &tx)?, None));
+        }
+        Gas::PostOffice(post_office) => {
+            let postage = add_postage(client, ecc, &post_office, &token_id, &contract_inputs, &mut token_outputs, lock_time)?;
+            let tx = sign_without_gas(ecc, &contract_inputs, &token_id, &token_outputs, lock_time)?;
+            let report = if dry_run {
+                partial_report(client, ecc, &tx)?
+            } else {
+                post(client, ecc, &post_office, &tx)?
+            };
+            return Ok((report, Some(postage)));
+        }
+    };
+
+    let num_token_outputs = token_outputs.len();
+    let make_tx_builder = || {
+        let mut tx_builder = tx_builder(&contract_inputs, &token_id, &token_outputs, lock_time, fee_policy.fee_per_kb());
+        tx_builder.add_leftover_output(change_script.clone());
+        tx_builder
+    };
+    let (unsigned_tx, gas_inputs) = util::add_gas_inputs(client, keys, ecc, make_tx_builder, fee_policy.fee_rate, coin_selection)?;
+    let htlc_tx = sign_tx(ecc, unsigned_tx, &contract_inputs, gas_inputs)?;
+    let prev_outputs = util::prev_outputs(client, &htlc_tx)?;
+    // Outputs are the SLP message, the token outputs and then the BCH change, if any.
+    let summary = TxSummary::new(&htlc_tx, &prev_outputs, |idx, _| idx > num_token_outputs)?;
+    fee_policy.check(&summary)?;
+    let report = util::verify_and_broadcast(client, ecc, &htlc_tx, &prev_outputs, &summary, dry_run)?;
+    Ok((report, None))
+}
+
+/// A tx builder spending the contract inputs into the SLP SEND and token outputs.
+fn tx_builder<'b>(
+    contract_inputs: &[ContractInput],
+    token_id: &TokenId,
+    token_outputs: &[TokenOutput],
+    lock_time: Option<u32>,
+    fee_per_kb: u64,
+) -> TxBuilder<'b> {
+    let mut tx_builder = match lock_time {
+        Some(lock_time) => TxBuilder::new_with_fee(2, lock_time, fee_per_kb),
+        None => TxBuilder::new_with_fee(1, 0, fee_per_kb),
+    };
+    for input in contract_inputs {
+        let sequence = match input.signatory {
+            SlpHtlcSignatory::Redeem { .. } => 0xffff_ffff,
+            SlpHtlcSignatory::Timeout { .. } => 0xffff_fffe,
+        };
+        tx_builder.add_input(
+            UnsignedTxInput {
+                prev_out: input.contract_utxo.clone(),
+                sequence,
+                value: DUST_AMOUNT,
+            },
+            input.params.script(),
+            input.signatory.clone(),
+        );
+    }
+    let amounts = token_outputs.iter().map(|output| output.amount).collect::<Vec<_>>();
+    tx_builder.add_output(slp_send_output(SlpTokenType::Fungible, token_id, &amounts));
+    for output in token_outputs {
+        tx_builder.add_output(TxOutput {
+            script: output.script.clone(),
+            value: DUST_AMOUNT,
+        });
+    }
+    tx_builder
+}
+
+/// Signs the contract inputs, which come first in the order given, and the gas inputs.
+fn sign_tx(
+    ecc: &impl ECC,
+    mut unsigned_tx: UnsignedTx,
+    contract_inputs: &[ContractInput],
+    gas_inputs: util::GasInputs,
+) -> Result<UnhashedTx> {
+    for (idx, input) in contract_inputs.iter().enumerate() {
+        let contract_ref = InputReference::<SlpHtlcSignatory>::new(idx);
+        let contract_sig = ecc.sign(&input.secret_key, Sha256d::digest(unsigned_tx.input_preimages(contract_ref).ser()))?;
+        unsigned_tx.sign_input(contract_ref, contract_sig)?;
+    }
+    for (gas_ref, utxo_sk) in gas_inputs {
+        let gas_sig = ecc.sign(&utxo_sk, Sha256d::digest(unsigned_tx.input_preimages(gas_ref).ser()))?;
+        unsigned_tx.sign_input(gas_ref, gas_sig)?;
+    }
+    Ok(unsigned_tx.complete_tx())
+}
+
+/// Signs the contract inputs, which must use ANYONECANPAY, and leaves the gas to someone else.
+fn sign_without_gas(
+    ecc: &impl ECC,
+    contract_inputs: &[ContractInput],
+    token_id: &TokenId,
+    token_outputs: &[TokenOutput],
+    lock_time: Option<u32>,
+) -> Result<UnhashedTx> {
+    let (unsigned_tx, placeholder_inputs) = build_without_gas(ecc, contract_inputs, token_id, token_outputs, lock_time)?;
+    let mut tx = sign_tx(ecc, unsigned_tx, contract_inputs, placeholder_inputs)?;
+    tx.inputs.truncate(contract_inputs.len());
+    Ok(tx)
+}
+
+/// The tx `sign_without_gas` would make, but with placeholder signatures of the maximum size.
+fn dummy_signed_without_gas(
+    ecc: &impl ECC,
+    contract_inputs: &[ContractInput],
+    token_id: &TokenId,
+    token_outputs: &[TokenOutput],
+    lock_time: Option<u32>,
+) -> Result<UnhashedTx> {
+    let (mut unsigned_tx, placeholder_inputs) = build_without_gas(ecc, contract_inputs, token_id, token_outputs, lock_time)?;
+    for (idx, input) in contract_inputs.iter().enumerate() {
+        unsigned_tx.sign_input(InputReference::<SlpHtlcSignatory>::new(idx), input.signatory.placeholder_signatures())?;
+    }
+    for (placeholder_ref, _) in placeholder_inputs {
+        unsigned_tx.sign_input(placeholder_ref, ByteArray::new_unnamed(vec![0; MAX_SIGNATURE_SIZE]))?;
+    }
+    let mut tx = unsigned_tx.complete_tx();
+    tx.inputs.truncate(contract_inputs.len());
+    Ok(tx)
+}
+
+/// Builds the tx spending only the contract inputs.
+fn build_without_gas<'b>(
+    ecc: &impl ECC,
+    contract_inputs: &[ContractInput],
+    token_id: &TokenId,
+    token_outputs: &[TokenOutput],
+    lock_time: Option<u32>,
+) -> Result<(UnsignedTx<'b>, util::GasInputs)> {
+    // The token outputs' dust may exceed the contracts' dust. For the tx to build, a placeholder input with
+    // a throwaway key pays for it; it's removed after signing, which ANYONECANPAY allows.
+    let mut tx_builder = tx_builder(contract_inputs, token_id, token_outputs, lock_time, 0);
+    let shortfall = tx_builder.known_output_sum().saturating_sub(tx_builder.input_sum());
+    let mut placeholder_inputs = Vec::new();
+    if shortfall > 0 {
+        let placeholder_sk = [1; 32];
+        let placeholder_pk = ecc.derive_pubkey(&placeholder_sk)?;
+        let placeholder_ref = tx_builder.add_input(
+            UnsignedTxInput {
+                prev_out: TxOutpoint { tx_hash: Sha256d::new([0; 32]), vout: 0 },
+                sequence: 0xffff_ffff,
+                value: shortfall,
+            },
+            Address::from_pk("bitcoincash", &placeholder_pk).p2pkh_script()?,
+            P2PKHSignatory {
+                pubkey: placeholder_pk,
+                sig_hash_flags: SigHashFlags::DEFAULT,
+            },
+        );
+        placeholder_inputs.push((placeholder_ref, placeholder_sk));
+    }
+    Ok((tx_builder.build()?, placeholder_inputs))
+}
+
+/// Verifies `tx`, which still lacks gas inputs, without broadcasting it.
+fn partial_report(client: &ECSClient, ecc: &impl ECC, tx: &UnhashedTx) -> Result<TxReport> {
+    let prev_outputs = util::prev_outputs(client, tx)?;
+    // Fee and change are up to whoever adds the gas.
+    let summary = TxSummary { size: tx.ser().len(), fee: 0, change: 0 };
+    let mut report = util::verify_and_broadcast(client, ecc, tx, &prev_outputs, &summary, true)?;
+    report.complete = false;
+    Ok(report)
+}
+
+/// Appends a token output paying the postage of `post_office`, taken from the first token output.
+/// Returns the postage in base units.
+fn add_postage(
+    client: &ECSClient,
+    ecc: &impl ECC,
+    post_office: &PostOffice,
+    token_id: &TokenId,
+    contract_inputs: &[ContractInput],
+    token_outputs: &mut Vec<TokenOutput>,
+    lock_time: Option<u32>,
+) -> Result<u64> {
+    let postage = post_office.postage()?;
+    let rate = postage.rate(&hex::encode(token_id.to_vec()))?;
+    let postage_address = util::parse_address(&postage.address, &[client.address_prefix()], "Post office")?;
+    if token_outputs.len() >= MAX_SLP_OUTPUTS {
+        bail_kind!(ErrorKind::InvalidInput, "SLP SEND has no room for a postage output, use at most {} token outputs", MAX_SLP_OUTPUTS - 1);
+    }
+    token_outputs.push(TokenOutput {
+        script: postage_address.into(),
+        amount: 0,
+    });
+    // Token amounts have a fixed size, so the postage doesn't change the size of the tx.
+    let unpaid_tx = dummy_signed_without_gas(ecc, contract_inputs, token_id, token_outputs, lock_time)?;
+    let input_sum = util::prev_outputs(client, &unpaid_tx)?.iter().map(|output| output.value).sum::<u64>();
+    let output_sum = unpaid_tx.outputs.iter().map(|output| output.value).sum::<u64>();
+    let num_stamps = postage.num_stamps(unpaid_tx.ser().len(), output_sum.saturating_sub(input_sum));
+    let postage_amount = num_stamps.saturating_mul(rate);
+    if postage_amount >= token_outputs[0].amount {
+        bail_kind!(
+            ErrorKind::InsufficientFunds,
+            "Postage of {} base units ({} stamps) exceeds the first token output of {} base units",
+            postage_amount, num_stamps, token_outputs[0].amount,
+        );
+    }
+    token_outputs[0].amount -= postage_amount;
+    token_outputs.last_mut().expect("infallible").amount = postage_amount;
+    Ok(postage_amount)
+}
+
+/// Submits `tx` to `post_office` and verifies the tx it broadcast, which must only add inputs.
+fn post(client: &ECSClient, ecc: &impl ECC, post_office: &PostOffice, tx: &UnhashedTx) -> Result<TxReport> {
+    let (posted_txs, memo) = post_office.submit(&tx.ser())?;
+    if let Some(memo) = memo {
+        eprintln!("Post office: {}", memo);
+    }
+    let posted_tx = match posted_txs.as_slice() {
+        [posted_tx] => util::deser_tx(posted_tx).context(ErrorKind::RpcTransport)?,
+        _ => bail_kind!(ErrorKind::RpcTransport, "Post office returned {} txs, expected 1", posted_txs.len()),
+    };
+    let same_outputs = posted_tx.outputs.len() == tx.outputs.len()
+        && posted_tx.outputs.iter().zip(&tx.outputs)
+            .all(|(posted, output)| posted.value == output.value && posted.script.ser_ops() == output.script.ser_ops());
+    let has_inputs = tx.inputs.iter().all(|input| {
+        posted_tx.inputs.iter()
+            .any(|posted| posted.prev_out == input.prev_out && posted.script.ser_ops() == input.script.ser_ops())
+    });
+    if !same_outputs || !has_inputs {
+        bail_kind!(ErrorKind::ContractMismatch, "Post office returned a different tx {}", Sha256d::digest(posted_tx.ser()).to_hex_le());
+    }
+    let prev_outputs = util::prev_outputs(client, &posted_tx)?;
+    let summary = TxSummary::new(&posted_tx, &prev_outputs, |_, _| false)?;
+    // The post office already broadcast it.
+    let mut report = util::verify_and_broadcast(client, ecc, &posted_tx, &prev_outputs, &summary, true)?;
+    report.broadcast = true;
+    Ok(report)
+}
+
+/// Token outputs for a batch spend to `script`: either one output per contract input,
+/// or a single output holding the sum of all contract amounts if `consolidate` is set.
+pub fn batch_token_outputs(contract_inputs: &[ContractInput], script: &Script, consolidate: bool) -> Vec<TokenOutput> {
+    if consolidate {
+        vec![TokenOutput {
+            script: script.clone(),
+            amount: contract_inputs.iter().map(|input| input.token_amount).sum(),
+        }]
+    } else {
+        contract_inputs.iter()
+            .map(|input| TokenOutput {
+                script: script.clone(),
+                amount: input.token_amount,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_cash_ecc::init_ecc;
+
+    fn outpoint(vout: u32) -> TxOutpoint {
+        TxOutpoint { tx_hash: Sha256d::new([0xaa; 32]), vout }
+    }
+
+    fn token_id(byte: u8) -> TokenId {
+        TokenId::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn address(sk_byte: u8) -> Address<'static> {
+        let pk = init_ecc().derive_pubkey(&[sk_byte; 32]).unwrap();
+        Address::from_pk("simpleledger", &pk).to_owned_address()
+    }
+
+    fn descriptor(vout: u32, buyer: u8, seller: u8) -> HtlcDescriptor {
+        HtlcDescriptor {
+            contract_utxo: outpoint(vout),
+            buyer_address: address(buyer),
+            seller_address: address(seller),
+            secret_hash: Hash160::digest(vec![vout as u8]),
+            timeout: 1_000,
+        }
+    }
+
+    fn contract_input(vout: u32, token_amount: u64) -> ContractInput {
+        let descriptor = descriptor(vout, 1, 2);
+        ContractInput {
+            contract_utxo: descriptor.contract_utxo.clone(),
+            params: descriptor.params().unwrap(),
+            signatory: SlpHtlcSignatory::Timeout {
+                buyer_pk: init_ecc().derive_pubkey(&[1; 32]).unwrap(),
+                sig_hash_flags: SigHashFlags::DEFAULT,
+            },
+            secret_key: vec![1; 32],
+            token_amount,
+        }
+    }
+
+    #[test]
+    fn test_common_token() {
+        let utxos = [outpoint(1), outpoint(2), outpoint(3)];
+        let utxos = utxos.iter().collect::<Vec<_>>();
+        let (common, amounts) = common_token(&utxos, vec![(token_id(1), 30), (token_id(1), 10), (token_id(1), 20)]).unwrap();
+        assert_eq!(common.as_slice_be(), token_id(1).as_slice_be());
+        assert_eq!(amounts, vec![30, 10, 20]);
+
+        let err = common_token(&utxos, vec![(token_id(1), 30), (token_id(2), 10), (token_id(1), 20)]).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ContractMismatch);
+        assert!(format!("{:#}", err).contains(&format!("{}:2 holds token {}", utxos[1].tx_hash, "02".repeat(32))), "{:#}", err);
+
+        let err = common_token(&[], vec![]).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_contract_keys() {
+        let descriptors = vec![descriptor(1, 1, 2), descriptor(2, 1, 3), descriptor(3, 4, 2)];
+        // Keys are looked up once per address and returned in contract order.
+        let mut lookups = Vec::new();
+        let keys = contract_keys(&descriptors, |descriptor| &descriptor.seller_address, |descriptor| {
+            lookups.push(descriptor.contract_utxo.vout);
+            Ok(descriptor.contract_utxo.vout)
+        }).unwrap();
+        assert_eq!(lookups, vec![1, 2]);
+        assert_eq!(keys, vec![1, 2, 1]);
+
+        let mut lookups = Vec::new();
+        let keys = contract_keys(&descriptors, |descriptor| &descriptor.buyer_address, |descriptor| {
+            lookups.push(descriptor.contract_utxo.vout);
+            Ok(descriptor.contract_utxo.vout)
+        }).unwrap();
+        assert_eq!(lookups, vec![1, 3]);
+        assert_eq!(keys, vec![1, 1, 3]);
+
+        let err = contract_keys(&descriptors, |descriptor| &descriptor.seller_address, |descriptor| {
+            match descriptor.contract_utxo.vout {
+                2 => bail_kind!(ErrorKind::ContractMismatch, "no key"),
+                vout => Ok(vout),
+            }
+        }).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ContractMismatch);
+    }
+
+    #[test]
+    fn test_batch_token_outputs() {
+        let contract_inputs = vec![contract_input(1, 30), contract_input(2, 10), contract_input(3, 20)];
+        let script: Script = address(5).into();
+        let outputs = batch_token_outputs(&contract_inputs, &script, false);
+        assert_eq!(outputs.iter().map(|output| output.amount).collect::<Vec<_>>(), vec![30, 10, 20]);
+        assert!(outputs.iter().all(|output| output.script.ser_ops() == script.ser_ops()));
+
+        let outputs = batch_token_outputs(&contract_inputs, &script, true);
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].amount, 60);
+        assert_eq!(outputs[0].script.ser_ops(), script.ser_ops());
+    }
+
+    #[test]
+    fn test_dummy_signed_size() {
+        let ecc = init_ecc();
+        let contract_inputs = vec![contract_input(1, 30), contract_input(2, 10)];
+        let script: Script = address(5).into();
+        // More outputs than contracts, so a placeholder input pays their dust.
+        let token_outputs = batch_token_outputs(&[contract_input(1, 10), contract_input(2, 10), contract_input(3, 20)], &script, false);
+        let signed = sign_without_gas(&ecc, &contract_inputs, &token_id(1), &token_outputs, Some(1_000)).unwrap();
+        let dummy = dummy_signed_without_gas(&ecc, &contract_inputs, &token_id(1), &token_outputs, Some(1_000)).unwrap();
+        assert_eq!(dummy.inputs.len(), 2);
+        assert_eq!(dummy.outputs, signed.outputs);
+        // Placeholder signatures have the maximum size, real ones are at most a few bytes shorter.
+        let (signed_size, dummy_size) = (signed.ser().len(), dummy.ser().len());
+        assert!(signed_size <= dummy_size && dummy_size <= signed_size + 2 * 3, "{} {}", signed_size, dummy_size);
+    }
+}
@@ -0,0 +1,231 @@
+use clap::Clap;
+use bitcoin_cash::*;
+use bitcoin_cash_ecc::init_ecc;
+use anyhow::{Context, Result};
+
+use crate::coin_selection::*;
+use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::fee::*;
+use crate::interpreter;
+use crate::keystore::KeyOpts;
+use crate::output::*;
+use crate::slp::{SlpMessage, TokenInfo, TokenType};
+use crate::slp_validator::{SlpValidationOpts, SlpValidity};
+use crate::util;
+
+#[derive(Clap)]
+pub struct Sponsor {
+    /// Partially signed tx (hex), as printed by a redeem or timeout command with --sponsored.
+    #[clap(long)]
+    tx: String,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    /// Strategy for picking the wallet UTXOs that pay the fee.
+    #[clap(long, arg_enum, default_value = "branch-and-bound")]
+    coin_selection: CoinSelection,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
+    #[clap(long)]
+    uri: String,
+}
+
+/// Token id, type and amount of a valid SLP output spent by an input, `None` if it holds no tokens.
+type InputTokens = Option<(Sha256d, TokenType, u64)>;
+
+impl Sponsor {
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let keys = self.keys.resolve(prefix)?;
+        let ecc = init_ecc();
+        let raw_tx = hex::decode(&self.tx)
+            .with_context(|| "Invalid tx hex")
+            .context(ErrorKind::InvalidInput)?;
+        let mut tx = util::deser_tx(&raw_tx)
+            .with_context(|| "Invalid tx")
+            .context(ErrorKind::InvalidInput)?;
+        let mut prev_outputs = util::prev_outputs(&client, &tx)?;
+        // Don't pay for a tx that can't be valid, whatever gas is added.
+        interpreter::verify_tx(&ecc, &tx, &prev_outputs)?;
+        let input_tokens = input_tokens(&client, &mut self.slp_validation.validity(&client)?, &tx)?;
+        let token_id = check_send(&tx, &input_tokens)?;
+        let token_id_hex = token_id.to_hex_le();
+        let token_info = TokenInfo::fetch(&client, &token_id_hex)?;
+
+        let input_sum = prev_outputs.iter().map(|output| output.value).sum::<u64>();
+        let output_sum = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+        let target = SelectionTarget {
+            shortfall: output_sum as i64 - input_sum as i64,
+            base_size: tx.ser().len(),
+            // The spender's signatures cover all outputs, so there's no room for change.
+            change_size: 0,
+            fee_rate: fee_policy.fee_rate,
+        };
+        let utxos = util::spendable_utxos(&client, &keys)?;
+        let values = utxos.iter().map(|utxo| utxo.value).collect::<Vec<_>>();
+        let selected = select_coins(&values, &target, self.coin_selection)
+            .ok_or_else(|| anyhow::anyhow!("Insufficient funds (not enough 'gas' in BCH)"))
+            .context(ErrorKind::InsufficientFunds)?;
+        let mut gas_keys = Vec::with_capacity(selected.len());
+        for idx in selected {
+            let utxo = &utxos[idx];
+            let utxo_sk = keys.secret_key(&client, &utxo.address)?;
+            let utxo_pk = ecc.derive_pubkey(&utxo_sk)?;
+            gas_keys.push((tx.inputs.len(), utxo_sk, utxo_pk));
+            tx.inputs.push(TxInput::new(utxo.outpoint.clone(), Script::default(), 0xffff_ffff));
+            prev_outputs.push(TxOutput {
+                value: utxo.value,
+                script: utxo.address.p2pkh_script()?.into(),
+            });
+        }
+        sign_p2pkh_inputs(&ecc, &mut tx, &prev_outputs, &gas_keys)?;
+
+        let summary = TxSummary::new(&tx, &prev_outputs, |_, _| false)?;
+        fee_policy.check(&summary)
+            .context("Gas inputs can't get change, try another --coin-selection or smaller UTXOs")?;
+        let tx = util::verify_and_broadcast(&client, &ecc, &tx, &prev_outputs, &summary, self.dry_run)?;
+        Ok(Report::Sponsor(SponsorReport {
+            tx,
+            token: TokenReport::new(token_id_hex, &token_info),
+        }))
+    }
+}
+
+/// The tokens spent by each input of `tx`, only counting outputs of valid SLP transactions.
+fn input_tokens(client: &ECSClient, slp_validity: &mut SlpValidity, tx: &UnhashedTx) -> Result<Vec<InputTokens>> {
+    let mut input_tokens = Vec::with_capacity(tx.inputs.len());
+    for input in &tx.inputs {
+        let txid = &input.prev_out.tx_hash;
+        let prev_tx = util::get_tx(client, &txid.to_hex_le())?;
+        let message = prev_tx.outputs.first()
+            .and_then(|output| SlpMessage::parse(&output.script))
+            .and_then(|message| message.ok());
+        let tokens = match message {
+            Some(message) => match message.token_amount(input.prev_out.vout) {
+                Some(amount) if slp_validity.is_valid(txid)? => Some((message.token_id(txid), message.token_type(), amount)),
+                _ => None,
+            },
+            None => None,
+        };
+        input_tokens.push(tokens);
+    }
+    Ok(input_tokens)
+}
+
+/// Checks that `tx` is a valid SLP SEND which doesn't burn tokens, given the tokens spent by its inputs.
+/// Returns the token id.
+fn check_send(tx: &UnhashedTx, input_tokens: &[InputTokens]) -> Result<Sha256d> {
+    let message = tx.outputs.first()
+        .and_then(|output| SlpMessage::parse(&output.script))
+        .and_then(|message| message.ok());
+    let (token_type, token_id, amounts) = match message {
+        Some(SlpMessage::Send { token_type, token_id, amounts }) => (token_type, token_id, amounts),
+        _ => bail_kind!(ErrorKind::InvalidSlp, "Tx isn't an SLP SEND"),
+    };
+    if amounts.len() >= tx.outputs.len() {
+        bail_kind!(ErrorKind::InvalidSlp, "SLP SEND has {} amounts, but only {} outputs", amounts.len(), tx.outputs.len());
+    }
+    let input_amount = input_tokens.iter()
+        .filter_map(|tokens| match tokens {
+            Some((input_token_id, input_token_type, amount))
+                if *input_token_id == token_id && *input_token_type == token_type => Some(*amount as u128),
+            _ => None,
+        })
+        .sum::<u128>();
+    let output_amount = amounts.iter().map(|&amount| amount as u128).sum::<u128>();
+    if input_amount < output_amount {
+        bail_kind!(
+            ErrorKind::InvalidSlp,
+            "SLP SEND sends {} base units of token {}, but its inputs only hold {}",
+            output_amount, token_id.to_hex_le(), input_amount,
+        );
+    }
+    if input_amount > output_amount {
+        bail_kind!(
+            ErrorKind::InvalidSlp,
+            "SLP SEND would burn {} base units of token {}", input_amount - output_amount, token_id.to_hex_le(),
+        );
+    }
+    Ok(token_id)
+}
+
+/// Signs the P2PKH inputs of `tx` at the given indices with ALL|FORKID.
+fn sign_p2pkh_inputs(
+    ecc: &impl ECC,
+    tx: &mut UnhashedTx,
+    prev_outputs: &[TxOutput],
+    keys: &[(usize, [u8; 32], Pubkey)],
+) -> Result<()> {
+    // Preimages need the value and script of every spent output.
+    let mut preimage_tx = tx.clone();
+    for (input, prev_output) in preimage_tx.inputs.iter_mut().zip(prev_outputs) {
+        input.value = Some(prev_output.value);
+        input.lock_script = Some(prev_output.script.clone());
+    }
+    let preimages = preimage_tx.preimages(&[SigHashFlags::DEFAULT]);
+    for (input_idx, secret_key, pubkey) in keys {
+        let sig = ecc.sign(secret_key, Sha256d::digest(preimages[*input_idx][0].ser()))?;
+        tx.inputs[*input_idx].script = Script::from_ops(vec![
+            Op::PushByteArray { array: sig.concat([SigHashFlags::DEFAULT.bits() as u8]), is_minimal: true },
+            Op::PushByteArray { array: pubkey.as_slice().to_vec().into(), is_minimal: true },
+        ]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_cash_slp::{slp_send_output, SlpTokenType, TokenId};
+
+    fn token_id() -> Sha256d {
+        Sha256d::new([0x11; 32])
+    }
+
+    /// A tx whose first output is a SEND of `amounts` of `token_id()`, followed by `num_outputs` outputs.
+    fn send_tx(amounts: &[u64], num_outputs: usize) -> UnhashedTx {
+        let slp_token_id = TokenId::from_slice(&hex::decode(token_id().to_hex_le()).unwrap()).unwrap();
+        let mut outputs = vec![slp_send_output(SlpTokenType::Fungible, &slp_token_id, amounts)];
+        outputs.extend((0..num_outputs).map(|_| TxOutput { value: DUST_AMOUNT, script: Script::default() }));
+        UnhashedTx { version: 1, inputs: vec![], outputs, lock_time: 0 }
+    }
+
+    fn tokens(amount: u64) -> InputTokens {
+        Some((token_id(), TokenType::Fungible, amount))
+    }
+
+    #[test]
+    fn test_check_send() {
+        let tx = send_tx(&[600, 400], 2);
+        assert_eq!(check_send(&tx, &[tokens(1000)]).unwrap(), token_id());
+        assert_eq!(check_send(&tx, &[tokens(300), None, tokens(700)]).unwrap(), token_id());
+    }
+
+    #[test]
+    fn test_check_send_invalid() {
+        let tx = send_tx(&[600, 400], 2);
+        let check = |tx: &UnhashedTx, input_tokens: &[InputTokens]| {
+            ErrorKind::of(&check_send(tx, input_tokens).unwrap_err())
+        };
+        // Sends more than it spends.
+        assert_eq!(check(&tx, &[tokens(999)]), ErrorKind::InvalidSlp);
+        // Other tokens and other token types don't count.
+        assert_eq!(check(&tx, &[Some((Sha256d::new([0x22; 32]), TokenType::Fungible, 1000))]), ErrorKind::InvalidSlp);
+        assert_eq!(check(&tx, &[Some((token_id(), TokenType::Nft1Group, 1000))]), ErrorKind::InvalidSlp);
+        // Burns tokens.
+        assert_eq!(check(&tx, &[tokens(1001)]), ErrorKind::InvalidSlp);
+        // Every amount needs an output.
+        assert_eq!(check(&send_tx(&[600, 400], 1), &[tokens(1000)]), ErrorKind::InvalidSlp);
+        let mut not_slp = send_tx(&[600, 400], 2);
+        not_slp.outputs[0] = TxOutput { value: 0, script: Script::default() };
+        assert_eq!(check(&not_slp, &[tokens(1000)]), ErrorKind::InvalidSlp);
+    }
+}
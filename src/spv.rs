@@ -0,0 +1,76 @@
+use bitcoin_cash::*;
+use anyhow::Result;
+
+use crate::chain::{self, BlockHeader};
+use crate::ecs_client::ECSClient;
+use crate::electrum::ElectrumClient;
+use crate::error::ErrorKind;
+
+/// A tx whose inclusion in the chain has been verified with SPV.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpvProof {
+    pub height: u32,
+    pub block_hash: Sha256d,
+    pub confirmations: u32,
+}
+
+/// Verifies that the tx `txid` paying to `script` is mined on the wallet daemon's chain, so the Electrum
+/// server can't fake its confirmations.
+///
+/// Looks up the tx's height in the history of `script`, then checks that the headers from that block
+/// up to the server's tip form a chain with valid proofs of work, and that the tx's merkle branch leads
+/// to the merkle root of its block. The headers are anchored to the daemon's chain: the daemon must
+/// have the same headers at the tx's height and at the lower of both tips, and only blocks up to
+/// there count as confirmations. Returns `None` for unconfirmed txs.
+pub fn verify_tx(electrum: &mut ElectrumClient, client: &ECSClient, txid: &Sha256d, script: &Script) -> Result<Option<SpvProof>> {
+    let height = electrum.script_history(script)?.into_iter()
+        .find(|(tx_hash, _)| tx_hash == txid)
+        .map(|(_, height)| height);
+    let height = match height {
+        Some(height) if height > 0 => height as u32,
+        Some(_) => return Ok(None),
+        None => bail_kind!(ErrorKind::SpvVerification, "Tx {} not found in the Electrum server's history", txid.to_hex_le()),
+    };
+
+    let (tip_height, tip_header) = electrum.tip()?;
+    let anchor_height = tip_height.min(client.blockchain_height()?);
+    if height > anchor_height {
+        bail_kind!(
+            ErrorKind::SpvVerification,
+            "Tx {} is reportedly mined at height {}, above the tip at {}", txid.to_hex_le(), height, anchor_height,
+        );
+    }
+    let headers = electrum.header_range(height, tip_height)?;
+    if headers.last() != Some(&tip_header) {
+        bail_kind!(ErrorKind::SpvVerification, "Block headers don't end in the tip {}", tip_header.hash.to_hex_le());
+    }
+    chain::verify_headers(&headers, electrum.network())?;
+    // As the headers are linked, matching both ends means all headers in between are on the daemon's chain.
+    let headers = &headers[..=(anchor_height - height) as usize];
+    for (anchor_height, header) in [(height, &headers[0]), (anchor_height, &headers[headers.len() - 1])] {
+        let daemon_header = client.getblockheader(anchor_height)?;
+        if *header != daemon_header {
+            bail_kind!(
+                ErrorKind::SpvVerification,
+                "Electrum server has block {} at height {}, but the wallet daemon has {}",
+                header.hash.to_hex_le(), anchor_height, daemon_header.hash.to_hex_le(),
+            );
+        }
+    }
+
+    let (branch, pos) = electrum.merkle_proof(txid, height)?;
+    let block: &BlockHeader = &headers[0];
+    let merkle_root = chain::merkle_root_from_branch(txid, &branch, pos);
+    if merkle_root != block.merkle_root {
+        bail_kind!(
+            ErrorKind::SpvVerification,
+            "Merkle proof of tx {} leads to {}, but block {} has merkle root {}",
+            txid.to_hex_le(), merkle_root.to_hex_le(), block.hash.to_hex_le(), block.merkle_root.to_hex_le(),
+        );
+    }
+    Ok(Some(SpvProof {
+        height,
+        block_hash: block.hash.clone(),
+        confirmations: headers.len() as u32,
+    }))
+}
@@ -1,13 +1,31 @@
 use clap::Clap;
 use bitcoin_cash::*;
 use bitcoin_cash_ecc::init_ecc;
-use bitcoin_cash_slp::{slp_send_output, SlpTokenType};
 use anyhow::{Context, Result};
+use std::time::Duration;
 
+use crate::chain::{self, Finality, LOCKTIME_THRESHOLD};
 use crate::contract::*;
 use crate::ecs_client::*;
+use crate::error::ErrorKind;
+use crate::fee::*;
+use crate::keystore::KeyOpts;
+use crate::output::*;
+use crate::slp::TokenInfo;
+use crate::slp_validator::SlpValidationOpts;
+use crate::spend_htlc::*;
 use crate::util;
 
+#[derive(Clap)]
+pub struct FinalityOpts {
+    /// If the refund isn't final yet, wait until it is and broadcast it then, instead of failing.
+    #[clap(long)]
+    wait: bool,
+    /// Seconds between checks of the chain tip while waiting with --wait.
+    #[clap(long, default_value = "60")]
+    poll_interval: u64,
+}
+
 #[derive(Clap)]
 pub struct TimeoutHtlc {
     #[clap(long)]
@@ -22,67 +40,115 @@ pub struct TimeoutHtlc {
     buyer_secret_key: Option<String>,
     #[clap(long)]
     buyer_address: Option<String>,
+    #[clap(flatten)]
+    destination: DestinationOpts,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    #[clap(flatten)]
+    gas: GasOpts,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    #[clap(flatten)]
+    finality: FinalityOpts,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
     #[clap(long)]
     uri: String,
 }
 
-impl TimeoutHtlc {
-    pub fn run(&self, prefix: &str) -> Result<()> {
-        let client = ECSClient::new(self.uri.clone(), prefix);
-        let utxo_msg = "Invalid contract UTXO, must be of form <txid>:<vout>";
-        let utxo_err = anyhow::anyhow!(utxo_msg);
-        let mut contract_utxo_split = self.contract_utxo.splitn(2, ":");
-        let contract_tx_hash_hex = contract_utxo_split.next().expect("infallible");
-        let contract_vout = contract_utxo_split.next().ok_or_else(|| utxo_err)?;
-        let contract_tx_hash = Sha256d::from_hex_le(contract_tx_hash_hex).with_context(|| utxo_msg)?;
-        let contract_vout: u32 = contract_vout.parse().with_context(|| utxo_msg)?;
-        let ecc = init_ecc();
-        let (buyer_address, buyer_pk, buyer_sk) = match (self.buyer_secret_key.as_ref(), self.buyer_address.as_ref()) {
-            (Some(_), Some(_)) | (None, None) => {
-                anyhow::bail!("Either buyer secret key or buyer address must be set, but not both.");
-            }
-            (Some(buyer_secret_key), None) => {
-                let buyer_sk = hex::decode(buyer_secret_key)
-                    .with_context(|| "Invalid buyer secret key")?;
-                let buyer_pk = ecc.derive_pubkey(&buyer_sk)?;
-                (Address::from_pk(prefix, &buyer_pk), buyer_pk, buyer_sk)
-            }
-            (None, Some(buyer_address)) => {
-                let buyer_address = Address::from_cash_addr(buyer_address)
-                    .with_context(|| "Invalid buyer address")?;
-                if buyer_address.prefix_str() != prefix {
-                    anyhow::bail!("Buyer address must start with {}.", prefix)
+#[derive(Clap)]
+pub struct TimeoutHtlcBatch {
+    /// Contract descriptor as printed by send-htlc, can be given multiple times.
+    #[clap(long = "contract", required = true)]
+    contracts: Vec<String>,
+    #[clap(long)]
+    buyer_secret_key: Option<String>,
+    /// Send all refunded tokens to a single output instead of one output per contract.
+    #[clap(long)]
+    consolidate: bool,
+    #[clap(flatten)]
+    destination: DestinationOpts,
+    #[clap(flatten)]
+    fee: FeeOpts,
+    #[clap(flatten)]
+    gas: GasOpts,
+    #[clap(flatten)]
+    keys: KeyOpts,
+    #[clap(flatten)]
+    finality: FinalityOpts,
+    #[clap(flatten)]
+    slp_validation: SlpValidationOpts,
+    /// Build, sign and verify the transaction, but don't broadcast it.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(flatten)]
+    retry: RetryOpts,
+    #[clap(long)]
+    uri: String,
+}
+
+impl FinalityOpts {
+    /// Makes sure a refund with `lock_time` can be mined in the next block, waiting for it if --wait is set.
+    ///
+    /// Lock times are checked against the height and median time past of the wallet daemon's tip.
+    /// For dry runs, a refund that isn't final yet only causes a warning.
+    pub fn wait_until_final(&self, client: &ECSClient, lock_time: u32, dry_run: bool) -> Result<()> {
+        loop {
+            let tip = client.chain_tip()?;
+            let finality = chain::lock_time_finality(lock_time, &tip);
+            let status = format!(
+                "Refund with lock time {} is {} (tip at height {}, median time past {})",
+                lock_time, finality, tip.height, tip.median_time_past,
+            );
+            match finality {
+                Finality::Final => return Ok(()),
+                _ if dry_run => {
+                    eprintln!("Warning: {}.", status);
+                    return Ok(());
                 }
-                if buyer_address.addr_type() != AddressType::P2PKH {
-                    anyhow::bail!("Buyer address must be P2PKH")
+                _ if self.wait => {
+                    eprintln!("{}, checking again in {} seconds.", status, self.poll_interval);
+                    std::thread::sleep(Duration::from_secs(self.poll_interval));
                 }
-                let buyer_sk = client.getprivatekeys(buyer_address.cash_addr())
-                    .with_context(|| format!("Address {} not part of wallet", buyer_address.cash_addr()))?;
-                let buyer_pk = ecc.derive_pubkey(&buyer_sk)?;
-                (buyer_address, buyer_pk, buyer_sk.to_vec())
+                _ => bail_kind!(ErrorKind::NotFinal, "{}. Use --wait to broadcast it once it is final.", status),
             }
-        };
-        let seller_address = Address::from_cash_addr(&self.seller_address).with_context(
-            || "Invalid seller address: {}"
-        )?;
-        if seller_address.prefix_str() != prefix {
-            anyhow::bail!("Seller address must start with {}.", prefix)
-        }
-        if seller_address.addr_type() != AddressType::P2PKH {
-            anyhow::bail!("Seller address must be P2PKH")
         }
+    }
+}
+
+impl TimeoutHtlc {
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let contract_utxo = util::parse_outpoint(&self.contract_utxo)?;
+        let keys = self.keys.resolve(prefix)?;
+        let ecc = init_ecc();
+        let (buyer_address, buyer_pk, buyer_sk) = util::resolve_key(
+            &client,
+            &keys,
+            &ecc,
+            prefix,
+            "Buyer",
+            self.buyer_secret_key.as_ref(),
+            self.buyer_address.as_ref(),
+        )?;
+        let seller_address = util::parse_p2pkh_address(&self.seller_address, prefix, "Seller")?;
         let timeout = Integer::new(self.timeout)
-            .with_context(|| format!("Invalid timeout: {}", self.timeout))?;
+            .with_context(|| format!("Invalid timeout: {}", self.timeout))
+            .context(ErrorKind::InvalidInput)?;
         let secret_hash = Hash160::from_hex_be(&self.secret_hash)
-            .with_context(|| format!("Invalid secret hash: {}", self.secret_hash))?;
-
-        if !client.slpvalidate(contract_tx_hash_hex)? {
-            anyhow::bail!("Contract tx is not a valid SLP transaction.");
-        }
+            .with_context(|| format!("Invalid secret hash: {}", self.secret_hash))
+            .context(ErrorKind::InvalidInput)?;
 
-        let (token_id, contract_amount) = util::get_utxo_token_amount(&client, contract_tx_hash_hex, contract_vout)?;
-        println!("contract_amount: {}", contract_amount);
-        println!("token_id: {:?}", token_id);
+        let (token_id, contract_amounts) = contract_token_amounts(&client, &mut self.slp_validation.validity(&client)?, &[&contract_utxo])?;
+        let contract_amount = contract_amounts[0];
+        let token_id_hex = hex::encode(token_id.to_vec());
+        let token_info = TokenInfo::fetch(&client, &token_id_hex)?;
 
         let params = SlpHtlcParams {
             buyer_pkh: buyer_address.hash().clone(),
@@ -90,54 +156,151 @@ impl TimeoutHtlc {
             secret_hash,
             timeout,
         };
-        let recipient_address = client.createaddress()?;
-        let recipient_script: Script = recipient_address.into();
-        let mut tx_builder = TxBuilder::new(2, self.timeout);
-        let contract_ref = tx_builder.add_input(
-            UnsignedTxInput {
-                prev_out: TxOutpoint { tx_hash: contract_tx_hash, vout: contract_vout },
-                sequence: 0xffff_fffe,
-                value: DUST_AMOUNT,
+        let (recipient_script, change_script) = self.destination.resolve(&client, &keys, prefix)?;
+        let contract = ContractReport::new(
+            &HtlcDescriptor {
+                contract_utxo: contract_utxo.clone(),
+                buyer_address,
+                seller_address,
+                secret_hash: params.secret_hash.clone(),
+                timeout: self.timeout,
             },
-            params.script(),
-            SlpHtlcSignatory::Timeout {
+            contract_amount,
+            &token_info,
+        );
+        let contract_input = ContractInput {
+            contract_utxo,
+            params,
+            signatory: SlpHtlcSignatory::Timeout {
                 buyer_pk,
+                sig_hash_flags: SigHashFlags::DEFAULT,
             },
-        );
-
-        tx_builder.add_output(
-            slp_send_output(SlpTokenType::Fungible, &token_id, &[contract_amount])
-        );
+            secret_key: buyer_sk,
+            token_amount: contract_amount,
+        };
+        let token_output = TokenOutput {
+            script: recipient_script,
+            amount: contract_amount,
+        };
 
-        tx_builder.add_output(TxOutput {
-            script: recipient_script.clone(),
-            value: DUST_AMOUNT,
-        });
+        self.finality.wait_until_final(&client, self.timeout, self.dry_run || self.gas.is_sponsored())?;
+        let (tx, postage) = spend_contracts(
+            &client,
+            &keys,
+            &ecc,
+            ContractSpend {
+                token_id,
+                contract_inputs: vec![contract_input],
+                token_outputs: vec![token_output],
+                change_script,
+                lock_time: Some(self.timeout),
+                gas: self.gas.resolve(self.retry.policy()),
+            },
+            &fee_policy,
+            self.dry_run,
+        )?;
 
-        tx_builder.add_leftover_output_bounded(0, u64::MAX, 0, recipient_script);
+        Ok(Report::TimeoutHtlc(SpendReport {
+            tx,
+            token: TokenReport::new(token_id_hex, &token_info),
+            contracts: vec![contract],
+            remainder: None,
+            postage: postage.map(|postage| AmountReport::new(postage, &token_info)),
+        }))
+    }
+}
 
-        let (mut unsigned_tx, gas_inputs) = util::add_gas_inputs(&client, &ecc, tx_builder)?;
+impl TimeoutHtlcBatch {
+    pub fn run(&self, prefix: &str) -> Result<Report> {
+        let client = ECSClient::new(self.uri.clone(), prefix, self.retry.policy());
+        let fee_policy = self.fee.resolve(&client)?;
+        let keys = self.keys.resolve(prefix)?;
+        let ecc = init_ecc();
+        let descriptors = self.contracts.iter()
+            .map(|contract| HtlcDescriptor::parse(contract, prefix))
+            .collect::<Result<Vec<_>>>()?;
 
-        let contract_sig = ecc.sign(&buyer_sk, Sha256d::digest(unsigned_tx.input_preimages(contract_ref).ser()))?;
-        unsigned_tx.sign_input(contract_ref, contract_sig)?;
-        for (gas_ref, utxo_sk) in gas_inputs {
-            let gas_sig = ecc.sign(&utxo_sk, Sha256d::digest(unsigned_tx.input_preimages(gas_ref).ser()))?;
-            unsigned_tx.sign_input(gas_ref, gas_sig)?;
+        // All contracts share one tx lock time, which must satisfy every contract's CLTV.
+        let height_locked = descriptors.iter().filter(|descriptor| descriptor.timeout < LOCKTIME_THRESHOLD).count();
+        if height_locked != 0 && height_locked != descriptors.len() {
+            bail_kind!(ErrorKind::InvalidInput, "Can't mix block height and timestamp timeouts in one transaction");
         }
+        let lock_time = descriptors.iter().map(|descriptor| descriptor.timeout).max().expect("infallible");
 
-        let htlc_tx = unsigned_tx.complete_tx();
-        let htlc_raw_tx = htlc_tx.ser();
-        let htlc_tx_hex = hex::encode(&htlc_raw_tx);
+        let buyer_key = match &self.buyer_secret_key {
+            Some(buyer_secret_key) => Some(util::resolve_key(
+                &client, &keys, &ecc, prefix, "Buyer", Some(buyer_secret_key), None,
+            )?),
+            None => None,
+        };
+        let contract_keys = contract_keys(&descriptors, |descriptor| &descriptor.buyer_address, |descriptor| {
+            match &buyer_key {
+                Some((buyer_address, buyer_pk, buyer_sk)) => {
+                    if buyer_address.hash() != descriptor.buyer_address.hash() {
+                        bail_kind!(
+                            ErrorKind::ContractMismatch,
+                            "Buyer secret key doesn't match buyer address {} of contract {}",
+                            descriptor.buyer_address.cash_addr(), descriptor,
+                        );
+                    }
+                    Ok((*buyer_pk, buyer_sk.clone()))
+                }
+                None => {
+                    let (_, buyer_pk, buyer_sk) = util::resolve_key(
+                        &client, &keys, &ecc, prefix, "Buyer", None, Some(&descriptor.buyer_address.cash_addr().to_string()),
+                    )?;
+                    Ok((buyer_pk, buyer_sk))
+                }
+            }
+        })?;
 
-        let tx_hash = client.broadcast(&htlc_tx_hex)
-            .with_context(|| format!("invalid tx: {}", htlc_tx_hex))?;
+        let contract_utxos = descriptors.iter().map(|descriptor| &descriptor.contract_utxo).collect::<Vec<_>>();
+        let (token_id, contract_amounts) = contract_token_amounts(&client, &mut self.slp_validation.validity(&client)?, &contract_utxos)?;
+        let token_id_hex = hex::encode(token_id.to_vec());
+        let token_info = TokenInfo::fetch(&client, &token_id_hex)?;
+        let contracts = descriptors.iter().zip(&contract_amounts)
+            .map(|(descriptor, &contract_amount)| ContractReport::new(descriptor, contract_amount, &token_info))
+            .collect();
 
-        if tx_hash.starts_with("error") {
-            anyhow::bail!("invalid tx: {}", htlc_tx_hex)
+        let (recipient_script, change_script) = self.destination.resolve(&client, &keys, prefix)?;
+        let mut contract_inputs = Vec::with_capacity(descriptors.len());
+        for ((descriptor, contract_amount), (buyer_pk, buyer_sk)) in descriptors.iter().zip(contract_amounts).zip(contract_keys) {
+            contract_inputs.push(ContractInput {
+                contract_utxo: descriptor.contract_utxo.clone(),
+                params: descriptor.params()?,
+                signatory: SlpHtlcSignatory::Timeout {
+                    buyer_pk,
+                    sig_hash_flags: SigHashFlags::DEFAULT,
+                },
+                secret_key: buyer_sk,
+                token_amount: contract_amount,
+            });
         }
+        let token_outputs = batch_token_outputs(&contract_inputs, &recipient_script, self.consolidate);
 
-        println!("{}", tx_hash);
+        self.finality.wait_until_final(&client, lock_time, self.dry_run || self.gas.is_sponsored())?;
+        let (tx, postage) = spend_contracts(
+            &client,
+            &keys,
+            &ecc,
+            ContractSpend {
+                token_id,
+                contract_inputs,
+                token_outputs,
+                change_script,
+                lock_time: Some(lock_time),
+                gas: self.gas.resolve(self.retry.policy()),
+            },
+            &fee_policy,
+            self.dry_run,
+        )?;
 
-        Ok(())
-    } 
+        Ok(Report::TimeoutHtlcBatch(SpendReport {
+            tx,
+            token: TokenReport::new(token_id_hex, &token_info),
+            contracts,
+            remainder: None,
+            postage: postage.map(|postage| AmountReport::new(postage, &token_info)),
+        }))
+    }
 }
@@ -1,66 +1,393 @@
 use bitcoin_cash::*;
 use bitcoin_cash_slp::TokenId;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
 
-use crate::ecs_client::ECSClient;
+use crate::coin_selection::*;
+use crate::ecs_client::{ECSClient, Utxo};
+use crate::error::ErrorKind;
+use crate::fee::TxSummary;
+use crate::output::TxReport;
+use crate::slp::{SlpMessage, TokenType};
+use crate::interpreter::{self, InputCheck};
+use crate::keystore::Keys;
 
-pub fn get_utxo_token_amount(client: &ECSClient, txid: &str, vout: u32) -> Result<(TokenId, u64)> {
-    let tx_hex = client.gettransaction(txid)?;
-    let raw_tx = hex::decode(&tx_hex)?;
-    let (tx, _): (UnhashedTx, _) = UnhashedTx::deser(raw_tx.into())?;
-    let slp_ops = tx.outputs[0].script.ops();
-    let utxo_amount = if let Op::PushByteArray {array, ..} = &slp_ops[vout as usize + 4].op {
-        let mut amount = [0; 8];
-        amount.copy_from_slice(&array);
-        u64::from_be_bytes(amount)
-    } else {
-        unreachable!()
+pub fn parse_outpoint(utxo: &str) -> Result<TxOutpoint> {
+    let utxo_msg = "Invalid contract UTXO, must be of form <txid>:<vout>";
+    let mut utxo_split = utxo.splitn(2, ':');
+    let tx_hash_hex = utxo_split.next().expect("infallible");
+    let vout = utxo_split.next()
+        .ok_or_else(|| anyhow::anyhow!(utxo_msg))
+        .context(ErrorKind::InvalidInput)?;
+    Ok(TxOutpoint {
+        tx_hash: Sha256d::from_hex_le(tx_hash_hex).with_context(|| utxo_msg).context(ErrorKind::InvalidInput)?,
+        vout: vout.parse().with_context(|| utxo_msg).context(ErrorKind::InvalidInput)?,
+    })
+}
+
+/// The BCH CashAddr prefix of the same network as the given SLP prefix.
+pub fn bch_prefix(slp_prefix: &str) -> &str {
+    match slp_prefix {
+        "simpleledger" => "bitcoincash",
+        "slptest" => "bchtest",
+        "slpreg" => "bchreg",
+        _ => slp_prefix,
+    }
+}
+
+/// Parses an address of any type (P2PKH or P2SH), which must have one of the given prefixes.
+pub fn parse_address(cash_addr: &str, prefixes: &[&str], role: &str) -> Result<Address<'static>> {
+    let address = Address::from_cash_addr(cash_addr)
+        .with_context(|| format!("Invalid {} address: {}", role.to_lowercase(), cash_addr))
+        .context(ErrorKind::InvalidInput)?
+        .to_owned_address();
+    if !prefixes.contains(&address.prefix_str()) {
+        bail_kind!(ErrorKind::InvalidInput, "{} address must start with {}.", role, prefixes.join(" or "))
+    }
+    Ok(address)
+}
+
+pub fn parse_p2pkh_address(cash_addr: &str, prefix: &str, role: &str) -> Result<Address<'static>> {
+    let address = Address::from_cash_addr(cash_addr)
+        .with_context(|| format!("Invalid {} address: {}", role.to_lowercase(), cash_addr))
+        .context(ErrorKind::InvalidInput)?
+        .to_owned_address();
+    if address.prefix_str() != prefix {
+        bail_kind!(ErrorKind::InvalidInput, "{} address must start with {}.", role, prefix)
+    }
+    if address.addr_type() != AddressType::P2PKH {
+        bail_kind!(ErrorKind::InvalidInput, "{} address must be P2PKH", role)
+    }
+    Ok(address)
+}
+
+/// Resolves the key of a contract party, either from a hex secret key or from an address of the wallet.
+pub fn resolve_key(
+    client: &ECSClient,
+    keys: &Keys,
+    ecc: &impl ECC,
+    prefix: &str,
+    role: &str,
+    secret_key: Option<&String>,
+    address: Option<&String>,
+) -> Result<(Address<'static>, Pubkey, Vec<u8>)> {
+    match (secret_key, address) {
+        (Some(_), Some(_)) | (None, None) => {
+            bail_kind!(ErrorKind::InvalidInput, "Either {0} secret key or {0} address must be set, but not both.", role.to_lowercase());
+        }
+        (Some(secret_key), None) => {
+            let sk = hex::decode(secret_key)
+                .with_context(|| format!("Invalid {} secret key", role.to_lowercase()))
+                .context(ErrorKind::InvalidInput)?;
+            let pk = ecc.derive_pubkey(&sk)
+                .with_context(|| format!("Invalid {} secret key", role.to_lowercase()))
+                .context(ErrorKind::InvalidInput)?;
+            Ok((Address::from_pk(prefix, &pk).to_owned_address(), pk, sk))
+        }
+        (None, Some(address)) => {
+            let address = parse_p2pkh_address(address, prefix, role)?;
+            let sk = match keys.secret_key(client, &address) {
+                Ok(sk) => sk,
+                // The wallet refuses to export keys of addresses it doesn't have; other failures keep their kind.
+                Err(err) if ErrorKind::of(&err) == ErrorKind::RpcError => {
+                    return Err(err)
+                        .with_context(|| format!("Address {} not part of wallet", address.cash_addr()))
+                        .context(ErrorKind::InvalidInput);
+                }
+                Err(err) => return Err(err),
+            };
+            let pk = ecc.derive_pubkey(&sk)?;
+            Ok((address, pk, sk.to_vec()))
+        }
+    }
+}
+
+/// Token id and amount held by the output at `outpoint`.
+pub fn get_utxo_token_amount(client: &ECSClient, outpoint: &TxOutpoint) -> Result<(TokenId, u64)> {
+    let tx = get_tx(client, &outpoint.tx_hash.to_hex_le())?;
+    utxo_token_amount(&tx, outpoint)
+}
+
+/// Token id and amount held by output `outpoint.vout` of `tx`, according to its SLP message.
+/// Only type 1 tokens are accepted, as spends send them on with a type 1 SEND.
+fn utxo_token_amount(tx: &UnhashedTx, outpoint: &TxOutpoint) -> Result<(TokenId, u64)> {
+    let slp_message = match tx.outputs.first().and_then(|output| SlpMessage::parse(&output.script)) {
+        Some(slp_message) => slp_message?,
+        None => bail_kind!(ErrorKind::InvalidSlp, "Tx {} has no SLP message", outpoint.tx_hash),
     };
-    let token_id = if let Op::PushByteArray {array, ..} = &slp_ops[4].op {
-        TokenId::from_slice(array)?
-    } else {
-        unreachable!()
+    if slp_message.token_type() != TokenType::Fungible {
+        bail_kind!(
+            ErrorKind::InvalidSlp,
+            "Output {}:{} holds {:?} tokens, only type 1 tokens can be spent",
+            outpoint.tx_hash, outpoint.vout, slp_message.token_type(),
+        );
+    }
+    let amount = match slp_message.token_amount(outpoint.vout) {
+        Some(amount) => amount,
+        None => bail_kind!(ErrorKind::InvalidSlp, "Output {}:{} holds no tokens", outpoint.tx_hash, outpoint.vout),
     };
-    Ok((token_id, utxo_amount))
+    let token_id = slp_message.token_id(&outpoint.tx_hash);
+    Ok((TokenId::from_slice(&token_id.to_vec_le()).expect("infallible"), amount))
+}
+
+pub type GasInputs = Vec<(InputReference<P2PKHSignatory>, [u8; 32])>;
+
+/// Deserializes a raw tx.
+///
+/// The deserializer of bitcoin-cash inverts the `is_minimal` flag of pushes, so serializing its result
+/// would e.g. re-encode the one byte push of an SLP token type as OP_1. This flips the flags back.
+pub fn deser_tx(raw_tx: &[u8]) -> Result<UnhashedTx> {
+    let (mut tx, _): (UnhashedTx, _) = UnhashedTx::deser(raw_tx.to_vec().into())?;
+    for input in &mut tx.inputs {
+        input.script = fix_push_flags(&input.script);
+    }
+    for output in &mut tx.outputs {
+        output.script = fix_push_flags(&output.script);
+    }
+    Ok(tx)
+}
+
+/// Parses serialized script ops, with the `is_minimal` flags of pushes fixed as in `deser_tx`, so that
+/// `Script::from_ops` of the result serializes to `bytes` again.
+pub fn deser_ops(bytes: &[u8]) -> Result<Vec<Op>> {
+    let mut ops = deserialize_ops(bytes)?;
+    ops.iter_mut().for_each(fix_push_flag);
+    Ok(ops)
+}
+
+fn fix_push_flags(script: &Script) -> Script {
+    let ops = script.ops().iter()
+        .map(|op| {
+            let mut op = op.clone();
+            fix_push_flag(&mut op.op);
+            op
+        })
+        .collect::<Vec<_>>();
+    Script::new(ops)
+}
+
+fn fix_push_flag(op: &mut Op) {
+    if let Op::PushByteArray { is_minimal, .. } = op {
+        *is_minimal = !*is_minimal;
+    }
+}
+
+/// Fetches the tx with the given txid (hex, as displayed), making sure the wallet returned the right one.
+pub fn get_tx(client: &ECSClient, txid: &str) -> Result<UnhashedTx> {
+    let tx_hex = client.gettransaction(txid)?;
+    let raw_tx = hex::decode(&tx_hex)
+        .with_context(|| format!("Wallet returned invalid hex for tx {}", txid))
+        .context(ErrorKind::RpcTransport)?;
+    if Sha256d::digest(raw_tx.as_slice()).to_hex_le() != txid.to_lowercase() {
+        bail_kind!(ErrorKind::RpcTransport, "Wallet returned a tx with a different txid for {}", txid);
+    }
+    deser_tx(&raw_tx)
+}
+
+/// Looks up the output at `outpoint`.
+pub fn get_output(client: &ECSClient, outpoint: &TxOutpoint) -> Result<TxOutput> {
+    let tx = get_tx(client, &outpoint.tx_hash.to_hex_le())?;
+    let output = tx.outputs.get(outpoint.vout as usize)
+        .ok_or_else(|| anyhow::anyhow!("Invalid output {}:{}", outpoint.tx_hash, outpoint.vout))
+        .context(ErrorKind::InvalidInput)?;
+    Ok(output.clone())
 }
 
-pub fn add_gas_inputs<'b>(client: &ECSClient, ecc: &impl ECC, mut tx_builder: TxBuilder<'b>) -> Result<(UnsignedTx<'b>, Vec<(InputReference<P2PKHSignatory>, [u8; 32])>)> {
-    let mut utxos = client.listunspent()?;
-    let mut gas_inputs = Vec::new();
-    let fee_rate = 1;
-    let unsigned_tx = loop {
-        if utxos.len() == 0 {
-            anyhow::bail!("Insufficient funds (not enough 'gas' in BCH)");
+/// Looks up the outputs spent by `tx`.
+pub fn prev_outputs(client: &ECSClient, tx: &UnhashedTx) -> Result<Vec<TxOutput>> {
+    let mut outputs = Vec::with_capacity(tx.inputs.len());
+    for input in &tx.inputs {
+        outputs.push(get_output(client, &input.prev_out)?);
+    }
+    Ok(outputs)
+}
+
+/// Verifies the signed `tx` against the outputs it spends and broadcasts it, unless `dry_run` is set.
+pub fn verify_and_broadcast(
+    client: &ECSClient,
+    ecc: &impl ECC,
+    tx: &UnhashedTx,
+    prev_outputs: &[TxOutput],
+    summary: &TxSummary,
+    dry_run: bool,
+) -> Result<TxReport> {
+    let checks = interpreter::verify_tx(ecc, tx, prev_outputs)?;
+    for (input_idx, check) in checks.iter().enumerate() {
+        if *check == InputCheck::SchnorrUnchecked {
+            eprintln!("Warning: input {} has a Schnorr signature, which isn't verified locally.", input_idx);
         }
-        let next_utxo = utxos.remove(0);
-        let utxo_sk = client.getprivatekeys(&next_utxo.address.cash_addr())?;
-        let utxo_pk = ecc.derive_pubkey(&utxo_sk)?;
-        let gas_ref = tx_builder.add_input(
-            UnsignedTxInput {
-                prev_out: next_utxo.outpoint.clone(),
-                sequence: 0xffff_ffff,
-                value: next_utxo.value,
-            },
-            next_utxo.address.p2pkh_script()?,
-            P2PKHSignatory {
-                pubkey: utxo_pk,
-                sig_hash_flags: SigHashFlags::DEFAULT,
+    }
+    let mut report = TxReport::new(tx, prev_outputs, &checks, summary, client.address_prefix());
+    if dry_run {
+        return Ok(report);
+    }
+    // The tx isn't quoted in errors, a redeem would reveal its secret.
+    let tx_hash = client.broadcast(&report.hex)
+        .with_context(|| format!("invalid tx {}", report.txid))?;
+    report.txid = tx_hash;
+    report.broadcast = true;
+    Ok(report)
+}
+
+/// Lists the funding UTXOs of `keys` that hold neither tokens nor a mint baton and whose keys are in `keys`,
+/// sorted by value and outpoint.
+pub fn spendable_utxos(client: &ECSClient, keys: &Keys) -> Result<Vec<Utxo>> {
+    let mut slp_messages = HashMap::new();
+    let mut spendable = Vec::new();
+    for utxo in keys.unspent(client)? {
+        let tx_hash_hex = utxo.outpoint.tx_hash.to_hex_le();
+        if !slp_messages.contains_key(&tx_hash_hex) {
+            let tx = get_tx(client, &tx_hash_hex)?;
+            let slp_message = tx.outputs.first().and_then(|output| SlpMessage::parse(&output.script));
+            slp_messages.insert(tx_hash_hex.clone(), slp_message);
+        }
+        let is_token_output = match &slp_messages[&tx_hash_hex] {
+            None => false,
+            Some(Ok(slp_message)) => slp_message.is_token_output(utxo.outpoint.vout),
+            // Play it safe if the message is malformed, there might be tokens we don't understand.
+            Some(Err(_)) => utxo.outpoint.vout != 0,
+        };
+        if !is_token_output && keys.has_key(&utxo.address)? {
+            spendable.push(utxo);
+        }
+    }
+    spendable.sort_by(|a, b| {
+        a.value.cmp(&b.value)
+            .then_with(|| a.outpoint.tx_hash.as_slice().cmp(b.outpoint.tx_hash.as_slice()))
+            .then(a.outpoint.vout.cmp(&b.outpoint.vout))
+    });
+    Ok(spendable)
+}
+
+/// Size of the tx built by `make_tx_builder` with one placeholder P2PKH gas input of the given value.
+fn probe_size<'b>(make_tx_builder: &impl Fn() -> TxBuilder<'b>, address: &Address, value: u64) -> Result<usize> {
+    let mut tx_builder = make_tx_builder();
+    tx_builder.add_input(
+        UnsignedTxInput {
+            prev_out: TxOutpoint {
+                tx_hash: Sha256d::new([0; 32]),
+                vout: 0,
             },
-        );
-        gas_inputs.push((gas_ref, utxo_sk));
+            sequence: 0xffff_ffff,
+            value,
+        },
+        address.p2pkh_script()?,
+        P2PKHSignatory {
+            pubkey: Pubkey::from_slice(&[0x02; 33]),
+            sig_hash_flags: SigHashFlags::DEFAULT,
+        },
+    );
+    Ok(tx_builder.build()?.estimated_size())
+}
+
+/// Pays the fee of the tx built by `make_tx_builder` with BCH from the wallet, avoiding token UTXOs.
+///
+/// `make_tx_builder` is called multiple times to estimate the tx size before and after adding gas inputs,
+/// so it must return the same tx every time. Its leftover output only receives change above dust.
+pub fn add_gas_inputs<'b>(
+    client: &ECSClient,
+    keys: &Keys,
+    ecc: &impl ECC,
+    make_tx_builder: impl Fn() -> TxBuilder<'b>,
+    fee_rate: u64,
+    strategy: CoinSelection,
+) -> Result<(UnsignedTx<'b>, GasInputs)> {
+    let insufficient_funds = "Insufficient funds (not enough 'gas' in BCH)";
+    let utxos = spendable_utxos(client, keys)?;
+    let first_utxo = utxos.first()
+        .ok_or_else(|| anyhow::anyhow!(insufficient_funds))
+        .context(ErrorKind::InsufficientFunds)?;
+
+    let tx_builder = make_tx_builder();
+    let shortfall = tx_builder.known_output_sum() as i64 - tx_builder.input_sum() as i64;
+    // A gas input covering the outputs exactly leaves no change, a large one always does.
+    let size_without_change = probe_size(&make_tx_builder, &first_utxo.address, shortfall.max(0) as u64)?;
+    let size_with_change = probe_size(&make_tx_builder, &first_utxo.address, shortfall.max(0) as u64 + 100_000_000)?;
+    let target = SelectionTarget {
+        shortfall,
+        base_size: size_without_change.saturating_sub(P2PKH_INPUT_SIZE),
+        change_size: size_with_change.saturating_sub(size_without_change),
+        fee_rate,
+    };
+    let values = utxos.iter().map(|utxo| utxo.value).collect::<Vec<_>>();
+    let mut selected = select_coins(&values, &target, strategy)
+        .ok_or_else(|| anyhow::anyhow!(insufficient_funds))
+        .context(ErrorKind::InsufficientFunds)?;
+
+    loop {
+        let mut tx_builder = make_tx_builder();
+        let mut gas_inputs = Vec::with_capacity(selected.len());
+        for &idx in &selected {
+            let utxo = &utxos[idx];
+            let utxo_sk = keys.secret_key(client, &utxo.address)?;
+            let utxo_pk = ecc.derive_pubkey(&utxo_sk)?;
+            let gas_ref = tx_builder.add_input(
+                UnsignedTxInput {
+                    prev_out: utxo.outpoint.clone(),
+                    sequence: 0xffff_ffff,
+                    value: utxo.value,
+                },
+                utxo.address.p2pkh_script()?,
+                P2PKHSignatory {
+                    pubkey: utxo_pk,
+                    sig_hash_flags: SigHashFlags::DEFAULT,
+                },
+            );
+            gas_inputs.push((gas_ref, utxo_sk));
+        }
         let known_output_sum = tx_builder.known_output_sum();
         let input_sum = tx_builder.input_sum();
-        if known_output_sum > input_sum {
-            continue;
-        }
-        let leftover = input_sum - known_output_sum;
-        let unsigned_tx = tx_builder.build()?;
-        if unsigned_tx.estimated_size() * fee_rate > leftover as usize {
-            tx_builder = unsigned_tx.into_tx_builder();
-            continue;
+        if known_output_sum <= input_sum {
+            let leftover = input_sum - known_output_sum;
+            let unsigned_tx = tx_builder.build()?;
+            if unsigned_tx.estimated_size() as u64 * fee_rate <= leftover {
+                return Ok((unsigned_tx, gas_inputs));
+            }
         }
-        break unsigned_tx;
-    };
-    Ok((unsigned_tx, gas_inputs))
+        // The selection was based on estimated sizes; if it falls short, add the largest UTXO left.
+        let next = (0..utxos.len()).rev().find(|idx| !selected.contains(idx))
+            .ok_or_else(|| anyhow::anyhow!(insufficient_funds))
+            .context(ErrorKind::InsufficientFunds)?;
+        selected.push(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(hex: &str) -> Script {
+        Script::from_ops(deserialize_ops(&hex::decode(hex).unwrap()).unwrap())
+    }
+
+    /// A GENESIS of 10000 tokens of `token_type`, funding a contract at output 1, with a mint baton at output 2.
+    fn genesis_tx(token_type: &str) -> (UnhashedTx, TxOutpoint) {
+        let genesis = script(&[
+            "6a", "04534c5000", token_type, "0747454e45534953",
+            "03545354", "0a5465737420546f6b656e", "4c00", "4c00", "0100", "0102", "080000000000002710",
+        ].concat());
+        let contract = TxOutput { value: 546, script: script("a914000000000000000000000000000000000000000087") };
+        let baton = TxOutput { value: 546, script: Script::default() };
+        let tx = UnhashedTx { version: 1, inputs: vec![], outputs: vec![TxOutput { value: 0, script: genesis }, contract, baton], lock_time: 0 };
+        (tx, TxOutpoint { tx_hash: Sha256d::new([0xaa; 32]), vout: 1 })
+    }
+
+    #[test]
+    fn test_genesis_token_amount() {
+        let (tx, outpoint) = genesis_tx("0101");
+        let (token_id, amount) = utxo_token_amount(&tx, &outpoint).unwrap();
+        assert_eq!(token_id.to_vec(), TokenId::from_slice(&outpoint.tx_hash.to_vec_le()).unwrap().to_vec());
+        assert_eq!(amount, 10_000);
+
+        let baton = TxOutpoint { vout: 2, ..outpoint.clone() };
+        let err = utxo_token_amount(&tx, &baton).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidSlp);
+        assert!(format!("{:#}", err).contains("holds no tokens"), "{:#}", err);
+
+        let (tx, outpoint) = genesis_tx("0181");
+        let err = utxo_token_amount(&tx, &outpoint).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidSlp);
+        assert!(format!("{:#}", err).contains("only type 1 tokens"), "{:#}", err);
+    }
 }
@@ -0,0 +1,644 @@
+//! End-to-end tests running the CLI against a mock Electron Cash SLP daemon.
+
+mod mock_ecs;
+
+use mock_ecs::MockEcs;
+use mock_ecs::post_office::MockPostOffice;
+use serde_json::{json, Value};
+use std::process::Command;
+
+/// Block height timeout, 10 blocks after the mock's tip.
+const TIMEOUT: u32 = 110;
+
+/// Runs the CLI with `--output json`, returning the exit code and the JSON report,
+/// which is the error report for failed commands.
+fn run(args: &[&str]) -> (i32, Value) {
+    let output = Command::new(env!("CARGO_BIN_EXE_slp-htlc"))
+        .args(["--output", "json"])
+        .args(args)
+        .output()
+        .unwrap();
+    let exit_code = output.status.code().unwrap();
+    let stream = if output.status.success() { &output.stdout } else { &output.stderr };
+    let stream = String::from_utf8_lossy(stream);
+    let report = stream.lines().last()
+        .unwrap_or_else(|| panic!("No output, exit code {}", exit_code));
+    (exit_code, serde_json::from_str(report).unwrap())
+}
+
+fn gen_secret() -> (String, String) {
+    let (exit_code, report) = run(&["gen-secret"]);
+    assert_eq!(exit_code, 0);
+    (report["secret"].as_str().unwrap().to_string(), report["secret_hash"].as_str().unwrap().to_string())
+}
+
+/// Locks 12.5 tokens into an HTLC, returning the send-htlc report.
+fn send_htlc(mock: &MockEcs, seller_address: &str, secret_hash: &str, timeout: u32, extra_args: &[&str]) -> (i32, Value) {
+    let token_id = mock.token_id();
+    let timeout = timeout.to_string();
+    let mut args = vec![
+        "send-htlc",
+        "--token-id", &token_id,
+        "--amount", "12.5",
+        "--seller-address", seller_address,
+        "--secret-hash", secret_hash,
+        "--timeout", &timeout,
+        "--uri", &mock.uri,
+        "--allow-key-export",
+    ];
+    args.extend_from_slice(extra_args);
+    run(&args)
+}
+
+fn contract_field<'a>(report: &'a Value, field: &str) -> &'a str {
+    report["contracts"][0][field].as_str().unwrap()
+}
+
+fn contract_timeout(report: &Value) -> String {
+    report["contracts"][0]["timeout"].as_u64().unwrap().to_string()
+}
+
+fn redeem_htlc(mock: &MockEcs, send_report: &Value, secret: &str, destination: &str, extra_args: &[&str]) -> (i32, Value) {
+    let timeout = contract_timeout(send_report);
+    let mut args = vec![
+        "redeem-htlc",
+        "--contract-utxo", contract_field(send_report, "contract_utxo"),
+        "--buyer-address", contract_field(send_report, "buyer_address"),
+        "--seller-address", contract_field(send_report, "seller_address"),
+        "--secret", secret,
+        "--timeout", &timeout,
+        "--destination", destination,
+        "--uri", &mock.uri,
+        "--allow-key-export",
+    ];
+    args.extend_from_slice(extra_args);
+    run(&args)
+}
+
+fn timeout_htlc(mock: &MockEcs, send_report: &Value, destination: &str, extra_args: &[&str]) -> (i32, Value) {
+    let timeout = contract_timeout(send_report);
+    let mut args = vec![
+        "timeout-htlc",
+        "--contract-utxo", contract_field(send_report, "contract_utxo"),
+        "--buyer-address", contract_field(send_report, "buyer_address"),
+        "--seller-address", contract_field(send_report, "seller_address"),
+        "--secret-hash", contract_field(send_report, "secret_hash"),
+        "--timeout", &timeout,
+        "--destination", destination,
+        "--uri", &mock.uri,
+        "--allow-key-export",
+    ];
+    args.extend_from_slice(extra_args);
+    run(&args)
+}
+
+#[test]
+fn test_send_and_redeem() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let seller_address = mock.new_address();
+    let (exit_code, send_report) = send_htlc(&mock, &seller_address, &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    assert_eq!(send_report["tx"]["broadcast"], true);
+    assert_eq!(send_report["token"]["token_id"], mock.token_id());
+    assert_eq!(send_report["contracts"][0]["amount"]["base_units"], 1250);
+    assert_eq!(contract_field(&send_report, "secret_hash"), secret_hash);
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+    assert!(!mock.is_spent(contract_utxo));
+
+    let destination = mock.new_address();
+    let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &[]);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    assert_eq!(redeem_report["tx"]["broadcast"], true);
+    assert_eq!(redeem_report["contracts"][0]["contract_utxo"], contract_utxo);
+    assert!(mock.is_spent(contract_utxo));
+    assert_eq!(mock.token_balance(&destination), 1250);
+
+    // The contract is gone, redeeming again is a double spend.
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[]);
+    assert_eq!(exit_code, 8, "{}", error);
+    assert_eq!(error["error"]["kind"], "broadcast_rejected");
+    // Errors never quote the tx, which holds the secret.
+    assert!(!error.to_string().contains(&secret), "{}", error);
+}
+
+#[test]
+fn test_redeem_wrong_secret() {
+    let mock = MockEcs::start();
+    let (_, secret_hash) = gen_secret();
+    let (wrong_secret, _) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+
+    let destination = mock.new_address();
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &wrong_secret, &destination, &[]);
+    assert_eq!(exit_code, 6, "{}", error);
+    assert_eq!(error["error"]["kind"], "contract_mismatch");
+    assert!(!error.to_string().contains(&wrong_secret), "{}", error);
+    assert!(!mock.is_spent(contract_field(&send_report, "contract_utxo")));
+}
+
+#[test]
+fn test_redeem_key_errors() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let timeout = contract_timeout(&send_report);
+    let redeem_args = |seller_address: &str, uri: &str| -> (i32, Value) {
+        run(&[
+            "redeem-htlc",
+            "--contract-utxo", contract_field(&send_report, "contract_utxo"),
+            "--buyer-address", contract_field(&send_report, "buyer_address"),
+            "--seller-address", seller_address,
+            "--secret", &secret,
+            "--timeout", &timeout,
+            "--fee-rate", "1",
+            "--rpc-max-attempts", "1",
+            "--uri", uri,
+            "--allow-key-export",
+        ])
+    };
+
+    // An address the wallet doesn't have is invalid input.
+    let (exit_code, error) = redeem_args("slptest:qrmtxm98pge5l3twljxvsf9u9quhy20pms4qdgwqsc", &mock.uri);
+    assert_eq!(exit_code, 2, "{}", error);
+    assert!(error["error"]["message"].as_str().unwrap().contains("not part of wallet"), "{}", error);
+    // Failing to ask the wallet isn't.
+    let unreachable_uri = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let (exit_code, error) = redeem_args(contract_field(&send_report, "seller_address"), &unreachable_uri);
+    assert_eq!(exit_code, 3, "{}", error);
+    assert_eq!(error["error"]["kind"], "rpc_transport");
+}
+
+#[test]
+fn test_redeem_expectations() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let token_id = mock.token_id();
+    let destination = mock.new_address();
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+
+    let other_token_id = "11".repeat(32);
+    let failing = [
+        (vec!["--expect-token-id", &other_token_id], "contract_mismatch"),
+        (vec!["--expect-amount", "12"], "contract_mismatch"),
+        (vec!["--expect-amount", "1250"], "contract_mismatch"),
+        (vec!["--min-confirmations", "1"], "reveal_unsafe"),
+    ];
+    for (args, kind) in &failing {
+        let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, args);
+        assert_eq!(error["error"]["kind"], *kind, "{:?}: {}", args, error);
+        assert_ne!(exit_code, 0);
+        assert!(!mock.is_spent(contract_utxo));
+    }
+
+    // One block later, 9 blocks are left until the timeout.
+    mock.mine_blocks(1);
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &["--min-time-to-timeout", "6000"]);
+    assert_eq!(exit_code, 10, "{}", error);
+    assert!(!mock.is_spent(contract_utxo));
+
+    let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--expect-token-id", &token_id,
+        "--expect-amount", "1250",
+        "--base-units",
+        "--min-confirmations", "1",
+        "--min-time-to-timeout", "5400",
+    ]);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    assert!(mock.is_spent(contract_utxo));
+}
+
+#[test]
+fn test_redeem_spv() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let destination = mock.new_address();
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+    let spv_args = ["--min-confirmations", "2", "--electrum-server", &mock.electrum_server, "--network", "regtest"];
+
+    // Unconfirmed, then with one confirmation.
+    for _ in 0..2 {
+        let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &spv_args);
+        assert_eq!(exit_code, 10, "{}", error);
+        mock.mine_blocks(1);
+    }
+
+    let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &spv_args);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    assert!(mock.is_spent(contract_utxo));
+}
+
+#[test]
+fn test_redeem_spv_bad_proof() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    mock.mine_blocks(1);
+    let destination = mock.new_address();
+    let spv_args = ["--min-confirmations", "1", "--electrum-server", &mock.electrum_server, "--network", "regtest"];
+    let assert_rejected = |extra_args: &[&str], message: &str| {
+        let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[&spv_args[..4], extra_args].concat());
+        assert_eq!(exit_code, 12, "{}", error);
+        assert_eq!(error["error"]["kind"], "spv_verification");
+        assert!(error["error"]["message"].as_str().unwrap().contains(message), "{}", error);
+        assert!(!mock.is_spent(contract_field(&send_report, "contract_utxo")));
+    };
+
+    // The mock's chain has the minimum difficulty of regtest, which anyone could fake for testnet.
+    assert_rejected(&[], "proof of work limit");
+    mock.set_bad_merkle_proofs();
+    assert_rejected(&spv_args[4..], "Merkle proof");
+}
+
+#[test]
+fn test_redeem_spv_forked_daemon() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    mock.mine_blocks(1);
+    mock.set_daemon_forked();
+
+    let destination = mock.new_address();
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--min-confirmations", "1", "--electrum-server", &mock.electrum_server, "--network", "regtest",
+    ]);
+    assert_eq!(exit_code, 12, "{}", error);
+    assert!(error["error"]["message"].as_str().unwrap().contains("wallet daemon has"), "{}", error);
+    assert!(!mock.is_spent(contract_field(&send_report, "contract_utxo")));
+}
+
+#[test]
+fn test_local_slp_validation() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    mock.set_slpvalidate_rejects_all();
+    let destination = mock.new_address();
+
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--trust-daemon-slp-validation", "--dry-run",
+    ]);
+    assert_eq!(exit_code, 5, "{}", error);
+    assert_eq!(error["error"]["kind"], "invalid_slp");
+
+    // The token DAG is validated locally by default, and the results are cached.
+    let cache = std::env::temp_dir().join(format!("slp-htlc-cli-cache-{}", std::process::id()));
+    let _ = std::fs::remove_file(&cache);
+    let cache_arg = cache.to_str().unwrap();
+    let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &["--slp-cache", cache_arg]);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    let funding_txid = contract_field(&send_report, "contract_utxo").split(':').next().unwrap();
+    let cached = std::fs::read_to_string(&cache).unwrap();
+    assert!(cached.lines().any(|line| line == format!("{} valid", funding_txid)), "{}", cached);
+    assert!(cached.lines().any(|line| line == format!("{} valid", mock.token_id())), "{}", cached);
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[test]
+fn test_send_and_timeout() {
+    let mock = MockEcs::start();
+    let (_, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+
+    // Before the timeout, the refund isn't final yet and isn't broadcast.
+    let destination = mock.new_address();
+    mock.mine_blocks(TIMEOUT - 1 - mock.height());
+    let num_txs = mock.num_txs();
+    let (exit_code, error) = timeout_htlc(&mock, &send_report, &destination, &[]);
+    assert_eq!(exit_code, 11, "{}", error);
+    assert_eq!(error["error"]["kind"], "not_final");
+    assert!(error["error"]["message"].as_str().unwrap().contains("final after the next block"));
+    assert_eq!(mock.num_txs(), num_txs);
+
+    // A dry run only warns.
+    let (exit_code, timeout_report) = timeout_htlc(&mock, &send_report, &destination, &["--dry-run"]);
+    assert_eq!(exit_code, 0, "{}", timeout_report);
+    assert!(!mock.is_spent(contract_utxo));
+
+    mock.mine_blocks(1);
+    let (exit_code, timeout_report) = timeout_htlc(&mock, &send_report, &destination, &[]);
+    assert_eq!(exit_code, 0, "{}", timeout_report);
+    assert_eq!(timeout_report["tx"]["broadcast"], true);
+    assert!(mock.is_spent(contract_utxo));
+    assert_eq!(mock.token_balance(&destination), 1250);
+}
+
+#[test]
+fn test_timeout_median_time_past() {
+    let mock = MockEcs::start();
+    let (_, secret_hash) = gen_secret();
+    // The median time past advances by one block interval per block.
+    let timeout = mock.median_time_past() + 3 * 600;
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, timeout, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+    let destination = mock.new_address();
+    mock.mine_blocks(3);
+
+    // The wall clock is long past the timeout, but the median time past only just reached it.
+    let num_txs = mock.num_txs();
+    let (exit_code, error) = timeout_htlc(&mock, &send_report, &destination, &[]);
+    assert_eq!(exit_code, 11, "{}", error);
+    assert!(error["error"]["message"].as_str().unwrap().contains("median time past"), "{}", error);
+    assert_eq!(mock.num_txs(), num_txs);
+    assert!(!mock.is_spent(contract_utxo));
+
+    // --wait broadcasts the refund once the next block is mined.
+    let miner = mock.clone();
+    let mining = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        miner.mine_blocks(1);
+    });
+    let (exit_code, timeout_report) = timeout_htlc(&mock, &send_report, &destination, &[
+        "--wait",
+        "--poll-interval", "1",
+    ]);
+    mining.join().unwrap();
+    assert_eq!(exit_code, 0, "{}", timeout_report);
+    assert!(mock.is_spent(contract_utxo));
+    assert_eq!(mock.token_balance(&destination), 1250);
+}
+
+#[test]
+fn test_send_from_funding_address() {
+    let mock = MockEcs::start();
+    let dir = std::env::temp_dir().join(format!("slp-htlc-cli-funding-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let passphrase_file = dir.join("passphrase");
+    std::fs::write(&passphrase_file, "correct horse battery staple\n").unwrap();
+    let keystore = dir.join("keystore.json");
+    let keystore_args = ["--keystore", keystore.to_str().unwrap(), "--keystore-passphrase-file", passphrase_file.to_str().unwrap()];
+    let (exit_code, create_report) = run(&[&["create-keystore"], &keystore_args[..]].concat());
+    assert_eq!(exit_code, 0, "{}", create_report);
+    let (exit_code, address_report) = run(&[&["keystore-address", "--role", "buyer"], &keystore_args[..]].concat());
+    assert_eq!(exit_code, 0, "{}", address_report);
+    let funding_address = address_report["address"].as_str().unwrap();
+
+    // Move tokens and BCH to the keystore's address, then take the wallet away.
+    for _ in 0..2 {
+        let (secret, secret_hash) = gen_secret();
+        let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+        assert_eq!(exit_code, 0, "{}", send_report);
+        let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, funding_address, &[]);
+        assert_eq!(exit_code, 0, "{}", redeem_report);
+    }
+    mock.fund(funding_address, 100_000);
+    mock.set_wallet_disabled();
+
+    let (_, secret_hash) = gen_secret();
+    let funding_args = [&keystore_args[..], &["--funding-address", funding_address]].concat();
+    let (exit_code, error) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &keystore_args);
+    assert_eq!(exit_code, 4, "{}", error);
+    assert!(error["error"]["message"].as_str().unwrap().contains("No wallet loaded"), "{}", error);
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &funding_args);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    assert!(!mock.is_spent(contract_field(&send_report, "contract_utxo")));
+    // Token change went back to the funding address.
+    assert_eq!(mock.token_balance(funding_address), 1250);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_send_dry_run() {
+    let mock = MockEcs::start();
+    let (_, secret_hash) = gen_secret();
+    let num_txs = mock.num_txs();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &["--dry-run"]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    assert_eq!(send_report["tx"]["broadcast"], false);
+    assert!(send_report["tx"]["inputs"].as_array().unwrap().iter().all(|input| input["verified"] == true));
+    assert_eq!(mock.num_txs(), num_txs);
+}
+
+#[test]
+fn test_send_token_selection() {
+    let mock = MockEcs::start();
+    let (_, secret_hash) = gen_secret();
+    // The second send spends the token change of the first.
+    for _ in 0..2 {
+        let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+        assert_eq!(exit_code, 0, "{}", send_report);
+        assert!(contract_field(&send_report, "contract_utxo").ends_with(":1"));
+        // SLP message, contract, token change and BCH change.
+        assert_eq!(send_report["tx"]["outputs"].as_array().unwrap().len(), 4);
+    }
+
+    // 975 tokens are left.
+    let token_id = mock.token_id();
+    let seller_address = mock.new_address();
+    let timeout = TIMEOUT.to_string();
+    let (exit_code, error) = run(&[
+        "send-htlc",
+        "--token-id", &token_id,
+        "--amount", "975.01",
+        "--seller-address", &seller_address,
+        "--secret-hash", &secret_hash,
+        "--timeout", &timeout,
+        "--uri", &mock.uri,
+        "--allow-key-export",
+    ]);
+    assert_eq!(exit_code, 7, "{}", error);
+    assert_eq!(error["error"]["kind"], "insufficient_funds");
+}
+
+#[test]
+fn test_sponsored_redeem() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (_, remainder_secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+
+    // The sponsor's gas inputs change the txid, so a remainder contract couldn't be reported.
+    let destination = mock.new_address();
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--sponsored", "--accept-secret-reveal", "--partial-amount", "5", "--remainder-secret-hash", &remainder_secret_hash,
+    ]);
+    assert_eq!(exit_code, 2, "{}", error);
+
+    // The sponsor learns the secret, which has to be confirmed.
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &["--sponsored"]);
+    assert_eq!(exit_code, 10, "{}", error);
+    assert!(error["error"]["message"].as_str().unwrap().contains("--accept-secret-reveal"), "{}", error);
+
+    let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &["--sponsored", "--accept-secret-reveal"]);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    assert_eq!(redeem_report["tx"]["broadcast"], false);
+    assert_eq!(redeem_report["tx"]["complete"], false);
+    let inputs = redeem_report["tx"]["inputs"].as_array().unwrap();
+    assert_eq!(inputs.len(), 1);
+    assert_eq!(inputs[0]["verified"], true);
+    assert!(!mock.is_spent(contract_utxo));
+    let partial_tx = redeem_report["tx"]["hex"].as_str().unwrap();
+
+    // The spender's signature covers all outputs.
+    let tampered_tx = partial_tx.replacen("2202000000000000", "2302000000000000", 1);
+    let (exit_code, error) = run(&["sponsor", "--tx", &tampered_tx, "--uri", &mock.uri, "--allow-key-export"]);
+    assert_eq!(exit_code, 9, "{}", error);
+    assert_eq!(error["error"]["kind"], "script_verification");
+
+    // Gas inputs can't get change, the fee fits in this UTXO.
+    let gas_utxo = mock.fund(&mock.new_address(), 1_500);
+    let (exit_code, sponsor_report) = run(&[
+        "sponsor", "--tx", partial_tx, "--uri", &mock.uri, "--allow-key-export", "--coin-selection", "smallest-sufficient",
+    ]);
+    assert_eq!(exit_code, 0, "{}", sponsor_report);
+    assert_eq!(sponsor_report["tx"]["broadcast"], true);
+    assert_eq!(sponsor_report["tx"]["complete"], true);
+    assert_eq!(sponsor_report["tx"]["outputs"], redeem_report["tx"]["outputs"]);
+    assert!(sponsor_report["tx"]["inputs"].as_array().unwrap().iter().all(|input| input["verified"] == true));
+    assert!(mock.is_spent(contract_utxo));
+    assert!(mock.is_spent(&gas_utxo));
+    assert_eq!(mock.token_balance(&destination), 1250);
+}
+
+#[test]
+fn test_post_office_redeem() {
+    let mock = MockEcs::start();
+    let (secret, secret_hash) = gen_secret();
+    let (exit_code, send_report) = send_htlc(&mock, &mock.new_address(), &secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+    let destination = mock.new_address();
+
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--sponsored", "--post-office", "http://127.0.0.1:1",
+    ]);
+    assert_eq!(exit_code, 2, "{}", error);
+
+    // The postage is taken from the redeemed tokens, which must be enough.
+    let expensive_post_office = MockPostOffice::start(&mock, 500, 1_000);
+    let (exit_code, error) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--post-office", &expensive_post_office.uri,
+    ]);
+    assert_eq!(exit_code, 7, "{}", error);
+    assert_eq!(error["error"]["kind"], "insufficient_funds");
+
+    let post_office = MockPostOffice::start(&mock, 500, 10);
+    let (exit_code, dry_run_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--post-office", &post_office.uri, "--dry-run",
+    ]);
+    assert_eq!(exit_code, 0, "{}", dry_run_report);
+    assert_eq!(dry_run_report["tx"]["broadcast"], false);
+    assert_eq!(dry_run_report["tx"]["complete"], false);
+    assert!(!mock.is_spent(contract_utxo));
+
+    let num_txs = mock.num_txs();
+    let (exit_code, redeem_report) = redeem_htlc(&mock, &send_report, &secret, &destination, &[
+        "--post-office", &post_office.uri,
+    ]);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    assert_eq!(redeem_report["tx"]["broadcast"], true);
+    assert_eq!(redeem_report["tx"]["complete"], true);
+    assert_eq!(redeem_report["postage"], dry_run_report["postage"]);
+    let inputs = redeem_report["tx"]["inputs"].as_array().unwrap();
+    assert_eq!(inputs.len(), 2);
+    assert!(inputs.iter().all(|input| input["verified"] == true));
+    // Only the stamp's funding tx and the redeem were added, no wallet UTXOs were spent.
+    assert_eq!(mock.num_txs(), num_txs + 2);
+    assert!(mock.is_spent(contract_utxo));
+    let postage = redeem_report["postage"]["base_units"].as_u64().unwrap();
+    assert_eq!(postage % 10, 0);
+    assert!(postage > 0);
+    assert_eq!(mock.token_balance(&post_office.address), postage);
+    assert_eq!(mock.token_balance(&destination), 1250 - postage);
+}
+
+#[test]
+fn test_keystore_redeem() {
+    let mock = MockEcs::start();
+    let dir = std::env::temp_dir().join(format!("slp-htlc-cli-keystore-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let passphrase_file = dir.join("passphrase");
+    std::fs::write(&passphrase_file, "correct horse battery staple\n").unwrap();
+    let wrong_passphrase_file = dir.join("wrong-passphrase");
+    std::fs::write(&wrong_passphrase_file, "wrong").unwrap();
+    let keystore = dir.join("keystore.json");
+    let keystore = keystore.to_str().unwrap();
+    let passphrase_file = passphrase_file.to_str().unwrap();
+
+    let (exit_code, create_report) = run(&["create-keystore", "--keystore", keystore, "--keystore-passphrase-file", passphrase_file]);
+    assert_eq!(exit_code, 0, "{}", create_report);
+    assert_eq!(create_report["mnemonic"].as_str().unwrap().split(' ').count(), 24);
+    // Keystores are never overwritten.
+    let (exit_code, error) = run(&["create-keystore", "--keystore", keystore, "--keystore-passphrase-file", passphrase_file]);
+    assert_eq!(exit_code, 2, "{}", error);
+    let (exit_code, error) = run(&[
+        "keystore-address", "--keystore", keystore, "--role", "seller",
+        "--keystore-passphrase-file", wrong_passphrase_file.to_str().unwrap(),
+    ]);
+    assert_eq!(exit_code, 2, "{}", error);
+    assert_eq!(error["error"]["kind"], "invalid_input");
+
+    let (exit_code, address_report) = run(&[
+        "keystore-address", "--keystore", keystore, "--role", "seller", "--keystore-passphrase-file", passphrase_file,
+    ]);
+    assert_eq!(exit_code, 0, "{}", address_report);
+    assert_eq!(address_report["path"], "m/44'/245'/1'/1/0");
+    let seller_address = address_report["address"].as_str().unwrap();
+    let keystore_args = ["--keystore", keystore, "--keystore-passphrase-file", passphrase_file];
+    let (exit_code, secret_report) = run(&[&["gen-secret"], &keystore_args[..]].concat());
+    assert_eq!(exit_code, 0, "{}", secret_report);
+    assert_eq!(secret_report["index"], 0);
+    let secret_hash = secret_report["secret_hash"].as_str().unwrap();
+    let (exit_code, send_report) = send_htlc(&mock, seller_address, secret_hash, TIMEOUT, &[]);
+    assert_eq!(exit_code, 0, "{}", send_report);
+
+    // The seller lost the secret, but can recover it from the contract's secret hash.
+    let (exit_code, error) = run(&[&["recover-secret"], &keystore_args[..]].concat());
+    assert_eq!(exit_code, 2, "{}", error);
+    let contract_secret_hash = contract_field(&send_report, "secret_hash");
+    let (exit_code, recovered) = run(&[&["recover-secret", "--secret-hash", contract_secret_hash], &keystore_args[..]].concat());
+    assert_eq!(exit_code, 0, "{}", recovered);
+    assert_eq!(recovered, json!({"command": "recover-secret", "index": 0, "secret": secret_report["secret"], "secret_hash": secret_hash}));
+    let (exit_code, by_index) = run(&[&["recover-secret", "--index", "0"], &keystore_args[..]].concat());
+    assert_eq!(exit_code, 0, "{}", by_index);
+    assert_eq!(by_index, recovered);
+    let secret = recovered["secret"].as_str().unwrap().to_string();
+    let contract_utxo = contract_field(&send_report, "contract_utxo");
+
+    let destination = mock.new_address();
+    let post_office = MockPostOffice::start(&mock, 500, 10);
+    let timeout = contract_timeout(&send_report);
+    let mut args = vec![
+        "redeem-htlc",
+        "--contract-utxo", contract_utxo,
+        "--buyer-address", contract_field(&send_report, "buyer_address"),
+        "--seller-address", seller_address,
+        "--secret", &secret,
+        "--timeout", &timeout,
+        "--destination", &destination,
+        "--post-office", &post_office.uri,
+        "--uri", &mock.uri,
+    ];
+    // Without a keystore, the seller key would have to be exported over RPC.
+    let (exit_code, error) = run(&args);
+    assert_eq!(exit_code, 2, "{}", error);
+    assert!(error["error"]["message"].as_str().unwrap().contains("--allow-key-export"), "{}", error);
+    assert!(!mock.is_spent(contract_utxo));
+
+    args.extend_from_slice(&keystore_args);
+    let (exit_code, redeem_report) = run(&args);
+    assert_eq!(exit_code, 0, "{}", redeem_report);
+    assert!(mock.is_spent(contract_utxo));
+    let postage = redeem_report["postage"]["base_units"].as_u64().unwrap();
+    assert_eq!(mock.token_balance(&destination), 1250 - postage);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,707 @@
+//! A mock Electron Cash SLP daemon for end-to-end tests of the CLI.
+//!
+//! Serves the JSON-RPC methods used by `ECSClient` from a simulated chain: a GENESIS of a test token
+//! sends tokens and BCH to the mock's wallet, and broadcast transactions are added to the UTXO set.
+//! Broadcast checks inputs, values, lock times and SLP amounts, but not signatures; the CLI verifies
+//! those itself before broadcasting.
+//!
+//! Mined blocks form a header chain with regtest difficulty, which is also served by a mock Electrum
+//! server, so lock times are checked against the height and median time past like on a real chain.
+//! A stand-in SLP post office paying the gas of txs out of the mock's funds is in `post_office`.
+
+use bitcoin_cash::*;
+use bitcoin_cash_ecc::{init_ecc, SelectedECC};
+use bitcoin_cash_slp::{slp_genesis_output, SlpGenesisParams, SlpTokenType};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+pub mod post_office;
+
+const PREFIX: &str = "slptest";
+const TOKEN_DECIMALS: u8 = 2;
+/// Token amount in base units the wallet receives in the GENESIS.
+const GENESIS_AMOUNT: u64 = 100_000;
+/// The wallet's BCH UTXOs created by the GENESIS.
+const GENESIS_BCH_UTXOS: &[u64] = &[20_000, 50_000, 100_000];
+const FEE_PER_KB: u64 = 1000;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// Height of the chain once the mock is started; the GENESIS is mined in block 1.
+const START_HEIGHT: u32 = 100;
+/// Timestamp of block 0; every following block is `BLOCK_INTERVAL` seconds later.
+const CHAIN_START_TIME: u32 = 1_600_000_000;
+const BLOCK_INTERVAL: u32 = 600;
+/// Regtest difficulty, about every second nonce is a valid proof of work.
+const BITS: u32 = 0x207f_ffff;
+const MEDIAN_TIME_SPAN: usize = 11;
+
+type Outpoint = (String, u32);
+
+#[derive(Clone)]
+pub struct MockEcs {
+    pub uri: String,
+    /// `<host>:<port>` of the mock Electrum server.
+    pub electrum_server: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    ecc: SelectedECC,
+    /// Secret keys of the wallet, in the order their addresses were handed out.
+    keys: Vec<[u8; 32]>,
+    /// Raw hex of every tx on the simulated chain, by txid.
+    txs: HashMap<String, String>,
+    outputs: HashMap<Outpoint, TxOutput>,
+    /// Txid of the tx spending each spent output.
+    spent: HashMap<Outpoint, String>,
+    /// Height of the block each tx was mined in, 0 for unconfirmed txs.
+    tx_heights: HashMap<String, i32>,
+    /// Token id (hex, as displayed) and amount of every output carrying tokens.
+    tokens: HashMap<Outpoint, (String, u64)>,
+    /// Txids of valid SLP transactions.
+    valid_slp: HashSet<String>,
+    /// Raw headers of all blocks, by height.
+    headers: Vec<Vec<u8>>,
+    /// Txids of the txs in each block, by height, starting with a pseudo coinbase.
+    block_txids: Vec<Vec<Sha256d>>,
+    /// Whether the Electrum server returns merkle proofs that don't match the blocks.
+    bad_merkle_proofs: bool,
+    /// Whether `slpvalidate` claims every tx is invalid.
+    slpvalidate_rejects_all: bool,
+    /// Whether the wallet methods (`listunspent`, `getunusedaddress`, `getprivatekeys`) fail, as on a
+    /// daemon without a wallet.
+    wallet_disabled: bool,
+    /// Whether `getblockheader` returns headers of another chain than the Electrum server's.
+    daemon_forked: bool,
+    token_id: String,
+}
+
+impl MockEcs {
+    /// Starts the server with a wallet holding `GENESIS_AMOUNT` of a fresh token and some BCH.
+    pub fn start() -> MockEcs {
+        let mut state = State {
+            ecc: init_ecc(),
+            keys: Vec::new(),
+            txs: HashMap::new(),
+            outputs: HashMap::new(),
+            spent: HashMap::new(),
+            tx_heights: HashMap::new(),
+            tokens: HashMap::new(),
+            valid_slp: HashSet::new(),
+            headers: Vec::new(),
+            block_txids: Vec::new(),
+            bad_merkle_proofs: false,
+            slpvalidate_rejects_all: false,
+            daemon_forked: false,
+            wallet_disabled: false,
+            token_id: String::new(),
+        };
+        state.mine_block();
+        state.genesis();
+        while state.height() < START_HEIGHT {
+            state.mine_block();
+        }
+        let state = Arc::new(Mutex::new(state));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let server_state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let body = read_request(&mut stream);
+                let response = server_state.lock().unwrap().handle(&body);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(), response,
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let electrum_server = listener.local_addr().unwrap().to_string();
+        let server_state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let state = Arc::clone(&server_state);
+                std::thread::spawn(move || serve_electrum(stream, &state));
+            }
+        });
+        MockEcs { uri, electrum_server, state }
+    }
+
+    /// Token id (hex, as displayed) of the token created in the GENESIS.
+    pub fn token_id(&self) -> String {
+        self.state.lock().unwrap().token_id.clone()
+    }
+
+    /// A new address of the wallet.
+    pub fn new_address(&self) -> String {
+        let mut state = self.state.lock().unwrap();
+        let idx = state.new_key();
+        state.address(idx).cash_addr().to_string()
+    }
+
+    /// Height of the chain tip.
+    pub fn height(&self) -> u32 {
+        self.state.lock().unwrap().height()
+    }
+
+    /// Median time past of the chain tip.
+    pub fn median_time_past(&self) -> u32 {
+        self.state.lock().unwrap().median_time_past()
+    }
+
+    /// Mines `num_blocks` blocks, the first of which confirms all unconfirmed txs.
+    pub fn mine_blocks(&self, num_blocks: u32) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..num_blocks {
+            state.mine_block();
+        }
+    }
+
+    /// Sends `value` sats to `address` out of thin air, returning the outpoint.
+    pub fn fund(&self, address: &str, value: u64) -> String {
+        let txid = self.state.lock().unwrap().fund(Address::from_cash_addr(address).unwrap().into(), value);
+        format!("{}:0", txid)
+    }
+
+    /// Makes the Electrum server return merkle proofs that don't lead to the blocks' merkle roots.
+    pub fn set_bad_merkle_proofs(&self) {
+        self.state.lock().unwrap().bad_merkle_proofs = true;
+    }
+
+    /// Makes `slpvalidate` claim every tx is invalid, as a broken or malicious daemon might.
+    pub fn set_slpvalidate_rejects_all(&self) {
+        self.state.lock().unwrap().slpvalidate_rejects_all = true;
+    }
+
+    /// Makes the wallet methods fail, as if the daemon had no wallet loaded.
+    pub fn set_wallet_disabled(&self) {
+        self.state.lock().unwrap().wallet_disabled = true;
+    }
+
+    /// Makes `getblockheader` return headers that differ from the Electrum server's, as if the server
+    /// served a chain of its own.
+    pub fn set_daemon_forked(&self) {
+        self.state.lock().unwrap().daemon_forked = true;
+    }
+
+    /// Unspent token amount, in base units, at `address`.
+    pub fn token_balance(&self, address: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        let script: Script = Address::from_cash_addr(address).unwrap().into();
+        state.tokens.iter()
+            .filter(|(outpoint, _)| !state.spent.contains_key(*outpoint))
+            .filter(|(outpoint, _)| state.outputs[*outpoint].script.ser_ops() == script.ser_ops())
+            .map(|(_, (_, amount))| amount)
+            .sum()
+    }
+
+    /// Whether the output `<txid>:<vout>` has been spent.
+    pub fn is_spent(&self, outpoint: &str) -> bool {
+        let (txid, vout) = parse_outpoint(outpoint);
+        self.state.lock().unwrap().spent.contains_key(&(txid, vout))
+    }
+
+    /// Number of transactions on the simulated chain.
+    pub fn num_txs(&self) -> usize {
+        self.state.lock().unwrap().txs.len()
+    }
+}
+
+impl State {
+    fn new_key(&mut self) -> usize {
+        let sk = Sha256d::digest(format!("mock wallet key {}", self.keys.len()).into_bytes());
+        let mut key = [0; 32];
+        key.copy_from_slice(sk.as_slice());
+        self.keys.push(key);
+        self.keys.len() - 1
+    }
+
+    fn pubkey(&self, idx: usize) -> Pubkey {
+        self.ecc.derive_pubkey(&self.keys[idx]).unwrap()
+    }
+
+    fn address(&self, idx: usize) -> Address<'static> {
+        Address::from_pk(PREFIX, &self.pubkey(idx)).to_owned_address()
+    }
+
+    fn key_of_script(&self, script: &Script) -> Option<usize> {
+        (0..self.keys.len()).find(|&idx| {
+            let p2pkh: Script = self.address(idx).p2pkh_script().unwrap().into();
+            p2pkh.ser_ops() == script.ser_ops()
+        })
+    }
+
+    fn height(&self) -> u32 {
+        self.headers.len() as u32 - 1
+    }
+
+    fn median_time_past(&self) -> u32 {
+        let start = self.headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times = self.headers[start..].iter().map(|header| header_time(header)).collect::<Vec<_>>();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// Mines a block confirming all unconfirmed txs.
+    fn mine_block(&mut self) {
+        let height = self.headers.len() as u32;
+        let mut txids = self.tx_heights.iter()
+            .filter(|(_, &tx_height)| tx_height == 0)
+            .map(|(txid, _)| txid.clone())
+            .collect::<Vec<_>>();
+        txids.sort();
+        for txid in &txids {
+            self.tx_heights.insert(txid.clone(), height as i32);
+        }
+        let mut block_txids = vec![Sha256d::digest(format!("mock coinbase {}", height).into_bytes())];
+        block_txids.extend(txids.iter().map(|txid| Sha256d::from_hex_le(txid).unwrap()));
+
+        let mut header = Vec::with_capacity(80);
+        header.extend_from_slice(&1u32.to_le_bytes());
+        match self.headers.last() {
+            Some(prev_header) => header.extend_from_slice(Sha256d::digest(prev_header.as_slice()).as_slice()),
+            None => header.extend_from_slice(&[0; 32]),
+        }
+        header.extend_from_slice(merkle_root(&block_txids).as_slice());
+        header.extend_from_slice(&(CHAIN_START_TIME + height * BLOCK_INTERVAL).to_le_bytes());
+        header.extend_from_slice(&BITS.to_le_bytes());
+        header.extend_from_slice(&[0; 4]);
+        // The hash, as a big-endian number, must not exceed 0x7fffff << 232.
+        for nonce in 0u32.. {
+            header[76..].copy_from_slice(&nonce.to_le_bytes());
+            if Sha256d::digest(header.as_slice()).as_slice()[31] < 0x7f {
+                break;
+            }
+        }
+        self.headers.push(header);
+        self.block_txids.push(block_txids);
+    }
+
+    fn genesis(&mut self) {
+        let key = self.new_key();
+        let wallet_script: Script = self.address(key).p2pkh_script().unwrap().into();
+        let mut outputs = vec![
+            slp_genesis_output(SlpGenesisParams {
+                slp_token_type: SlpTokenType::Fungible,
+                token_ticker: "MOCK",
+                token_name: "Mock Token",
+                token_document_url: "",
+                token_document_hash: "",
+                decimals: TOKEN_DECIMALS,
+                mint_baton_vout: None,
+                initial_token_mint_quantity: GENESIS_AMOUNT,
+            }),
+            TxOutput { value: DUST_AMOUNT, script: wallet_script.clone() },
+        ];
+        for &value in GENESIS_BCH_UTXOS {
+            outputs.push(TxOutput { value, script: wallet_script.clone() });
+        }
+        // Funded out of thin air, like a coinbase.
+        let tx = UnhashedTx {
+            version: 1,
+            inputs: vec![TxInput::new(
+                TxOutpoint { tx_hash: Sha256d::new([0; 32]), vout: SEQUENCE_FINAL },
+                Script::default(),
+                SEQUENCE_FINAL,
+            )],
+            outputs,
+            lock_time: 0,
+        };
+        let txid = self.add_tx(&tx);
+        self.tokens.insert((txid.clone(), 1), (txid.clone(), GENESIS_AMOUNT));
+        self.valid_slp.insert(txid.clone());
+        self.token_id = txid;
+    }
+
+    /// Sends `value` sats to `script` out of thin air, returning the txid; the output is at vout 0.
+    fn fund(&mut self, script: Script, value: u64) -> String {
+        let tx = UnhashedTx {
+            version: 1,
+            inputs: vec![TxInput::new(
+                TxOutpoint { tx_hash: Sha256d::new([0; 32]), vout: self.txs.len() as u32 },
+                Script::default(),
+                SEQUENCE_FINAL,
+            )],
+            outputs: vec![TxOutput { value, script }],
+            lock_time: 0,
+        };
+        self.add_tx(&tx)
+    }
+
+    fn add_tx(&mut self, tx: &UnhashedTx) -> String {
+        let raw_tx = tx.ser();
+        let txid = Sha256d::digest(raw_tx.as_slice()).to_hex_le();
+        for input in &tx.inputs {
+            self.spent.insert((input.prev_out.tx_hash.to_hex_le(), input.prev_out.vout), txid.clone());
+        }
+        self.tx_heights.insert(txid.clone(), 0);
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            self.outputs.insert((txid.clone(), vout as u32), output.clone());
+        }
+        self.txs.insert(txid.clone(), hex::encode(raw_tx.as_slice()));
+        txid
+    }
+
+    fn wallet_utxos(&self) -> Vec<(Outpoint, usize, u64)> {
+        let mut utxos = self.outputs.iter()
+            .filter(|(outpoint, _)| !self.spent.contains_key(*outpoint))
+            .filter_map(|(outpoint, output)| {
+                self.key_of_script(&output.script).map(|key| (outpoint.clone(), key, output.value))
+            })
+            .collect::<Vec<_>>();
+        utxos.sort();
+        utxos
+    }
+
+    fn handle(&mut self, body: &str) -> String {
+        let request: Value = serde_json::from_str(body).unwrap();
+        let method = request["method"].as_str().unwrap();
+        let params = &request["params"];
+        let result = match method {
+            "listunspent" | "getunusedaddress" | "getprivatekeys" if self.wallet_disabled => {
+                Err("No wallet loaded".to_string())
+            }
+            "getunusedaddress" => {
+                let idx = self.new_key();
+                let address = self.address(idx);
+                Ok(json!(address.cash_addr().split_once(':').unwrap().1))
+            }
+            "getprivatekeys" => self.getprivatekeys(params["address"].as_str().unwrap()),
+            "getfeerate" => Ok(json!(FEE_PER_KB)),
+            "getinfo" => Ok(json!({ "blockchain_height": self.height() })),
+            "getblockheader" => {
+                let height = params["height"].as_u64().unwrap() as usize;
+                self.headers.get(height)
+                    .map(|header| {
+                        let mut header = header.clone();
+                        if self.daemon_forked {
+                            header[76] ^= 1;
+                        }
+                        json!(hex::encode(header))
+                    })
+                    .ok_or_else(|| format!("No block at height {}", height))
+            }
+            "getaddresshistory" => self.getaddresshistory(params["address"].as_str().unwrap()),
+            "listunspent" => Ok(self.listunspent()),
+            "getaddressunspent" => self.getaddressunspent(params["address"].as_str().unwrap()),
+            "gettransaction" => {
+                let txid = params["txid"].as_str().unwrap();
+                self.txs.get(txid)
+                    .map(|hex| json!({ "hex": hex }))
+                    .ok_or_else(|| format!("Transaction {} not found", txid))
+            }
+            "slpvalidate" => {
+                let txid = params["txid"].as_str().unwrap();
+                let is_valid = self.valid_slp.contains(txid) && !self.slpvalidate_rejects_all;
+                Ok(json!(if is_valid { "Valid" } else { "Invalid" }))
+            }
+            "broadcast" => Ok(match self.broadcast(params["tx"].as_str().unwrap()) {
+                Ok(txid) => json!([true, txid]),
+                Err(message) => json!([false, message]),
+            }),
+            _ => Err(format!("Unknown method {}", method)),
+        };
+        match result {
+            Ok(result) => json!({ "id": request["id"], "result": result }).to_string(),
+            Err(message) => json!({ "id": request["id"], "error": { "code": -1, "message": message } }).to_string(),
+        }
+    }
+
+    fn handle_electrum(&self, request: &Value) -> String {
+        let params = &request["params"];
+        let result = match request["method"].as_str().unwrap() {
+            "server.version" => Ok(json!(["mock electrum", "1.4"])),
+            "blockchain.headers.subscribe" => Ok(json!({
+                "height": self.height(),
+                "hex": hex::encode(self.headers.last().unwrap()),
+            })),
+            "blockchain.block.headers" => {
+                let start = params[0].as_u64().unwrap() as usize;
+                let end = (start + params[1].as_u64().unwrap() as usize).min(self.headers.len());
+                let headers = self.headers[start.min(end)..end].concat();
+                Ok(json!({ "count": end - start.min(end), "hex": hex::encode(headers), "max": 2016 }))
+            }
+            "blockchain.scripthash.get_history" => {
+                let script_hash = params[0].as_str().unwrap();
+                Ok(self.history(|script| Sha256::digest(script.ser_ops()).to_hex_le() == script_hash))
+            }
+            "blockchain.transaction.get_merkle" => {
+                self.merkle_proof(params[0].as_str().unwrap(), params[1].as_u64().unwrap() as usize)
+            }
+            method => Err(format!("Unknown method {}", method)),
+        };
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }).to_string(),
+            Err(message) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": { "code": -1, "message": message } }).to_string(),
+        }
+    }
+
+    fn getprivatekeys(&self, address: &str) -> Result<Value, String> {
+        let idx = (0..self.keys.len()).find(|&idx| self.address(idx).cash_addr() == address)
+            .ok_or_else(|| format!("Address {} not in wallet", address))?;
+        let sk = bitcoin::PrivateKey {
+            compressed: true,
+            network: bitcoin::Network::Testnet,
+            key: bitcoin::secp256k1::SecretKey::from_slice(&self.keys[idx]).unwrap(),
+        };
+        Ok(json!(sk.to_wif()))
+    }
+
+    fn getaddresshistory(&self, address: &str) -> Result<Value, String> {
+        let address = Address::from_cash_addr(address)
+            .map_err(|err| format!("Invalid address {}: {:?}", address, err))?;
+        let script: Script = address.into();
+        Ok(self.history(|output_script| output_script.ser_ops() == script.ser_ops()))
+    }
+
+    fn getaddressunspent(&self, address: &str) -> Result<Value, String> {
+        let address = Address::from_cash_addr(address)
+            .map_err(|err| format!("Invalid address {}: {:?}", address, err))?;
+        let script: Script = address.into();
+        let mut unspent = self.outputs.iter()
+            .filter(|(outpoint, output)| !self.spent.contains_key(*outpoint) && output.script.ser_ops() == script.ser_ops())
+            .map(|((txid, vout), output)| (txid.clone(), *vout, output.value))
+            .collect::<Vec<_>>();
+        unspent.sort();
+        Ok(unspent.into_iter()
+            .map(|(txid, vout, value)| json!({
+                "tx_hash": txid, "tx_pos": vout, "height": self.tx_heights[&txid], "value": value,
+            }))
+            .collect())
+    }
+
+    /// History of the outputs whose script matches, as `[{tx_hash, height}]`.
+    fn history(&self, matches: impl Fn(&Script) -> bool) -> Value {
+        let mut txids = HashSet::new();
+        for (outpoint, output) in &self.outputs {
+            if matches(&output.script) {
+                txids.insert(outpoint.0.clone());
+                if let Some(spending_txid) = self.spent.get(outpoint) {
+                    txids.insert(spending_txid.clone());
+                }
+            }
+        }
+        let mut history = txids.into_iter()
+            .map(|txid| (self.tx_heights[&txid], txid))
+            .collect::<Vec<_>>();
+        history.sort();
+        history.into_iter().map(|(height, txid)| json!({ "tx_hash": txid, "height": height })).collect()
+    }
+
+    /// Merkle branch of `txid` in the block at `height`, as `{block_height, merkle, pos}`.
+    fn merkle_proof(&self, txid: &str, height: usize) -> Result<Value, String> {
+        let txid = Sha256d::from_hex_le(txid).map_err(|err| format!("Invalid txid: {:?}", err))?;
+        let block_txids = self.block_txids.get(height).ok_or("Block not found")?;
+        let mut pos = block_txids.iter().position(|block_txid| *block_txid == txid).ok_or("Tx not in block")?;
+        let result_pos = pos;
+        let mut level = block_txids.clone();
+        let mut branch = Vec::new();
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(level.last().unwrap().clone());
+            }
+            branch.push(level[pos ^ 1].to_hex_le());
+            level = next_merkle_level(&level);
+            pos /= 2;
+        }
+        if self.bad_merkle_proofs {
+            branch.push(Sha256d::digest(b"bad proof".to_vec()).to_hex_le());
+        }
+        Ok(json!({ "block_height": height, "merkle": branch, "pos": result_pos }))
+    }
+
+    fn listunspent(&self) -> Value {
+        let unspent = self.wallet_utxos().into_iter()
+            .map(|((txid, vout), key, value)| json!({
+                "address": self.address(key).cash_addr().split_once(':').unwrap().1,
+                "value": format!("{}.{:08}", value / 100_000_000, value % 100_000_000),
+                "prevout_n": vout,
+                "prevout_hash": txid,
+            }))
+            .collect();
+        Value::Array(unspent)
+    }
+
+    /// Adds the tx to the simulated chain, returning its txid, or the reason it was rejected.
+    fn broadcast(&mut self, tx_hex: &str) -> Result<String, String> {
+        let tx = deser_tx(tx_hex)?;
+        let mut input_value = 0;
+        let mut input_tokens = HashMap::new();
+        for input in &tx.inputs {
+            let outpoint = (input.prev_out.tx_hash.to_hex_le(), input.prev_out.vout);
+            let output = self.outputs.get(&outpoint).ok_or("missing-inputs")?;
+            if self.spent.contains_key(&outpoint) {
+                return Err("txn-mempool-conflict".to_string());
+            }
+            input_value += output.value;
+            if let Some((token_id, amount)) = self.tokens.get(&outpoint) {
+                *input_tokens.entry(token_id.clone()).or_insert(0) += amount;
+            }
+        }
+        if tx.outputs.iter().map(|output| output.value).sum::<u64>() > input_value {
+            return Err("bad-txns-in-belowout".to_string());
+        }
+        let is_final = tx.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL) || if tx.lock_time < LOCKTIME_THRESHOLD {
+            tx.lock_time <= self.height()
+        } else {
+            tx.lock_time < self.median_time_past()
+        };
+        if !is_final {
+            return Err("non-final".to_string());
+        }
+
+        let txid = self.add_tx(&tx);
+        // Invalid SLP transactions burn their input tokens.
+        if let Some((token_id, amounts)) = tx.outputs.first().and_then(|output| parse_slp_send(&output.script)) {
+            let is_valid = amounts.len() < tx.outputs.len()
+                && amounts.iter().sum::<u64>() <= input_tokens.get(&token_id).cloned().unwrap_or(0);
+            if is_valid {
+                for (idx, &amount) in amounts.iter().enumerate() {
+                    if amount > 0 {
+                        self.tokens.insert((txid.clone(), idx as u32 + 1), (token_id.clone(), amount));
+                    }
+                }
+                self.valid_slp.insert(txid.clone());
+            }
+        }
+        Ok(txid)
+    }
+}
+
+fn header_time(header: &[u8]) -> u32 {
+    let mut time = [0; 4];
+    time.copy_from_slice(&header[68..72]);
+    u32::from_le_bytes(time)
+}
+
+fn merkle_root(txids: &[Sha256d]) -> Sha256d {
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(level.last().unwrap().clone());
+        }
+        level = next_merkle_level(&level);
+    }
+    level[0].clone()
+}
+
+/// Hashes pairs of an even number of merkle tree nodes.
+fn next_merkle_level(level: &[Sha256d]) -> Vec<Sha256d> {
+    level.chunks(2)
+        .map(|pair| Sha256d::digest([pair[0].as_slice(), pair[1].as_slice()].concat()))
+        .collect()
+}
+
+/// Answers line-delimited JSON-RPC requests of the Electrum protocol until the client disconnects.
+fn serve_electrum(stream: TcpStream, state: &Mutex<State>) {
+    let mut writer = stream.try_clone().unwrap();
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let request: Value = serde_json::from_str(&line).unwrap();
+        let state = state.lock().unwrap();
+        let response = state.handle_electrum(&request);
+        drop(state);
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Token id and output amounts of an SLP SEND message.
+fn parse_slp_send(script: &Script) -> Option<(String, Vec<u64>)> {
+    let ops = script.ops();
+    if ops.len() < 6 || ops[0].op != Op::Code(Opcode::OP_RETURN) {
+        return None;
+    }
+    let mut pushes = Vec::with_capacity(ops.len() - 1);
+    for op in &ops[1..] {
+        match &op.op {
+            Op::PushByteArray { array, .. } => pushes.push(array.to_vec()),
+            _ => return None,
+        }
+    }
+    if pushes[0] != b"SLP\0" || pushes[1] != [1] || pushes[2] != b"SEND" {
+        return None;
+    }
+    let amounts = pushes[4..].iter()
+        .map(|amount| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(amount);
+            u64::from_be_bytes(bytes)
+        })
+        .collect();
+    Some((hex::encode(&pushes[3]), amounts))
+}
+
+/// Deserializes a tx, flipping back the `is_minimal` flags bitcoin-cash inverts so it serializes unchanged.
+fn deser_tx(tx_hex: &str) -> Result<UnhashedTx, String> {
+    let raw_tx = hex::decode(tx_hex).map_err(|err| format!("Invalid hex: {}", err))?;
+    let (mut tx, _): (UnhashedTx, _) = UnhashedTx::deser(raw_tx.into())
+        .map_err(|err| format!("Invalid tx: {:?}", err))?;
+    let fix_push_flags = |script: &Script| {
+        let ops = script.ops().iter()
+            .map(|op| {
+                let mut op = op.clone();
+                if let Op::PushByteArray { is_minimal, .. } = &mut op.op {
+                    *is_minimal = !*is_minimal;
+                }
+                op
+            })
+            .collect::<Vec<_>>();
+        Script::new(ops)
+    };
+    for input in &mut tx.inputs {
+        input.script = fix_push_flags(&input.script);
+    }
+    for output in &mut tx.outputs {
+        output.script = fix_push_flags(&output.script);
+    }
+    Ok(tx)
+}
+
+fn parse_outpoint(outpoint: &str) -> Outpoint {
+    let (txid, vout) = outpoint.split_once(':').unwrap();
+    (txid.to_string(), vout.parse().unwrap())
+}
+
+/// Reads an HTTP request, answering `Expect: 100-continue`, and returns its body.
+fn read_request(stream: &mut TcpStream) -> String {
+    let (_, body) = read_http_request(stream);
+    String::from_utf8(body).unwrap()
+}
+
+/// Reads an HTTP request, answering `Expect: 100-continue`, and returns its request line and body.
+fn read_http_request(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end().to_lowercase();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("content-length:") {
+            content_length = length.trim().parse().unwrap();
+        }
+        if line == "expect: 100-continue" {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    (request_line.trim_end().to_string(), body)
+}
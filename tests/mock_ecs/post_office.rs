@@ -0,0 +1,156 @@
+//! A stand-in SLP post office, which pays the gas of txs out of thin air of the mock's chain.
+//!
+//! Serves its postage at `GET /postage` and takes BIP70 `Payment` messages at `POST /postage`. A tx is
+//! accepted if it pays enough stamps of the mock's token to the post office's address; the post office
+//! then adds an input covering the missing BCH and a fee of 1 sat/byte, signs it and broadcasts the tx.
+
+use super::*;
+
+/// Size of the P2PKH input the post office adds.
+const STAMP_INPUT_SIZE: u64 = 148;
+
+pub struct MockPostOffice {
+    pub uri: String,
+    /// Wallet address of the mock receiving the postage.
+    pub address: String,
+}
+
+impl MockPostOffice {
+    /// Starts a post office taking `rate` base units of the mock's token per stamp of `weight` sats.
+    pub fn start(mock: &MockEcs, weight: u64, rate: u64) -> MockPostOffice {
+        let address = mock.new_address();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::clone(&mock.state);
+        let postage_address = address.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (request_line, body) = read_http_request(&mut stream);
+                let mut state = state.lock().unwrap();
+                let (status, content_type, body) = match request_line.split(' ').next().unwrap() {
+                    "GET" => {
+                        let postage = json!({
+                            "version": 1,
+                            "address": postage_address,
+                            "weight": weight,
+                            "transactionttl": 30,
+                            "stamps": [{
+                                "name": "Mock Token",
+                                "symbol": "MOCK",
+                                "decimals": TOKEN_DECIMALS,
+                                "tokenId": state.token_id,
+                                "rate": rate,
+                            }],
+                        });
+                        ("200 OK", "application/json", postage.to_string().into_bytes())
+                    }
+                    _ => match state.post(&body, &postage_address, weight, rate) {
+                        Ok(ack) => ("200 OK", "application/simpleledger-paymentack", ack),
+                        Err(message) => ("400 Bad Request", "text/plain", message.into_bytes()),
+                    },
+                };
+                let header = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status, content_type, body.len(),
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        MockPostOffice { uri, address }
+    }
+}
+
+impl State {
+    /// Adds gas to the tx in the `Payment` and broadcasts it, returning the `PaymentACK`.
+    fn post(&mut self, payment: &[u8], postage_address: &str, weight: u64, rate: u64) -> Result<Vec<u8>, String> {
+        let raw_tx = match decode_bytes_field(payment) {
+            Some((2, raw_tx, [])) => raw_tx,
+            _ => return Err("Payment must hold exactly one tx".to_string()),
+        };
+        let mut tx = deser_tx(&hex::encode(raw_tx))?;
+        let mut prev_outputs = tx.inputs.iter()
+            .map(|input| self.outputs.get(&(input.prev_out.tx_hash.to_hex_le(), input.prev_out.vout)).cloned())
+            .collect::<Option<Vec<_>>>()
+            .ok_or("missing-inputs")?;
+        let input_value = prev_outputs.iter().map(|output| output.value).sum::<u64>();
+        let output_value = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+        let shortfall = output_value.saturating_sub(input_value);
+        let num_stamps = (raw_tx.len() as u64 + shortfall).div_ceil(weight);
+
+        let postage_script: Script = Address::from_cash_addr(postage_address).unwrap().into();
+        let (token_id, amounts) = tx.outputs.first()
+            .and_then(|output| parse_slp_send(&output.script))
+            .ok_or("Not an SLP SEND")?;
+        let postage = tx.outputs.iter().enumerate().skip(1)
+            .filter(|(_, output)| output.script.ser_ops() == postage_script.ser_ops())
+            .filter_map(|(idx, _)| amounts.get(idx - 1))
+            .sum::<u64>();
+        if token_id != self.token_id || postage < num_stamps * rate {
+            return Err(format!("Insufficient postage, {} stamps needed", num_stamps));
+        }
+
+        let stamp_key = self.new_key();
+        let stamp_script: Script = self.address(stamp_key).p2pkh_script().unwrap().into();
+        let stamp_value = shortfall + raw_tx.len() as u64 + STAMP_INPUT_SIZE;
+        let stamp_txid = self.fund(stamp_script.clone(), stamp_value);
+        tx.inputs.push(TxInput::new(
+            TxOutpoint { tx_hash: Sha256d::from_hex_le(&stamp_txid).unwrap(), vout: 0 },
+            Script::default(),
+            SEQUENCE_FINAL,
+        ));
+        prev_outputs.push(TxOutput { value: stamp_value, script: stamp_script });
+        let mut preimage_tx = tx.clone();
+        for (input, prev_output) in preimage_tx.inputs.iter_mut().zip(&prev_outputs) {
+            input.value = Some(prev_output.value);
+            input.lock_script = Some(prev_output.script.clone());
+        }
+        let stamp_idx = tx.inputs.len() - 1;
+        let preimage = &preimage_tx.preimages(&[SigHashFlags::DEFAULT])[stamp_idx][0];
+        let sig = self.ecc.sign(&self.keys[stamp_key], Sha256d::digest(preimage.ser())).unwrap();
+        tx.inputs[stamp_idx].script = Script::from_ops(vec![
+            Op::PushByteArray { array: sig.concat([SigHashFlags::DEFAULT.bits() as u8]), is_minimal: true },
+            Op::PushByteArray { array: self.pubkey(stamp_key).as_slice().to_vec().into(), is_minimal: true },
+        ]);
+
+        let raw_tx = tx.ser();
+        self.broadcast(&hex::encode(raw_tx.as_slice()))?;
+        let mut payment = Vec::new();
+        encode_bytes_field(&mut payment, 2, &raw_tx);
+        let mut ack = Vec::new();
+        encode_bytes_field(&mut ack, 1, &payment);
+        encode_bytes_field(&mut ack, 2, b"Postage paid");
+        Ok(ack)
+    }
+}
+
+/// Encodes a length-delimited protobuf field, assuming the field number and length are small.
+fn encode_bytes_field(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    out.push(field << 3 | 2);
+    let mut len = bytes.len();
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+    out.extend_from_slice(bytes);
+}
+
+/// Decodes a length-delimited protobuf field, returning its field number, value and the remaining data.
+fn decode_bytes_field(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&key, mut rest) = data.split_first()?;
+    if key & 7 != 2 {
+        return None;
+    }
+    let mut len = 0;
+    for shift in (0..35).step_by(7) {
+        let (&byte, next) = rest.split_first()?;
+        rest = next;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return (rest.len() >= len).then(|| (key >> 3, &rest[..len], &rest[len..]));
+        }
+    }
+    None
+}